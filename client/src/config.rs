@@ -0,0 +1,244 @@
+use std::{
+    env, fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
+use shared::net::config::{parse_protocol_id, positive, NetConfig};
+
+/// Every flag overrides its `LOCKSTEP_*` environment variable, which overrides the configuration
+/// file, which overrides the defaults.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// TOML configuration file; see `--print-config` for its layout
+    #[arg(long, env = "LOCKSTEP_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Print the resulting configuration as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Port to expose
+    #[arg(short, long, env = "LOCKSTEP_CLIENT_PORT")]
+    pub port: Option<u16>,
+
+    /// Server IP to connect to
+    #[arg(long, env = "LOCKSTEP_SERVER_IP")]
+    pub server_ip: Option<IpAddr>,
+
+    /// Server port to connect to
+    #[arg(long, env = "LOCKSTEP_SERVER_PORT")]
+    pub server_port: Option<u16>,
+
+    /// Bandwidth budget both up and down
+    #[arg(long, env = "LOCKSTEP_MAX_BITS_PER_SECOND")]
+    pub max_bits_per_second: Option<f64>,
+
+    /// Four letter protocol id; the server must use the same
+    #[arg(long, env = "LOCKSTEP_PROTOCOL_ID", value_parser = parse_protocol_id)]
+    pub protocol_id: Option<u32>,
+
+    /// Network frames per second
+    #[arg(long, env = "LOCKSTEP_NETWORK_FPS")]
+    pub network_fps: Option<f64>,
+
+    /// Seconds a connection may take to be established
+    #[arg(long, env = "LOCKSTEP_HANDSHAKE_TIMEOUT")]
+    pub handshake_timeout: Option<f64>,
+
+    /// Seconds without receiving anything before the connection times out
+    #[arg(long, env = "LOCKSTEP_IDLE_TIMEOUT")]
+    pub idle_timeout: Option<f64>,
+
+    /// Seconds a packet may go unacked before the connection times out
+    #[arg(long, env = "LOCKSTEP_UNACKED_TIMEOUT")]
+    pub unacked_timeout: Option<f64>,
+
+    /// Seconds without sending anything after which to send a keep-alive
+    #[arg(long, env = "LOCKSTEP_KEEP_ALIVE_INTERVAL")]
+    pub keep_alive_interval: Option<f64>,
+
+    /// Simulation frames per second
+    #[arg(long, env = "LOCKSTEP_SIM_FPS")]
+    pub sim_fps: Option<f64>,
+
+    /// Capture all datagrams into a pcap file
+    #[arg(long, env = "LOCKSTEP_CAPTURE")]
+    pub capture: Option<PathBuf>,
+
+    /// Log stats every this many network frames; 0 disables stats logging
+    #[arg(long, env = "LOCKSTEP_STATS_INTERVAL")]
+    pub stats_interval: Option<u32>,
+
+    /// Serve network metrics in the Prometheus text format on this address
+    #[arg(long, env = "LOCKSTEP_METRICS")]
+    pub metrics: Option<SocketAddr>,
+
+    /// Log filter, such as `info` or `info,shared::net=trace`; falls back to `RUST_LOG`
+    #[arg(long, env = "LOCKSTEP_LOG")]
+    pub log: Option<String>,
+
+    /// Log JSON lines rather than text
+    #[arg(long, env = "LOCKSTEP_LOG_JSON")]
+    pub log_json: bool,
+
+    /// Say this in the lobby chat once connected
+    #[arg(long)]
+    pub chat: Option<String>,
+
+    /// Connect as a spectator, who follows the game without taking a seat
+    #[arg(long, env = "LOCKSTEP_SPECTATE")]
+    pub spectate: bool,
+}
+
+/// NOTE: plain values must come before tables in TOML
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub port: u16,
+    pub server_ip: IpAddr,
+    pub capture: Option<PathBuf>,
+    pub net: NetConfig,
+    pub sim: SimConfig,
+    pub log: LogConfig,
+    pub metrics: MetricsConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimConfig {
+    pub fps: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub filter: String,
+    pub json: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// in network frames; 0 disables stats logging
+    pub stats_interval: u32,
+    pub address: Option<SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 4322,
+            server_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            capture: None,
+            net: NetConfig::default(),
+            sim: SimConfig::default(),
+            log: LogConfig::default(),
+            metrics: MetricsConfig::default(),
+        }
+    }
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self { fps: 50. }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: "info".into(),
+            json: false,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            stats_interval: 100,
+            address: None,
+        }
+    }
+}
+
+impl Config {
+    /// Layers the defaults, the configuration file, and the environment and flags in `args`.
+    pub fn load(args: &Args) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let text =
+                    fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+                toml::from_str(&text).map_err(|e| format!("{}: {e}", path.display()))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(port) = args.port {
+            config.port = port;
+        }
+        if let Some(ip) = args.server_ip {
+            config.server_ip = ip;
+        }
+        let net = &mut config.net;
+        if let Some(port) = args.server_port {
+            net.server_port = port;
+        }
+        if let Some(bits) = args.max_bits_per_second {
+            net.client_bytes_per_second = bits / 8.;
+        }
+        if let Some(protocol_id) = args.protocol_id {
+            net.protocol_id = protocol_id;
+        }
+        if let Some(fps) = args.network_fps {
+            net.fps = fps;
+        }
+        if let Some(timeout) = args.handshake_timeout {
+            net.handshake_timeout = timeout;
+        }
+        if let Some(timeout) = args.idle_timeout {
+            net.idle_timeout = timeout;
+        }
+        if let Some(timeout) = args.unacked_timeout {
+            net.unacked_timeout = timeout;
+        }
+        if let Some(interval) = args.keep_alive_interval {
+            net.keep_alive_interval = interval;
+        }
+        if let Some(fps) = args.sim_fps {
+            config.sim.fps = fps;
+        }
+        if let Some(path) = &args.capture {
+            config.capture = Some(path.clone());
+        }
+        if let Some(interval) = args.stats_interval {
+            config.metrics.stats_interval = interval;
+        }
+        if let Some(address) = args.metrics {
+            config.metrics.address = Some(address);
+        }
+        // NOTE: `RUST_LOG` used to set the filter, so it still does when nothing else overrides it
+        if let Some(filter) = args.log.clone().or_else(|| env::var("RUST_LOG").ok()) {
+            config.log.filter = filter;
+        }
+        // NOTE: a flag can only turn this on
+        config.log.json |= args.log_json;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.net.validate().map_err(|e| format!("net: {e}"))?;
+        positive("sim.fps", self.sim.fps)
+    }
+
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string_pretty(self)
+    }
+}
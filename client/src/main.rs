@@ -1,82 +1,151 @@
+mod config;
+
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 use clap::Parser;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
 
 use shared::{
     net::{
+        capture::CaptureTransport,
         client::{Client, ClientEvent, ClientState},
-        network::{bind_socket, NETWORK_FPS, SERVER_PORT},
+        metrics::{ConnectionQuality, LogMetricsSink, PrometheusExporter},
+        network::{bind_socket, Role},
+    },
+    sim::{
+        chat::{ChatMessage, CHAT_VERSION},
+        physics_test::PhysicsTest,
+        GameState, LobbyMessage,
     },
-    sim::{physics_test::PhysicsTest, GameState, Lobby, LobbyMessage},
     timing::FrameDurationAccumulator,
 };
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Port to expose
-    #[arg(short, long, default_value_t = 4322)]
-    pub port: u16,
-
-    /// Server IP to connect to
-    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
-    pub server_ip: IpAddr,
-}
+use crate::config::{Args, Config};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
+    let config = Config::load(&args)?;
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    init_logging(&config)?;
 
-    println!("{:?}", args);
+    debug!(?config);
 
     let mut client = {
         let socket = {
-            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), args.port);
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), config.port);
             let socket = bind_socket(addr)?;
-            println!("Socket bound to {}", addr);
-            socket
+            info!(%addr, "socket bound");
+            CaptureTransport::new(socket, addr, config.capture.as_deref())?
         };
 
-        let server_addr = SocketAddr::new(args.server_ip, SERVER_PORT);
+        let server_addr = SocketAddr::new(config.server_ip, config.net.server_port);
 
-        Client::new(socket, server_addr, NETWORK_FPS)
+        let role = if args.spectate {
+            Role::Spectator
+        } else {
+            Role::Player
+        };
+        let mut client = Client::with_role(socket, server_addr, role, config.net.clone());
+        if config.metrics.stats_interval > 0 {
+            client.add_metrics_sink(LogMetricsSink::every(config.metrics.stats_interval));
+        }
+        if let Some(addr) = config.metrics.address {
+            client.add_metrics_sink(PrometheusExporter::bind(addr)?);
+            info!(%addr, "serving metrics");
+        }
+        client
     };
 
-    let mut sim = FrameDurationAccumulator::with_fps(50.0, 0.25);
+    let mut sim = FrameDurationAccumulator::with_fps(config.sim.fps, 0.25);
 
     let mut state = GameState::Lobby;
-    let mut lobby = Lobby::new();
 
     let mut physics_test = PhysicsTest::new();
 
+    let mut quality = ConnectionQuality::Good;
+
     loop {
         match client.process_packets() {
             Some(ClientEvent::Connected) => {
-                println!("connected");
-                lobby.add_player(client.index); // hey, it's me!
+                if let Some(text) = &args.chat {
+                    // NOTE: the server drops whatever spectators send
+                    if args.spectate {
+                        warn!("spectators can't chat");
+                    } else if client.version() >= CHAT_VERSION {
+                        client.write(&mut LobbyMessage::Chat(ChatMessage::new(text)));
+                    } else {
+                        warn!(version = client.version(), "server has no chat");
+                    }
+                }
             }
+            // NOTE: this is where a "connection problem" notice would show and hide
+            Some(ClientEvent::ConnectionProblem | ClientEvent::ConnectionRecovered) => {}
+            // NOTE: the client requests a new connection on its own, and the server starts us
+            // over in the lobby
             Some(ClientEvent::ConnectionTimeout) => {
-                todo!("handle connection timeout");
+                warn!("connection to the server timed out, reconnecting");
+                state = GameState::Lobby;
+            }
+            Some(ClientEvent::ConnectionDenied {
+                reason,
+                server_versions,
+            }) => {
+                error!(?reason, %server_versions, "server denied the connection");
+                return Err("connection denied".into());
             }
             None => {}
         }
 
+        let mut sim_deadline = None;
+
         if client.state == ClientState::Connected {
+            let info = client.connection_info();
+            if info.quality != quality {
+                quality = info.quality;
+                if quality == ConnectionQuality::Good {
+                    info!(rtt_ms = info.rtt * 1e3, "connection quality recovered");
+                } else {
+                    warn!(
+                        ?quality,
+                        rtt_ms = info.rtt * 1e3,
+                        jitter_ms = info.jitter * 1e3,
+                        loss_percent = info.loss * 100.,
+                        since_last_receive = info.since_last_receive,
+                        "connection quality degraded"
+                    );
+                }
+            }
+
             match state {
                 GameState::Lobby => {
+                    // NOTE: other clients may send anything, and there is nothing for them to
+                    // send us yet, so their messages are dropped unread
                     while let Some(message) = client.read_new::<LobbyMessage>() {
                         match message {
-                            LobbyMessage::LobbyUpdated(Lobby { join_mask }) => {
-                                lobby.join_mask = join_mask;
-                                print!("lobby seats: ");
-                                for i in 0..8 {
-                                    print!("{}", (lobby.join_mask >> i) & 1);
-                                }
-                                println!();
+                            LobbyMessage::LobbyUpdated(lobby) => {
+                                info!(seats = %lobby, "lobby updated");
                             }
                             LobbyMessage::StartGame => {
-                                println!("starting game!");
+                                info!("starting game");
                                 state = GameState::Running;
                             }
+                            LobbyMessage::Chat(message) => {
+                                let text = message.text().unwrap_or_default();
+                                if message.is_system() {
+                                    info!(time = message.time, text, "chat");
+                                } else {
+                                    info!(
+                                        sender = message.sender,
+                                        time = message.time,
+                                        text,
+                                        "chat"
+                                    );
+                                }
+                            }
                         }
                     }
                     // sim.run_frame(|frame| {
@@ -93,7 +162,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         physics_test.simulate(frame.dt);
 
                         // sync
-                        {
+                        if !args.spectate {
                             client.write(&mut physics_test);
                         }
 
@@ -103,6 +172,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             // println!("\tp={}\tdp={}\tddp={}", position, velocity, acceleration);
                         }
                     });
+                    sim_deadline = Some(sim.next_frame_deadline());
 
                     // Rendering
                     // let dt = simulation.interpolate_frames();
@@ -114,7 +184,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
 
-        // NOTE: don't use all of the CPU core
-        std::thread::sleep(std::time::Duration::from_millis(1));
+        client.wait_for_activity(sim_deadline);
+    }
+}
+
+fn init_logging(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let subscriber =
+        tracing_subscriber::fmt().with_env_filter(EnvFilter::try_new(&config.log.filter)?);
+    if config.log.json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
     }
+    Ok(())
 }
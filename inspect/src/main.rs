@@ -0,0 +1,264 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::Parser;
+
+use shared::{
+    net::{
+        buffer::Buffer,
+        capture::{CapturedDatagram, PcapReader},
+        config::parse_protocol_id,
+        network::{
+            packet_checksum, ConnectionRequestPacket, DirectMessagePacket, PacketHeader,
+            PacketType, DIRECT_MESSAGE_HEADER_SIZE, MAX_DATAGRAM_SIZE, PACKET_HEADER_SIZE,
+            PACKET_TYPE_OFFSET, PROTOCOL_VERSION, RELAY_HEADER_SIZE, SUPPORTED_VERSIONS,
+        },
+        rendezvous::RelayPacket,
+        stream::{ReadStream, Stream},
+    },
+    sim::LobbyMessage,
+};
+
+/// Decodes captured datagrams of the lockstep protocol
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Capture to read; a pcap file, or a raw log with one hex encoded datagram per line
+    pub file: PathBuf,
+
+    /// Read a raw log rather than a pcap file
+    #[arg(long)]
+    pub raw: bool,
+
+    /// Only show datagrams sent from or to this port
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Decode user payloads as lobby messages
+    #[arg(long)]
+    pub lobby: bool,
+
+    /// Four letter protocol id of the application, mixed into checksums
+    #[arg(long, default_value = "MAJG", value_parser = parse_protocol_id)]
+    pub protocol_id: u32,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let datagrams = if args.raw {
+        read_raw_log(&args)?
+    } else {
+        let mut pcap = PcapReader::new(BufReader::new(File::open(&args.file)?))?;
+        let mut datagrams = Vec::new();
+        while let Some(datagram) = pcap.next_datagram()? {
+            datagrams.push(datagram);
+        }
+        datagrams
+    };
+
+    let start_time = datagrams.first().map_or(UNIX_EPOCH, |d| d.time);
+    let mut buffer = Buffer::with_capacity(MAX_DATAGRAM_SIZE);
+    let mut shown = 0;
+
+    for (index, datagram) in datagrams.iter().enumerate() {
+        if let Some(port) = args.port {
+            if datagram.source.port() != port && datagram.destination.port() != port {
+                continue;
+            }
+        }
+        shown += 1;
+
+        println!(
+            "#{index} +{time:.6}s {source} -> {destination} {size}B",
+            time = datagram
+                .time
+                .duration_since(start_time)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            source = datagram.source,
+            destination = datagram.destination,
+            size = datagram.payload.len(),
+        );
+        inspect(&datagram.payload, &args, &mut buffer);
+        println!();
+    }
+
+    println!("{shown} of {} datagrams shown", datagrams.len());
+
+    Ok(())
+}
+
+fn read_raw_log(args: &Args) -> Result<Vec<CapturedDatagram>, Box<dyn std::error::Error>> {
+    // NOTE: raw logs carry no addresses
+    let unknown = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+    let mut datagrams = Vec::new();
+
+    for (number, line) in BufReader::new(File::open(&args.file)?).lines().enumerate() {
+        let line = line?;
+        let hex = line
+            .split('#')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+        if hex.is_empty() {
+            continue;
+        }
+        if hex.len() % 2 != 0 {
+            return Err(format!("line {}: odd number of hex digits", number + 1).into());
+        }
+
+        let payload = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("line {}: {e}", number + 1))?;
+
+        datagrams.push(CapturedDatagram {
+            time: SystemTime::UNIX_EPOCH,
+            source: unknown,
+            destination: unknown,
+            payload,
+        });
+    }
+
+    Ok(datagrams)
+}
+
+fn inspect(datagram: &[u8], args: &Args, buffer: &mut Buffer) {
+    if datagram.len() < PACKET_HEADER_SIZE {
+        println!("  too short for a packet header");
+        print_hex(datagram);
+        return;
+    }
+    if datagram.len() > MAX_DATAGRAM_SIZE {
+        println!("  larger than the max datagram size {MAX_DATAGRAM_SIZE}");
+        print_hex(datagram);
+        return;
+    }
+    let Some(packet_type) = PacketType::from_u8(datagram[PACKET_TYPE_OFFSET]) else {
+        println!("  unknown packet type {}", datagram[PACKET_TYPE_OFFSET]);
+        print_hex(datagram);
+        return;
+    };
+
+    buffer.reset_reader_from(datagram);
+    let header: PacketHeader = ReadStream(buffer, PROTOCOL_VERSION).stream_new();
+
+    let checksum = packet_checksum(datagram, args.protocol_id);
+    println!(
+        "  checksum {:08x} {} | version {} {} | {:?}{}",
+        header.checksum,
+        if checksum == header.checksum {
+            "valid".to_string()
+        } else {
+            format!("INVALID, expected {checksum:08x}")
+        },
+        header.version,
+        if SUPPORTED_VERSIONS.contains(header.version) {
+            "valid"
+        } else {
+            "UNSUPPORTED"
+        },
+        packet_type,
+        if packet_type.invalid_size(datagram.len()) {
+            " of INVALID SIZE"
+        } else {
+            ""
+        },
+    );
+
+    let mut acked = vec![header.ack.unwrap()];
+    for bit in 0..32 {
+        if header.ack_bits & (1 << bit) != 0 {
+            acked.push(header.ack.wrapping_sub(bit + 1).unwrap());
+        }
+    }
+    println!(
+        "  seq {} | ack {} | ack_bits {:032b} | acks {:?}",
+        header.seq.unwrap(),
+        header.ack.unwrap(),
+        header.ack_bits,
+        acked
+    );
+
+    if packet_type == PacketType::Relay && !packet_type.invalid_size(datagram.len()) {
+        let relay: RelayPacket = ReadStream(buffer, header.version).stream_new();
+        println!(
+            "  relayed from {} to {} of session {}",
+            relay.from, relay.to, relay.session
+        );
+        // NOTE: the relayed packet is a whole packet of its own
+        inspect(&datagram[RELAY_HEADER_SIZE..], args, buffer);
+        return;
+    }
+
+    if packet_type == PacketType::ConnectionRequest && !packet_type.invalid_size(datagram.len()) {
+        let request: ConnectionRequestPacket = ReadStream(buffer, header.version).stream_new();
+        match request.role() {
+            Some(role) => println!("  request for versions {} as {role:?}", request.versions),
+            None => println!(
+                "  request for versions {} as UNKNOWN role {}",
+                request.versions, request.role
+            ),
+        }
+    }
+
+    if packet_type == PacketType::DirectMessage && !packet_type.invalid_size(datagram.len()) {
+        let direct: DirectMessagePacket = ReadStream(buffer, header.version).stream_new();
+        // NOTE: which one depends on whether the client or the server sent it
+        println!("  direct message to or from player {}", direct.index);
+        let payload = &datagram[DIRECT_MESSAGE_HEADER_SIZE..];
+        println!("  payload {}B", payload.len());
+        print_hex(payload);
+        return;
+    }
+
+    let payload = &datagram[PACKET_HEADER_SIZE..];
+    println!("  payload {}B", payload.len());
+    print_hex(payload);
+
+    if args.lobby && packet_type == PacketType::UserPayload {
+        inspect_lobby_message(buffer, header.version);
+    }
+}
+
+fn inspect_lobby_message(buffer: &mut Buffer, version: u16) {
+    let Some(&discriminant) = buffer.unread_slice().first() else {
+        return;
+    };
+    if !LobbyMessage::is_valid_discriminant(discriminant) {
+        println!("  not a lobby message");
+        return;
+    }
+
+    match LobbyMessage::read_checked(buffer, version) {
+        Some(LobbyMessage::LobbyUpdated(lobby)) => println!("  LobbyUpdated seats {lobby}"),
+        Some(LobbyMessage::StartGame) => println!("  StartGame"),
+        Some(LobbyMessage::Chat(message)) => println!(
+            "  Chat from {} at {}: {:?}",
+            message.sender,
+            message.time,
+            message.text().unwrap_or("<INVALID UTF-8>")
+        ),
+        None => println!("  truncated lobby message"),
+    }
+}
+
+fn print_hex(bytes: &[u8]) {
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let hex = chunk
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!("    {:04x}  {hex}", line * 16);
+    }
+}
@@ -0,0 +1,139 @@
+use std::{env, fs, path::PathBuf};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
+use shared::net::{config::parse_protocol_id, rendezvous::RendezvousConfig};
+
+/// Every flag overrides its `LOCKSTEP_*` environment variable, which overrides the configuration
+/// file, which overrides the defaults.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// TOML configuration file; see `--print-config` for its layout
+    #[arg(long, env = "LOCKSTEP_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Print the resulting configuration as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Four letter protocol id; players must use the same
+    #[arg(long, env = "LOCKSTEP_PROTOCOL_ID", value_parser = parse_protocol_id)]
+    pub protocol_id: Option<u32>,
+
+    /// Port to listen on
+    #[arg(long, env = "LOCKSTEP_RENDEZVOUS_PORT")]
+    pub port: Option<u16>,
+
+    /// Seconds without hearing from a player before its registration expires
+    #[arg(long, env = "LOCKSTEP_REGISTRATION_TIMEOUT")]
+    pub registration_timeout: Option<f64>,
+
+    /// Capture all datagrams into a pcap file
+    #[arg(long, env = "LOCKSTEP_CAPTURE")]
+    pub capture: Option<PathBuf>,
+
+    /// Log stats every this many seconds; 0 disables stats logging
+    #[arg(long, env = "LOCKSTEP_STATS_INTERVAL")]
+    pub stats_interval: Option<f64>,
+
+    /// Log filter, such as `info` or `info,shared::net=trace`; falls back to `RUST_LOG`
+    #[arg(long, env = "LOCKSTEP_LOG")]
+    pub log: Option<String>,
+
+    /// Log JSON lines rather than text
+    #[arg(long, env = "LOCKSTEP_LOG_JSON")]
+    pub log_json: bool,
+}
+
+/// NOTE: plain values must come before tables in TOML
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub capture: Option<PathBuf>,
+    /// in seconds; 0 disables stats logging
+    pub stats_interval: f64,
+    pub rendezvous: RendezvousConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub filter: String,
+    pub json: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            capture: None,
+            stats_interval: 60.,
+            rendezvous: RendezvousConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: "info".into(),
+            json: false,
+        }
+    }
+}
+
+impl Config {
+    /// Layers the defaults, the configuration file, and the environment and flags in `args`.
+    pub fn load(args: &Args) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let text =
+                    fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+                toml::from_str(&text).map_err(|e| format!("{}: {e}", path.display()))?
+            }
+            None => Config::default(),
+        };
+
+        let rendezvous = &mut config.rendezvous;
+        if let Some(protocol_id) = args.protocol_id {
+            rendezvous.protocol_id = protocol_id;
+        }
+        if let Some(port) = args.port {
+            rendezvous.port = port;
+        }
+        if let Some(timeout) = args.registration_timeout {
+            rendezvous.registration_timeout = timeout;
+        }
+        if let Some(path) = &args.capture {
+            config.capture = Some(path.clone());
+        }
+        if let Some(interval) = args.stats_interval {
+            config.stats_interval = interval;
+        }
+        // NOTE: `RUST_LOG` used to set the filter, so it still does when nothing else overrides it
+        if let Some(filter) = args.log.clone().or_else(|| env::var("RUST_LOG").ok()) {
+            config.log.filter = filter;
+        }
+        // NOTE: a flag can only turn this on
+        config.log.json |= args.log_json;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.rendezvous
+            .validate()
+            .map_err(|e| format!("rendezvous: {e}"))?;
+        if self.stats_interval.is_nan() || self.stats_interval < 0. {
+            return Err(format!(
+                "stats_interval must not be negative, got {}",
+                self.stats_interval
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+mod config;
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+use shared::net::{capture::CaptureTransport, network::bind_socket, rendezvous::RendezvousServer};
+
+use crate::config::{Args, Config};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let config = Config::load(&args)?;
+    if args.print_config {
+        print!("{}", toml::to_string_pretty(&config)?);
+        return Ok(());
+    }
+    init_logging(&config)?;
+
+    let mut rendezvous = {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), config.rendezvous.port);
+        let socket = bind_socket(addr)?;
+        info!(%addr, "socket bound");
+        let socket = CaptureTransport::new(socket, addr, config.capture.as_deref())?;
+        RendezvousServer::new(socket, config.rendezvous.clone())
+    };
+
+    // NOTE: zero disables stats logging
+    let stats_interval =
+        (config.stats_interval > 0.).then(|| Duration::from_secs_f64(config.stats_interval));
+    let mut last_stats = Instant::now();
+
+    loop {
+        rendezvous.process_packets();
+
+        if let Some(interval) = stats_interval {
+            if last_stats.elapsed() >= interval {
+                last_stats = Instant::now();
+                info!(
+                    sessions = rendezvous.sessions(),
+                    players = rendezvous.players(),
+                    relayed_packets = rendezvous.relayed_packets,
+                    relayed_bytes = rendezvous.relayed_bytes,
+                    relays_dropped = rendezvous.relays_dropped,
+                    "stats"
+                );
+            }
+        }
+
+        rendezvous.wait_for_activity();
+    }
+}
+
+fn init_logging(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let subscriber =
+        tracing_subscriber::fmt().with_env_filter(EnvFilter::try_new(&config.log.filter)?);
+    if config.log.json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+    Ok(())
+}
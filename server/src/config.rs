@@ -0,0 +1,300 @@
+use std::{env, fs, net::SocketAddr, path::PathBuf};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
+use shared::{
+    net::{
+        config::{parse_protocol_id, positive, NetConfig},
+        rate_limit::RateLimits,
+    },
+    sim::chat::ChatConfig,
+};
+
+/// Every flag overrides its `LOCKSTEP_*` environment variable, which overrides the configuration
+/// file, which overrides the defaults.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// TOML configuration file; see `--print-config` for its layout
+    #[arg(long, env = "LOCKSTEP_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Print the resulting configuration as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Number of player seats
+    #[arg(long, env = "LOCKSTEP_MAX_PLAYERS", value_parser = clap::value_parser!(u8).range(1..))]
+    pub max_players: Option<u8>,
+
+    /// Number of spectator seats, besides those of players
+    #[arg(long, env = "LOCKSTEP_MAX_SPECTATORS")]
+    pub max_spectators: Option<u8>,
+
+    /// Seconds broadcasts reach spectators after players; 0 disables the delay
+    #[arg(long, env = "LOCKSTEP_SPECTATOR_DELAY")]
+    pub spectator_delay: Option<f64>,
+
+    /// Players needed to start a game
+    #[arg(long, env = "LOCKSTEP_MIN_PLAYERS")]
+    pub min_players: Option<u8>,
+
+    /// Seconds without players joining or leaving before a game starts
+    #[arg(long, env = "LOCKSTEP_START_DELAY")]
+    pub start_delay: Option<f64>,
+
+    /// Bandwidth budget both up and down, split evenly among connected players
+    #[arg(long, env = "LOCKSTEP_MAX_BITS_PER_SECOND")]
+    pub max_bits_per_second: Option<f64>,
+
+    /// Ban source addresses for a while once they exceed rate limits this many times, forgiving
+    /// a violation per second
+    #[arg(long, env = "LOCKSTEP_BAN_AFTER_VIOLATIONS")]
+    pub ban_after_violations: Option<u32>,
+
+    /// Four letter protocol id; clients must use the same
+    #[arg(long, env = "LOCKSTEP_PROTOCOL_ID", value_parser = parse_protocol_id)]
+    pub protocol_id: Option<u32>,
+
+    /// Port to listen on
+    #[arg(long, env = "LOCKSTEP_SERVER_PORT")]
+    pub server_port: Option<u16>,
+
+    /// Network frames per second
+    #[arg(long, env = "LOCKSTEP_NETWORK_FPS")]
+    pub network_fps: Option<f64>,
+
+    /// Seconds a connection may take to be established
+    #[arg(long, env = "LOCKSTEP_HANDSHAKE_TIMEOUT")]
+    pub handshake_timeout: Option<f64>,
+
+    /// Seconds without receiving anything from a client before it times out
+    #[arg(long, env = "LOCKSTEP_IDLE_TIMEOUT")]
+    pub idle_timeout: Option<f64>,
+
+    /// Seconds a packet may go unacked before the connection times out
+    #[arg(long, env = "LOCKSTEP_UNACKED_TIMEOUT")]
+    pub unacked_timeout: Option<f64>,
+
+    /// Seconds without sending anything after which to send a keep-alive
+    #[arg(long, env = "LOCKSTEP_KEEP_ALIVE_INTERVAL")]
+    pub keep_alive_interval: Option<f64>,
+
+    /// Simulation frames per second
+    #[arg(long, env = "LOCKSTEP_SIM_FPS")]
+    pub sim_fps: Option<f64>,
+
+    /// Ban and allow rules, one `ban <range>` or `allow <range>` per line, such as
+    /// `ban 10.0.0.0/8`
+    #[arg(long, env = "LOCKSTEP_ACCESS_LIST")]
+    pub access_list: Option<PathBuf>,
+
+    /// Capture all datagrams into a pcap file
+    #[arg(long, env = "LOCKSTEP_CAPTURE")]
+    pub capture: Option<PathBuf>,
+
+    /// Log stats every this many network frames; 0 disables stats logging
+    #[arg(long, env = "LOCKSTEP_STATS_INTERVAL")]
+    pub stats_interval: Option<u32>,
+
+    /// Serve network metrics in the Prometheus text format on this address
+    #[arg(long, env = "LOCKSTEP_METRICS")]
+    pub metrics: Option<SocketAddr>,
+
+    /// Log filter, such as `info` or `info,shared::net=trace`; falls back to `RUST_LOG`
+    #[arg(long, env = "LOCKSTEP_LOG")]
+    pub log: Option<String>,
+
+    /// Log JSON lines rather than text
+    #[arg(long, env = "LOCKSTEP_LOG_JSON")]
+    pub log_json: bool,
+}
+
+/// NOTE: plain values must come before tables in TOML
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub access_list: Option<PathBuf>,
+    pub capture: Option<PathBuf>,
+    pub net: NetConfig,
+    pub lobby: LobbyConfig,
+    pub chat: ChatConfig,
+    pub sim: SimConfig,
+    pub log: LogConfig,
+    pub metrics: MetricsConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LobbyConfig {
+    pub min_players: u8,
+    /// in seconds
+    pub start_delay: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimConfig {
+    pub fps: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub filter: String,
+    pub json: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// in network frames; 0 disables stats logging
+    pub stats_interval: u32,
+    pub address: Option<SocketAddr>,
+}
+
+impl Default for LobbyConfig {
+    fn default() -> Self {
+        Self {
+            min_players: 2,
+            start_delay: 3.,
+        }
+    }
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self { fps: 50. }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: "info".into(),
+            json: false,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            stats_interval: 100,
+            address: None,
+        }
+    }
+}
+
+impl Config {
+    /// Layers the defaults, the configuration file, and the environment and flags in `args`.
+    pub fn load(args: &Args) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let text =
+                    fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+                toml::from_str(&text).map_err(|e| format!("{}: {e}", path.display()))?
+            }
+            None => Config::default(),
+        };
+
+        let net = &mut config.net;
+        if let Some(max_players) = args.max_players {
+            net.max_clients = max_players;
+        }
+        if let Some(max_spectators) = args.max_spectators {
+            net.max_spectators = max_spectators;
+        }
+        if let Some(delay) = args.spectator_delay {
+            net.spectator_delay = delay;
+        }
+        if let Some(bits) = args.max_bits_per_second {
+            net.server_bytes_per_second = bits / 8.;
+        }
+        if let Some(protocol_id) = args.protocol_id {
+            net.protocol_id = protocol_id;
+        }
+        if let Some(port) = args.server_port {
+            net.server_port = port;
+        }
+        if let Some(fps) = args.network_fps {
+            net.fps = fps;
+        }
+        if let Some(timeout) = args.handshake_timeout {
+            net.handshake_timeout = timeout;
+        }
+        if let Some(timeout) = args.idle_timeout {
+            net.idle_timeout = timeout;
+        }
+        if let Some(timeout) = args.unacked_timeout {
+            net.unacked_timeout = timeout;
+        }
+        if let Some(interval) = args.keep_alive_interval {
+            net.keep_alive_interval = interval;
+        }
+        if let Some(violations) = args.ban_after_violations {
+            net.rate_limits = Some(RateLimits {
+                ban_after_violations: Some(violations),
+                ..net.rate_limits()
+            });
+        }
+        if let Some(min_players) = args.min_players {
+            config.lobby.min_players = min_players;
+        }
+        if let Some(start_delay) = args.start_delay {
+            config.lobby.start_delay = start_delay;
+        }
+        if let Some(fps) = args.sim_fps {
+            config.sim.fps = fps;
+        }
+        if let Some(path) = &args.access_list {
+            config.access_list = Some(path.clone());
+        }
+        if let Some(path) = &args.capture {
+            config.capture = Some(path.clone());
+        }
+        if let Some(interval) = args.stats_interval {
+            config.metrics.stats_interval = interval;
+        }
+        if let Some(address) = args.metrics {
+            config.metrics.address = Some(address);
+        }
+        // NOTE: `RUST_LOG` used to set the filter, so it still does when nothing else overrides it
+        if let Some(filter) = args.log.clone().or_else(|| env::var("RUST_LOG").ok()) {
+            config.log.filter = filter;
+        }
+        // NOTE: a flag can only turn this on
+        config.log.json |= args.log_json;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.net.validate().map_err(|e| format!("net: {e}"))?;
+        positive("sim.fps", self.sim.fps)?;
+        self.chat.validate().map_err(|e| format!("chat: {e}"))?;
+        let lobby = &self.lobby;
+        if lobby.min_players == 0 || lobby.min_players > self.net.max_clients {
+            return Err(format!(
+                "lobby.min_players must be within 1..={}, got {}",
+                self.net.max_clients, lobby.min_players
+            ));
+        }
+        if lobby.start_delay.is_nan() || lobby.start_delay < 0. {
+            return Err(format!(
+                "lobby.start_delay must not be negative, got {}",
+                lobby.start_delay
+            ));
+        }
+        Ok(())
+    }
+
+    /// NOTE: derived settings are written out, so that the dump shows what's in effect
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        let mut config = self.clone();
+        config.net.rate_limits = Some(config.net.rate_limits());
+        toml::to_string_pretty(&config)
+    }
+}
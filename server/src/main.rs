@@ -1,65 +1,145 @@
+mod config;
+
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
     time::Instant,
 };
 
+use clap::Parser;
+use tracing::{debug, info};
+use tracing_subscriber::EnvFilter;
+
 use shared::{
     net::{
-        network::{bind_socket, MAX_CLIENTS, NETWORK_FPS, SERVER_PORT},
+        access::AccessList,
+        batched::bind_batched_socket,
+        buffer::Buffer,
+        capture::CaptureTransport,
+        metrics::{LogMetricsSink, PrometheusExporter},
+        network::PACKET_BUFFER_SIZE,
         server::{Server, ServerEvent},
+        transport::Transport,
+    },
+    sim::{
+        chat::{Chat, ChatMessage, CHAT_VERSION},
+        physics_test::PhysicsTest,
+        GameState, Lobby, LobbyMessage,
     },
-    sim::{physics_test::PhysicsTest, GameState, Lobby, LobbyMessage},
     timing::FrameDurationAccumulator,
 };
 
+use crate::config::{Args, Config};
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let config = Config::load(&args)?;
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    init_logging(&config)?;
+
     let mut server = {
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), SERVER_PORT);
-        let socket = bind_socket(addr)?;
-        println!("socket bound to {}", addr);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), config.net.server_port);
+        let socket = bind_batched_socket(addr)?;
+        info!(%addr, "socket bound");
+        let socket = CaptureTransport::new(socket, addr, config.capture.as_deref())?;
 
-        Server::new(socket, MAX_CLIENTS, NETWORK_FPS)
+        let mut server = Server::new(socket, config.net.clone());
+        if let Some(path) = &config.access_list {
+            let access = AccessList::load(path)?;
+            info!(
+                path = %path.display(),
+                banned = access.banned().len(),
+                allowed = access.allowed().len(),
+                "access list loaded"
+            );
+            server.set_access_list(access);
+        }
+        if config.metrics.stats_interval > 0 {
+            server.add_metrics_sink(LogMetricsSink::every(config.metrics.stats_interval));
+        }
+        if let Some(addr) = config.metrics.address {
+            server.add_metrics_sink(PrometheusExporter::bind(addr)?);
+            info!(%addr, "serving metrics");
+        }
+        server
     };
 
-    let mut sim = FrameDurationAccumulator::with_fps(50.0, 0.25);
+    let mut sim = FrameDurationAccumulator::with_fps(config.sim.fps, 0.25);
 
     let mut state = GameState::Lobby;
 
-    let mut lobby = Lobby::new();
+    let mut lobby = Lobby::new(config.net.max_clients);
+
+    let mut chat = Chat::new(config.chat.clone(), config.net.max_clients);
+    let mut buffer = Buffer::with_capacity(PACKET_BUFFER_SIZE);
 
     let mut start_time = Instant::now();
 
     loop {
         if let Some(event) = server.process_packets() {
-            match event {
+            let lobby_changed = match event {
                 ServerEvent::ClientConnected(index) => {
                     lobby.add_player(index);
+                    if server
+                        .version(index as usize)
+                        .is_some_and(|v| v >= CHAT_VERSION)
+                    {
+                        for message in chat.history() {
+                            server.write(index as usize, &mut LobbyMessage::Chat(message.clone()));
+                        }
+                    }
+                    let message = chat.join(index, Instant::now());
+                    broadcast_chat(&mut server, message);
+                    true
                 }
                 ServerEvent::ClientTimeout(index) => {
                     lobby.remove_player(index);
+                    let message = chat.leave(index);
+                    broadcast_chat(&mut server, message);
+                    true
                 }
-            }
-            start_time = Instant::now();
-            server.broadcast(&mut LobbyMessage::LobbyUpdated(lobby.clone()));
-            {
-                print!("lobby seats: ");
-                for i in 0..8 {
-                    print!("{}", (lobby.join_mask >> i) & 1);
+                // NOTE: spectators get the state as of now, and broadcasts from then on
+                ServerEvent::SpectatorConnected(index) => {
+                    let index = index as usize;
+                    server.write(index, &mut LobbyMessage::LobbyUpdated(lobby.clone()));
+                    if server.version(index).is_some_and(|v| v >= CHAT_VERSION) {
+                        for message in chat.history() {
+                            server.write(index, &mut LobbyMessage::Chat(message.clone()));
+                        }
+                    }
+                    if let GameState::Running = state {
+                        server.write(index, &mut LobbyMessage::StartGame);
+                    }
+                    false
                 }
-                println!();
+                // NOTE: already logged by the server
+                ServerEvent::SpectatorTimeout(_)
+                | ServerEvent::ClientConnectionProblem(_)
+                | ServerEvent::ClientConnectionRecovered(_) => false,
+            };
+            if lobby_changed {
+                start_time = Instant::now();
+                server.broadcast(&mut LobbyMessage::LobbyUpdated(lobby.clone()));
+                info!(seats = %lobby, "lobby updated");
             }
         }
 
-        match state {
+        let sim_deadline = match state {
             GameState::Lobby => {
-                if Instant::now().duration_since(start_time).as_secs() >= 3
-                    && lobby.join_mask >= 0b11
+                if Instant::now().duration_since(start_time).as_secs_f64()
+                    >= config.lobby.start_delay
+                    && lobby.player_count() >= config.lobby.min_players as usize
                 {
-                    println!("starting");
+                    info!("starting game");
                     server.broadcast(&mut LobbyMessage::StartGame);
                     state = GameState::Running;
                 }
-                server.drop_incoming();
+                for index in 0..server.capacity {
+                    receive_chat(&mut server, index, &mut chat, &mut buffer);
+                }
+                None
             }
             GameState::Running => {
                 sim.run_frame(|_frame| {
@@ -71,10 +151,58 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         }
                     }
                 });
+                Some(sim.next_frame_deadline())
+            }
+        };
+
+        server.wait_for_activity(sim_deadline);
+    }
+}
+
+/// Reads the lobby messages of a player, and broadcasts what it says in the chat.
+fn receive_chat<T: Transport>(
+    server: &mut Server<T>,
+    index: usize,
+    chat: &mut Chat,
+    buffer: &mut Buffer,
+) {
+    let Some(version) = server.version(index) else {
+        return;
+    };
+    while server.read_payload(index, buffer) {
+        // NOTE: players may send anything, so their messages are checked before reading
+        match LobbyMessage::read_checked(buffer, version) {
+            Some(LobbyMessage::Chat(message)) => {
+                if let Some(message) = chat.receive(index as u8, message, Instant::now()) {
+                    info!(
+                        sender = index,
+                        text = message.text().unwrap_or_default(),
+                        "chat"
+                    );
+                    broadcast_chat(server, message);
+                }
+            }
+            // NOTE: only the server updates the lobby and starts games
+            Some(LobbyMessage::LobbyUpdated(_) | LobbyMessage::StartGame) | None => {
+                debug!(index, "dropped unexpected lobby message");
             }
         }
+    }
+}
+
+/// NOTE: clients older than `CHAT_VERSION` can't read chat messages, and spectators read along
+/// as delayed as the rest of their broadcasts
+fn broadcast_chat<T: Transport>(server: &mut Server<T>, message: ChatMessage) {
+    server.broadcast_since(CHAT_VERSION, &mut LobbyMessage::Chat(message));
+}
 
-        // NOTE: don't use all of the CPU core
-        std::thread::sleep(std::time::Duration::from_millis(1));
+fn init_logging(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let subscriber =
+        tracing_subscriber::fmt().with_env_filter(EnvFilter::try_new(&config.log.filter)?);
+    if config.log.json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
     }
+    Ok(())
 }
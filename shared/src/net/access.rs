@@ -0,0 +1,314 @@
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::net::network::DenyReason;
+
+/// A single address, or a CIDR range such as `10.0.0.0/8` or `fd00::/8`.
+///
+/// NOTE: IPv4-mapped ranges such as `::ffff:10.0.0.0/104` are the IPv4 range they map; those
+/// shorter than /96 cover more than the mapped addresses, and stay IPv6 ranges
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn new(address: IpAddr, prefix: u8) -> Option<Self> {
+        let (address, prefix) = match address {
+            IpAddr::V6(ip) if prefix >= 96 => match ip.to_ipv4_mapped() {
+                Some(ip) => (IpAddr::V4(ip), prefix - 96),
+                None => (address, prefix),
+            },
+            _ => (address, prefix),
+        };
+        let bits = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix > bits {
+            return None;
+        }
+        // NOTE: host bits are cleared, so that equal ranges compare equal
+        let network = match address {
+            IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask_u32(prefix))),
+            IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask_u128(prefix))),
+        };
+        Some(Self { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // NOTE: dual stack sockets see IPv4 clients as IPv4-mapped IPv6 addresses
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                u32::from(ip) & mask_u32(self.prefix) == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                u128::from(ip) & mask_u128(self.prefix) == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn mask_u32(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn mask_u128(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(ip: IpAddr) -> Self {
+        let prefix = if ip.is_ipv4() { 32 } else { 128 };
+        Self::new(ip, prefix).unwrap()
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((address, prefix)) => {
+                let address: IpAddr = address.parse().map_err(|e| format!("{address}: {e}"))?;
+                let prefix: u8 = prefix.parse().map_err(|e| format!("{prefix}: {e}"))?;
+                Self::new(address, prefix).ok_or_else(|| format!("prefix /{prefix} is too long"))
+            }
+            None => {
+                let address: IpAddr = s.parse().map_err(|e| format!("{s}: {e}"))?;
+                Ok(address.into())
+            }
+        }
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Decides which source addresses may connect.
+///
+/// Bans take precedence over the allow list; an empty allow list allows everyone.
+#[derive(Clone, Debug, Default)]
+pub struct AccessList {
+    banned: Vec<IpRange>,
+    allowed: Vec<IpRange>,
+}
+
+impl AccessList {
+    /// Reads one rule per line, either `ban <range>` or `allow <range>`; `#` starts a comment.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut list = Self::default();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |message: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {message}", path.display(), number + 1),
+                )
+            };
+            let (rule, range) = line.split_once(char::is_whitespace).ok_or_else(|| {
+                invalid(format!(
+                    "expected `ban <range>` or `allow <range>`, got `{line}`"
+                ))
+            })?;
+            let range: IpRange = range.trim().parse().map_err(invalid)?;
+            match rule {
+                "ban" => list.ban(range),
+                "allow" => list.allow(range),
+                _ => return Err(invalid(format!("unknown rule `{rule}`"))),
+            }
+        }
+        Ok(list)
+    }
+
+    pub fn check(&self, ip: IpAddr) -> Result<(), DenyReason> {
+        if self.banned.iter().any(|range| range.contains(ip)) {
+            Err(DenyReason::Banned)
+        } else if !self.allowed.is_empty() && !self.allowed.iter().any(|range| range.contains(ip)) {
+            Err(DenyReason::NotAllowed)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn banned(&self) -> &[IpRange] {
+        &self.banned
+    }
+
+    pub fn allowed(&self) -> &[IpRange] {
+        &self.allowed
+    }
+
+    pub fn ban(&mut self, range: IpRange) {
+        if !self.banned.contains(&range) {
+            self.banned.push(range);
+        }
+    }
+
+    /// Returns whether the exact range was banned.
+    pub fn unban(&mut self, range: IpRange) -> bool {
+        let len = self.banned.len();
+        self.banned.retain(|r| *r != range);
+        self.banned.len() != len
+    }
+
+    pub fn allow(&mut self, range: IpRange) {
+        if !self.allowed.contains(&range) {
+            self.allowed.push(range);
+        }
+    }
+
+    /// Returns whether the exact range was allowed.
+    pub fn disallow(&mut self, range: IpRange) -> bool {
+        let len = self.allowed.len();
+        self.allowed.retain(|r| *r != range);
+        self.allowed.len() != len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> IpRange {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_ranges_contain_their_addresses() {
+        let private = range("10.0.0.0/8");
+        assert!(private.contains(ip("10.0.0.0")));
+        assert!(private.contains(ip("10.255.255.255")));
+        assert!(!private.contains(ip("11.0.0.0")));
+        assert!(!private.contains(ip("::a00:0")), "IPv6 isn't IPv4");
+
+        let single = range("192.168.1.1/32");
+        assert_eq!(single, range("192.168.1.1"));
+        assert!(single.contains(ip("192.168.1.1")));
+        assert!(!single.contains(ip("192.168.1.2")));
+
+        let all = range("0.0.0.0/0");
+        assert!(all.contains(ip("0.0.0.0")));
+        assert!(all.contains(ip("255.255.255.255")));
+        assert!(!all.contains(ip("::1")));
+    }
+
+    #[test]
+    fn ipv6_ranges_contain_their_addresses() {
+        let unique_local = range("fd00::/8");
+        assert!(unique_local.contains(ip("fd12:3456::1")));
+        assert!(!unique_local.contains(ip("fe80::1")));
+
+        let single = range("2001:db8::1/128");
+        assert_eq!(single, range("2001:db8::1"));
+        assert!(single.contains(ip("2001:db8::1")));
+        assert!(!single.contains(ip("2001:db8::2")));
+
+        let all = range("::/0");
+        assert!(all.contains(ip("::")));
+        assert!(all.contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!all.contains(ip("10.0.0.1")), "IPv4 isn't IPv6");
+    }
+
+    #[test]
+    fn mapped_addresses_are_their_ipv4_address() {
+        // NOTE: as seen by dual stack sockets
+        assert!(range("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!range("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+
+        assert_eq!(range("::ffff:10.0.0.0/104"), range("10.0.0.0/8"));
+        assert_eq!(range("::ffff:10.1.2.3"), range("10.1.2.3/32"));
+        assert_eq!(range("::ffff:0.0.0.0/96"), range("0.0.0.0/0"));
+        assert!(range("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+
+        // NOTE: wider than the mapped addresses
+        let wide = range("::ffff:0.0.0.0/95");
+        assert_eq!(wide.to_string(), "::fffe:0:0/95");
+        assert!(!wide.contains(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn host_bits_are_masked_off() {
+        assert_eq!(range("10.1.2.3/8"), range("10.0.0.0/8"));
+        assert_eq!(range("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(range("10.1.2.3/0").to_string(), "0.0.0.0/0");
+        assert_eq!(range("fd12:3456::1/16").to_string(), "fd12::/16");
+        assert_eq!(range("fd12:3456::1/0").to_string(), "::/0");
+        assert_eq!(range("::ffff:10.1.2.3/104").to_string(), "10.0.0.0/8");
+    }
+
+    #[test]
+    fn malformed_ranges_are_rejected() {
+        for s in [
+            "",
+            "10.0.0",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "10.0.0.0/-1",
+            "10.0.0.0/256",
+            "10.0.0.0/8/8",
+            "10.0.0.0/255.0.0.0",
+            "::/129",
+            "::ffff:10.0.0.0/129",
+            "fd00::/x",
+            "host.example/8",
+        ] {
+            assert!(s.parse::<IpRange>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn bans_take_precedence_over_the_allow_list() {
+        let mut access = AccessList::default();
+        assert_eq!(access.check(ip("10.0.0.1")), Ok(()));
+
+        access.allow(range("10.0.0.0/8"));
+        access.ban(range("10.0.0.1"));
+        assert_eq!(access.check(ip("10.0.0.1")), Err(DenyReason::Banned));
+        assert_eq!(access.check(ip("10.0.0.2")), Ok(()));
+        assert_eq!(access.check(ip("::ffff:10.0.0.2")), Ok(()));
+        assert_eq!(access.check(ip("11.0.0.1")), Err(DenyReason::NotAllowed));
+
+        assert!(access.unban(range("10.0.0.1/32")));
+        assert!(!access.unban(range("10.0.0.1/32")));
+        assert_eq!(access.check(ip("10.0.0.1")), Ok(()));
+    }
+
+    #[test]
+    fn lists_load_with_comments_and_report_bad_lines() {
+        let path = std::env::temp_dir().join(format!("access-{}.txt", std::process::id()));
+        fs::write(
+            &path,
+            "# rules\nallow 10.0.0.0/8\n\nban ::ffff:10.0.0.0/120 # noisy\n",
+        )
+        .unwrap();
+        let access = AccessList::load(&path).unwrap();
+        assert_eq!(access.allowed(), [range("10.0.0.0/8")]);
+        assert_eq!(access.banned(), [range("10.0.0.0/24")]);
+
+        fs::write(&path, "allow 10.0.0.0/8\nban 10.0.0.0/40\n").unwrap();
+        let error = AccessList::load(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains(":2:"), "{error}");
+
+        fs::write(&path, "deny 10.0.0.0/8\n").unwrap();
+        assert!(AccessList::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+use std::{io, net::SocketAddr};
+
+use tokio::{net::UdpSocket, time::Instant};
+
+use crate::net::{
+    buffer::Buffer,
+    client::{Client, ClientEvent, ClientState, Sender},
+    network::bind_socket,
+    server::{Server, ServerEvent},
+    stream::Streamable,
+    transport::Transport,
+};
+
+// NOTE: sends may report `WouldBlock` until the runtime has polled the socket once, which is fine
+// as unacked packets are resent anyway
+impl Transport for UdpSocket {
+    fn send_to(&self, buffer: &[u8], address: SocketAddr) -> io::Result<usize> {
+        self.try_send_to(buffer, address)
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.try_recv_from(buffer)
+    }
+}
+
+/// NOTE: must be called from within a tokio runtime
+pub fn bind_async_socket(address: SocketAddr) -> io::Result<UdpSocket> {
+    UdpSocket::from_std(bind_socket(address)?)
+}
+
+pub enum Received<E, M> {
+    Event(E),
+    Message(M),
+}
+
+impl Server<UdpSocket> {
+    /// Waits until a datagram arrives or the next network frame is due, then processes packets.
+    pub async fn process_packets_async(&mut self) -> Option<ServerEvent> {
+        let deadline = Instant::from_std(self.timing.next_frame_deadline());
+        tokio::select! {
+            result = self.socket.readable() => result.expect("socket readiness io error"),
+            _ = tokio::time::sleep_until(deadline) => {}
+        }
+        self.process_packets()
+    }
+
+    pub async fn recv_event(&mut self) -> ServerEvent {
+        loop {
+            if let Some(event) = self.process_packets_async().await {
+                return event;
+            }
+        }
+    }
+
+    /// Resolves with the next message of any client, or with a server event if one happens first.
+    pub async fn recv<S: Streamable>(&mut self) -> Received<ServerEvent, (usize, S)> {
+        loop {
+            // NOTE: lower indices are favored, which is fine as long as the user keeps up
+            for index in 0..self.capacity {
+                if let Some(message) = self.read_new(index) {
+                    return Received::Message((index, message));
+                }
+            }
+            if let Some(event) = self.process_packets_async().await {
+                return Received::Event(event);
+            }
+        }
+    }
+}
+
+impl Client<UdpSocket> {
+    /// Waits until a datagram arrives or the next network frame is due, then processes packets.
+    pub async fn process_packets_async(&mut self) -> Option<ClientEvent> {
+        let deadline = Instant::from_std(self.timing.next_frame_deadline());
+        tokio::select! {
+            result = self.socket.readable() => result.expect("socket readiness io error"),
+            _ = tokio::time::sleep_until(deadline) => {}
+        }
+        self.process_packets()
+    }
+
+    pub async fn recv_event(&mut self) -> ClientEvent {
+        loop {
+            if let Some(event) = self.process_packets_async().await {
+                return event;
+            }
+        }
+    }
+
+    /// Resolves with the next message of the server, or with a client event if one happens
+    /// first; like `read_new`, messages of other clients are dropped.
+    pub async fn recv<S: Streamable>(&mut self) -> Received<ClientEvent, S> {
+        loop {
+            if self.state == ClientState::Connected {
+                if let Some(message) = self.read_new() {
+                    return Received::Message(message);
+                }
+            }
+            if let Some(event) = self.process_packets_async().await {
+                return Received::Event(event);
+            }
+        }
+    }
+
+    /// Like `recv`, for messages of the server or of another client, which are copied into
+    /// `target` as by `read_from`.
+    pub async fn recv_from(&mut self, target: &mut Buffer) -> Received<ClientEvent, Sender> {
+        loop {
+            if self.state == ClientState::Connected {
+                if let Some(sender) = self.read_from(target) {
+                    return Received::Message(sender);
+                }
+            }
+            if let Some(event) = self.process_packets_async().await {
+                return Received::Event(event);
+            }
+        }
+    }
+}
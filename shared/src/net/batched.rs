@@ -0,0 +1,394 @@
+use std::{io, net::SocketAddr};
+
+use crate::net::network::bind_socket;
+
+#[cfg(target_os = "linux")]
+pub use linux::BatchedUdpSocket;
+
+/// NOTE: elsewhere, we fall back to one syscall per datagram
+#[cfg(not(target_os = "linux"))]
+pub type BatchedUdpSocket = std::net::UdpSocket;
+
+/// Binds a socket that sends and receives datagrams in batches where supported, in order to save
+/// syscalls when serving many endpoints.
+pub fn bind_batched_socket(address: SocketAddr) -> io::Result<BatchedUdpSocket> {
+    let socket = bind_socket(address)?;
+    #[cfg(target_os = "linux")]
+    let socket = BatchedUdpSocket::new(socket);
+    Ok(socket)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        cell::{Cell, RefCell},
+        io,
+        mem::{size_of, zeroed},
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+        os::fd::AsRawFd,
+        ptr::null_mut,
+        time::Duration,
+    };
+
+    use tracing::debug;
+
+    use crate::net::{network::PACKET_BUFFER_SIZE, transport::Transport};
+
+    /// max datagrams per syscall
+    const BATCH_SIZE: usize = 64;
+
+    struct Batch {
+        buffers: Box<[[u8; PACKET_BUFFER_SIZE]; BATCH_SIZE]>,
+        sizes: [usize; BATCH_SIZE],
+        addresses: [libc::sockaddr_storage; BATCH_SIZE],
+        address_sizes: [libc::socklen_t; BATCH_SIZE],
+        /// whether a received datagram didn't fit its buffer
+        truncated: [bool; BATCH_SIZE],
+        /// number of datagrams in the batch
+        count: usize,
+        /// next datagram to hand out from a receive batch
+        next: usize,
+    }
+
+    impl Batch {
+        fn new() -> Self {
+            Self {
+                buffers: Box::new([[0; PACKET_BUFFER_SIZE]; BATCH_SIZE]),
+                sizes: [0; BATCH_SIZE],
+                addresses: unsafe { zeroed() },
+                address_sizes: [0; BATCH_SIZE],
+                truncated: [false; BATCH_SIZE],
+                count: 0,
+                next: 0,
+            }
+        }
+
+        /// Fills out headers pointing into the batch for the first `count` datagrams; the sizes
+        /// are taken from `self.sizes`.
+        fn headers(
+            &mut self,
+            count: usize,
+            iovecs: &mut [libc::iovec; BATCH_SIZE],
+            headers: &mut [libc::mmsghdr; BATCH_SIZE],
+        ) {
+            for i in 0..count {
+                iovecs[i] = libc::iovec {
+                    iov_base: self.buffers[i].as_mut_ptr() as *mut libc::c_void,
+                    iov_len: self.sizes[i],
+                };
+                let mut header: libc::msghdr = unsafe { zeroed() };
+                header.msg_name = &mut self.addresses[i] as *mut _ as *mut libc::c_void;
+                header.msg_namelen = self.address_sizes[i];
+                header.msg_iov = &mut iovecs[i];
+                header.msg_iovlen = 1;
+                headers[i] = libc::mmsghdr {
+                    msg_hdr: header,
+                    msg_len: 0,
+                };
+            }
+        }
+    }
+
+    /// Queues datagrams on `send_to` and sends them all with one `sendmmsg` on `flush`, and
+    /// receives up to a batch of datagrams at a time with `recvmmsg`.
+    pub struct BatchedUdpSocket {
+        socket: UdpSocket,
+        send_batch: RefCell<Batch>,
+        receive_batch: RefCell<Batch>,
+        truncated: Cell<u64>,
+    }
+
+    impl BatchedUdpSocket {
+        pub fn new(socket: UdpSocket) -> Self {
+            Self {
+                socket,
+                send_batch: RefCell::new(Batch::new()),
+                receive_batch: RefCell::new(Batch::new()),
+                truncated: Cell::new(0),
+            }
+        }
+
+        pub fn socket(&self) -> &UdpSocket {
+            &self.socket
+        }
+
+        /// Number of received datagrams dropped for exceeding the packet buffer size.
+        pub fn truncated(&self) -> u64 {
+            self.truncated.get()
+        }
+
+        fn receive_batch(&self, batch: &mut Batch) -> io::Result<()> {
+            let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { zeroed() };
+            let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { zeroed() };
+            batch.sizes = [PACKET_BUFFER_SIZE; BATCH_SIZE];
+            batch.address_sizes = [size_of::<libc::sockaddr_storage>() as _; BATCH_SIZE];
+            batch.headers(BATCH_SIZE, &mut iovecs, &mut headers);
+
+            let count = unsafe {
+                libc::recvmmsg(
+                    self.socket.as_raw_fd(),
+                    headers.as_mut_ptr(),
+                    BATCH_SIZE as _,
+                    libc::MSG_DONTWAIT,
+                    null_mut(),
+                )
+            };
+            if count < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let count = count as usize;
+            for (i, header) in headers.iter().take(count).enumerate() {
+                batch.sizes[i] = header.msg_len as usize;
+                batch.address_sizes[i] = header.msg_hdr.msg_namelen;
+                batch.truncated[i] = header.msg_hdr.msg_flags & libc::MSG_TRUNC != 0;
+            }
+            batch.count = count;
+            batch.next = 0;
+            Ok(())
+        }
+    }
+
+    impl Transport for BatchedUdpSocket {
+        fn send_to(&self, buffer: &[u8], address: SocketAddr) -> io::Result<usize> {
+            let size = buffer.len();
+            if size > PACKET_BUFFER_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "datagram exceeds packet buffer size",
+                ));
+            }
+            if self.send_batch.borrow().count == BATCH_SIZE {
+                self.flush()?;
+            }
+
+            let mut batch = self.send_batch.borrow_mut();
+            let i = batch.count;
+            batch.buffers[i][..size].copy_from_slice(buffer);
+            batch.sizes[i] = size;
+            batch.address_sizes[i] = write_sockaddr(address, &mut batch.addresses[i]);
+            batch.count += 1;
+            Ok(size)
+        }
+
+        fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            let mut batch = self.receive_batch.borrow_mut();
+            if batch.next == batch.count {
+                self.receive_batch(&mut batch)?;
+            }
+
+            while batch.next < batch.count {
+                let i = batch.next;
+                batch.next += 1;
+                // NOTE: a truncated datagram can't be parsed, so it's dropped rather than handed
+                // out cut short
+                if batch.truncated[i] {
+                    self.truncated.set(self.truncated.get() + 1);
+                    debug!(size = batch.sizes[i], "dropped truncated datagram");
+                    continue;
+                }
+                // NOTE: skip datagrams from address families we don't know about
+                if let Some(address) = read_sockaddr(&batch.addresses[i]) {
+                    let size = batch.sizes[i].min(buffer.len());
+                    buffer[..size].copy_from_slice(&batch.buffers[i][..size]);
+                    return Ok((size, address));
+                }
+            }
+
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            let mut batch = self.send_batch.borrow_mut();
+            let count = batch.count;
+            batch.count = 0;
+            if count == 0 {
+                return Ok(());
+            }
+
+            let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { zeroed() };
+            let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { zeroed() };
+            batch.headers(count, &mut iovecs, &mut headers);
+
+            let mut sent = 0;
+            let mut failure = None;
+            while sent < count {
+                let result = unsafe {
+                    libc::sendmmsg(
+                        self.socket.as_raw_fd(),
+                        headers[sent..].as_mut_ptr(),
+                        (count - sent) as _,
+                        libc::MSG_DONTWAIT,
+                    )
+                };
+                if result < 0 {
+                    let error = io::Error::last_os_error();
+                    match error.kind() {
+                        io::ErrorKind::Interrupted => continue,
+                        // NOTE: drop the rest, as unacked packets are resent anyway
+                        io::ErrorKind::WouldBlock => break,
+                        // NOTE: the failure is for the first datagram left, so skip it and send
+                        // the rest
+                        _ => {
+                            failure.get_or_insert(error);
+                            sent += 1;
+                            continue;
+                        }
+                    }
+                }
+                sent += result as usize;
+            }
+            failure.map_or(Ok(()), Err)
+        }
+
+        fn wait_readable(&self, timeout: Duration) -> io::Result<()> {
+            let batch = self.receive_batch.borrow();
+            if batch.next < batch.count {
+                return Ok(());
+            }
+            self.socket.wait_readable(timeout)
+        }
+    }
+
+    fn write_sockaddr(
+        address: SocketAddr,
+        storage: &mut libc::sockaddr_storage,
+    ) -> libc::socklen_t {
+        match address {
+            SocketAddr::V4(address) => {
+                let sockaddr = storage as *mut _ as *mut libc::sockaddr_in;
+                unsafe {
+                    *sockaddr = libc::sockaddr_in {
+                        sin_family: libc::AF_INET as _,
+                        sin_port: address.port().to_be(),
+                        sin_addr: libc::in_addr {
+                            s_addr: u32::from_ne_bytes(address.ip().octets()),
+                        },
+                        sin_zero: [0; 8],
+                    }
+                };
+                size_of::<libc::sockaddr_in>() as _
+            }
+            SocketAddr::V6(address) => {
+                let sockaddr = storage as *mut _ as *mut libc::sockaddr_in6;
+                unsafe {
+                    *sockaddr = libc::sockaddr_in6 {
+                        sin6_family: libc::AF_INET6 as _,
+                        sin6_port: address.port().to_be(),
+                        sin6_flowinfo: address.flowinfo(),
+                        sin6_addr: libc::in6_addr {
+                            s6_addr: address.ip().octets(),
+                        },
+                        sin6_scope_id: address.scope_id(),
+                    }
+                };
+                size_of::<libc::sockaddr_in6>() as _
+            }
+        }
+    }
+
+    fn read_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sockaddr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(sockaddr.sin_addr.s_addr.to_ne_bytes()),
+                    u16::from_be(sockaddr.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                let sockaddr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(sockaddr.sin6_addr.s6_addr),
+                    u16::from_be(sockaddr.sin6_port),
+                    sockaddr.sin6_flowinfo,
+                    sockaddr.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{
+        net::UdpSocket,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::net::{network::PACKET_BUFFER_SIZE, transport::Transport};
+
+    fn bind() -> BatchedUdpSocket {
+        bind_batched_socket("127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    /// Receives the next datagram, or fails after a few seconds.
+    fn receive(socket: &BatchedUdpSocket, buffer: &mut [u8]) -> (usize, SocketAddr) {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        loop {
+            match socket.recv_from(buffer) {
+                Ok(received) => return received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(Instant::now() < deadline, "timed out");
+                    socket.wait_readable(Duration::from_millis(10)).unwrap();
+                }
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+
+    #[test]
+    fn batches_are_sent_on_flush_and_received_in_order() {
+        let sender = bind();
+        let receiver = bind();
+        let sender_address = sender.socket().local_addr().unwrap();
+        let receiver_address = receiver.socket().local_addr().unwrap();
+
+        for i in 0..5u8 {
+            assert_eq!(sender.send_to(&[i; 3], receiver_address).unwrap(), 3);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            receiver
+                .recv_from(&mut [0; PACKET_BUFFER_SIZE])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::WouldBlock,
+            "sent before the flush"
+        );
+
+        sender.flush().unwrap();
+        let mut buffer = [0; PACKET_BUFFER_SIZE];
+        for i in 0..5u8 {
+            assert_eq!(receive(&receiver, &mut buffer), (3, sender_address));
+            assert_eq!(buffer[..3], [i; 3]);
+        }
+    }
+
+    #[test]
+    fn oversized_datagrams_are_rejected_and_truncated_ones_dropped() {
+        let socket = bind();
+        let address = socket.socket().local_addr().unwrap();
+        let error = socket
+            .send_to(&[0; PACKET_BUFFER_SIZE + 1], address)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        socket.flush().unwrap();
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender
+            .send_to(&[1; PACKET_BUFFER_SIZE + 1], address)
+            .unwrap();
+        sender.send_to(&[2; 4], address).unwrap();
+
+        let mut buffer = [0; PACKET_BUFFER_SIZE];
+        assert_eq!(
+            receive(&socket, &mut buffer),
+            (4, sender.local_addr().unwrap())
+        );
+        assert_eq!(buffer[..4], [2; 4]);
+        assert_eq!(socket.truncated(), 1);
+    }
+}
@@ -28,11 +28,11 @@ impl Buffer {
             {
                 let alignment = align_of::<T>();
                 assert!(
-                    (value_le_ptr as usize) % alignment == 0,
+                    (value_le_ptr as usize).is_multiple_of(alignment),
                     "Source is not properly aligned"
                 );
                 assert!(
-                    (buffer_ptr as usize) % alignment == 0,
+                    (buffer_ptr as usize).is_multiple_of(alignment),
                     "Destination (buffer pointer) is not properly aligned"
                 );
             }
@@ -46,6 +46,23 @@ impl Buffer {
         self.index += size_of::<T>();
     }
 
+    pub fn write_slice(&mut self, slice: &[u8]) {
+        let size = slice.len();
+        assert!(
+            self.data.len() >= self.index + size,
+            "write_slice out of bounds"
+        );
+        self.data[self.index..self.index + size].copy_from_slice(slice);
+        self.index += size;
+    }
+
+    /// Copies `slice` into the buffer and prepares it for reading.
+    pub fn reset_reader_from(&mut self, slice: &[u8]) {
+        self.reset_writer();
+        self.write_slice(slice);
+        self.reset_reader(slice.len());
+    }
+
     pub fn pad<T>(&mut self) {
         let size = size_of::<T>();
         self.index += size;
@@ -70,11 +87,11 @@ impl Buffer {
             {
                 let alignment = align_of::<T>();
                 assert!(
-                    (value_le_ptr as usize) % alignment == 0,
+                    (value_le_ptr as usize).is_multiple_of(alignment),
                     "Destination is not properly aligned"
                 );
                 assert!(
-                    (buffer_ptr as usize) % alignment == 0,
+                    (buffer_ptr as usize).is_multiple_of(alignment),
                     "Source (buffer pointer) is not properly aligned"
                 );
             }
@@ -91,6 +108,14 @@ impl Buffer {
         result
     }
 
+    /// Like `read`, but None instead of panicking past the end of what there is to read.
+    pub fn try_read<T: Endian + Copy>(&mut self) -> Option<T> {
+        if self.data.len() < self.index + size_of::<T>() {
+            return None;
+        }
+        Some(self.read())
+    }
+
     pub fn full_slice_mut(&mut self) -> &mut [u8] {
         unsafe { std::slice::from_raw_parts_mut(self.data.as_mut_ptr(), self.data.capacity()) }
     }
@@ -103,6 +128,10 @@ impl Buffer {
         &self.data // NOTE: uses len set by reset_reader
     }
 
+    pub fn unread_slice(&self) -> &[u8] {
+        &self.data[self.index..]
+    }
+
     pub fn reset_reader(&mut self, eof: usize) {
         assert!(eof <= self.data.capacity());
         unsafe { self.data.set_len(eof) };
@@ -0,0 +1,443 @@
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::warn;
+
+use crate::net::transport::Transport;
+
+// DOCS: https://wiki.wireshark.org/Development/LibpcapFileFormat
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b23c4d;
+const PCAP_VERSION: (u16, u16) = (2, 4);
+const PCAP_SNAPLEN: u32 = 65535;
+/// raw IPv4 or IPv6 packets, told apart by the IP version field
+// DOCS: https://www.tcpdump.org/linktypes.html
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_LOOP: u32 = 108;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+/// Writes datagrams into a standard pcap file, wrapped in synthesized IP and UDP headers so that
+/// standard tools show addresses and ports.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        writer.write_all(&PCAP_VERSION.0.to_le_bytes())?;
+        writer.write_all(&PCAP_VERSION.1.to_le_bytes())?;
+        writer.write_all(&0i32.to_le_bytes())?; // NOTE: timestamps are in UTC
+        writer.write_all(&0u32.to_le_bytes())?; // NOTE: timestamp accuracy, always 0
+        writer.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        Ok(Self { writer })
+    }
+
+    pub fn write_datagram(
+        &mut self,
+        time: SystemTime,
+        source: SocketAddr,
+        destination: SocketAddr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let udp_size = UDP_HEADER_SIZE + payload.len();
+        let (source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+            // NOTE: mixed families can only be captured as IPv6
+            (s, d) => (IpAddr::V6(to_ipv6(s)), IpAddr::V6(to_ipv6(d))),
+        };
+        let ip_header_size = match source_ip {
+            IpAddr::V4(_) => IPV4_HEADER_SIZE,
+            IpAddr::V6(_) => IPV6_HEADER_SIZE,
+        };
+        let size = (ip_header_size + udp_size) as u32;
+
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        self.writer
+            .write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&since_epoch.subsec_micros().to_le_bytes())?;
+        self.writer.write_all(&size.to_le_bytes())?;
+        self.writer.write_all(&size.to_le_bytes())?;
+
+        match (source_ip, destination_ip) {
+            (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                let mut header = [0u8; IPV4_HEADER_SIZE];
+                header[0] = 0x45; // NOTE: version 4, 5 * 4 bytes of header
+                header[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+                header[6] = 0x40; // NOTE: don't fragment
+                header[8] = 64; // NOTE: time to live
+                header[9] = IP_PROTOCOL_UDP;
+                header[12..16].copy_from_slice(&source_ip.octets());
+                header[16..20].copy_from_slice(&destination_ip.octets());
+                let checksum = ipv4_checksum(&header);
+                header[10..12].copy_from_slice(&checksum.to_be_bytes());
+                self.writer.write_all(&header)?;
+            }
+            (IpAddr::V6(source_ip), IpAddr::V6(destination_ip)) => {
+                let mut header = [0u8; IPV6_HEADER_SIZE];
+                header[0] = 0x60; // NOTE: version 6
+                header[4..6].copy_from_slice(&(udp_size as u16).to_be_bytes());
+                header[6] = IP_PROTOCOL_UDP;
+                header[7] = 64; // NOTE: hop limit
+                header[8..24].copy_from_slice(&source_ip.octets());
+                header[24..40].copy_from_slice(&destination_ip.octets());
+                self.writer.write_all(&header)?;
+            }
+            _ => unreachable!("families were unified above"),
+        }
+
+        self.writer.write_all(&source.port().to_be_bytes())?;
+        self.writer.write_all(&destination.port().to_be_bytes())?;
+        self.writer.write_all(&(udp_size as u16).to_be_bytes())?;
+        self.writer.write_all(&0u16.to_be_bytes())?; // NOTE: no checksum
+        self.writer.write_all(payload)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Records every datagram sent and received through the wrapped transport into a pcap file, if
+/// a capture path was given.
+///
+/// NOTE: capturing stops at the first failure to write the file, such as when the disk is full,
+/// as traffic matters more than its capture
+pub struct CaptureTransport<T: Transport> {
+    transport: T,
+    /// NOTE: used as source of sent and destination of received datagrams
+    local_address: SocketAddr,
+    writer: RefCell<Option<PcapWriter<BufWriter<File>>>>,
+}
+
+impl<T: Transport> CaptureTransport<T> {
+    pub fn new(transport: T, local_address: SocketAddr, path: Option<&Path>) -> io::Result<Self> {
+        let writer = match path {
+            Some(path) => Some(PcapWriter::new(BufWriter::new(File::create(path)?))?),
+            None => None,
+        };
+        Ok(Self {
+            transport,
+            local_address,
+            writer: RefCell::new(writer),
+        })
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Whether datagrams are still being captured.
+    pub fn is_capturing(&self) -> bool {
+        self.writer.borrow().is_some()
+    }
+
+    /// Runs `f` on the writer while capturing, and stops capturing if it fails.
+    fn capture<F: FnOnce(&mut PcapWriter<BufWriter<File>>) -> io::Result<()>>(&self, f: F) {
+        let mut writer = self.writer.borrow_mut();
+        if let Some(Err(e)) = writer.as_mut().map(f) {
+            warn!(error = %e, "failed to write capture, stopped capturing");
+            *writer = None;
+        }
+    }
+
+    fn record(&self, source: SocketAddr, destination: SocketAddr, datagram: &[u8]) {
+        self.capture(|writer| {
+            writer.write_datagram(SystemTime::now(), source, destination, datagram)
+        });
+    }
+}
+
+impl<T: Transport> Transport for CaptureTransport<T> {
+    fn send_to(&self, buffer: &[u8], address: SocketAddr) -> io::Result<usize> {
+        let size = self.transport.send_to(buffer, address)?;
+        self.record(self.local_address, address, &buffer[..size]);
+        Ok(size)
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (size, address) = self.transport.recv_from(buffer)?;
+        self.record(address, self.local_address, &buffer[..size]);
+        Ok((size, address))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.transport.flush()?;
+        // NOTE: once per network frame, so that captures are complete even if we get killed
+        self.capture(PcapWriter::flush);
+        Ok(())
+    }
+
+    fn wait_readable(&self, timeout: Duration) -> io::Result<()> {
+        self.transport.wait_readable(timeout)
+    }
+}
+
+pub struct CapturedDatagram {
+    pub time: SystemTime,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+/// Reads UDP datagrams from a pcap file, as written by `PcapWriter` or by standard tools capturing
+/// raw IP, ethernet or loopback traffic.
+pub struct PcapReader<R: Read> {
+    reader: R,
+    big_endian: bool,
+    nanos: bool,
+    /// max size of records, as the file claims
+    snaplen: u32,
+    linktype: u32,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header)?;
+
+        let magic = [header[0], header[1], header[2], header[3]];
+        let (big_endian, nanos) = if u32::from_le_bytes(magic) == PCAP_MAGIC {
+            (false, false)
+        } else if u32::from_be_bytes(magic) == PCAP_MAGIC {
+            (true, false)
+        } else if u32::from_le_bytes(magic) == PCAP_MAGIC_NANOS {
+            (false, true)
+        } else if u32::from_be_bytes(magic) == PCAP_MAGIC_NANOS {
+            (true, true)
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a pcap file",
+            ));
+        };
+
+        let mut pcap = Self {
+            reader,
+            big_endian,
+            nanos,
+            snaplen: 0,
+            linktype: 0,
+        };
+        pcap.snaplen = pcap.u32_at(&header, 16);
+        pcap.linktype = pcap.u32_at(&header, 20);
+        match pcap.linktype {
+            LINKTYPE_NULL | LINKTYPE_ETHERNET | LINKTYPE_RAW | LINKTYPE_LOOP | LINKTYPE_IPV4
+            | LINKTYPE_IPV6 => Ok(pcap),
+            linktype => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported pcap link type {linktype}"),
+            )),
+        }
+    }
+
+    fn u32_at(&self, bytes: &[u8], index: usize) -> u32 {
+        let value = [
+            bytes[index],
+            bytes[index + 1],
+            bytes[index + 2],
+            bytes[index + 3],
+        ];
+        if self.big_endian {
+            u32::from_be_bytes(value)
+        } else {
+            u32::from_le_bytes(value)
+        }
+    }
+
+    /// Skips records that aren't UDP over IP; returns `None` at the end of the file.
+    pub fn next_datagram(&mut self) -> io::Result<Option<CapturedDatagram>> {
+        loop {
+            let mut header = [0u8; 16];
+            match self.reader.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+
+            let seconds = self.u32_at(&header, 0) as u64;
+            let fraction = self.u32_at(&header, 4);
+            let size = self.u32_at(&header, 8);
+            // NOTE: sizes come from the file, so they're checked before allocating for them
+            if size > self.snaplen {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "record of {size} bytes exceeds the snaplen {}",
+                        self.snaplen
+                    ),
+                ));
+            }
+            if size > PCAP_SNAPLEN {
+                // NOTE: too large for a UDP datagram over IPv4 or IPv6 without jumbograms
+                io::copy(&mut (&mut self.reader).take(size as u64), &mut io::sink())?;
+                continue;
+            }
+            let mut record = vec![0u8; size as usize];
+            self.reader.read_exact(&mut record)?;
+
+            let subsec = if self.nanos {
+                Duration::from_nanos(fraction as u64)
+            } else {
+                Duration::from_micros(fraction as u64)
+            };
+            let time = UNIX_EPOCH + Duration::from_secs(seconds) + subsec;
+
+            let ip_offset = match self.linktype {
+                LINKTYPE_ETHERNET => match record.get(12..14) {
+                    // NOTE: IPv4 and IPv6 ethertypes
+                    Some([0x08, 0x00]) | Some([0x86, 0xdd]) => 14,
+                    _ => continue,
+                },
+                LINKTYPE_NULL | LINKTYPE_LOOP => 4,
+                _ => 0,
+            };
+
+            let Some(ip_packet) = record.get(ip_offset..) else {
+                continue;
+            };
+            if let Some((source, destination, payload)) = parse_udp(ip_packet) {
+                return Ok(Some(CapturedDatagram {
+                    time,
+                    source,
+                    destination,
+                    payload: payload.to_vec(),
+                }));
+            }
+        }
+    }
+}
+
+fn parse_udp(ip_packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let (source_ip, destination_ip, udp): (IpAddr, IpAddr, &[u8]) = match ip_packet.first()? >> 4 {
+        4 => {
+            let header_size = ((ip_packet[0] & 0x0f) as usize) * 4;
+            if ip_packet.len() < header_size.max(IPV4_HEADER_SIZE)
+                || ip_packet[9] != IP_PROTOCOL_UDP
+            {
+                return None;
+            }
+            let source: [u8; 4] = ip_packet[12..16].try_into().ok()?;
+            let destination: [u8; 4] = ip_packet[16..20].try_into().ok()?;
+            (source.into(), destination.into(), &ip_packet[header_size..])
+        }
+        6 => {
+            // NOTE: extension headers aren't supported
+            if ip_packet.len() < IPV6_HEADER_SIZE || ip_packet[6] != IP_PROTOCOL_UDP {
+                return None;
+            }
+            let source: [u8; 16] = ip_packet[8..24].try_into().ok()?;
+            let destination: [u8; 16] = ip_packet[24..40].try_into().ok()?;
+            (
+                source.into(),
+                destination.into(),
+                &ip_packet[IPV6_HEADER_SIZE..],
+            )
+        }
+        _ => return None,
+    };
+
+    if udp.len() < UDP_HEADER_SIZE {
+        return None;
+    }
+    let source_port = u16::from_be_bytes([udp[0], udp[1]]);
+    let destination_port = u16::from_be_bytes([udp[2], udp[3]]);
+    let udp_size =
+        (u16::from_be_bytes([udp[4], udp[5]]) as usize).clamp(UDP_HEADER_SIZE, udp.len());
+
+    Some((
+        SocketAddr::new(source_ip, source_port),
+        SocketAddr::new(destination_ip, destination_port),
+        &udp[UDP_HEADER_SIZE..udp_size],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn keeps_receiving_when_capture_fails() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = socket.local_addr().unwrap();
+        // NOTE: writes to /dev/full fail as if the disk was full
+        let transport =
+            CaptureTransport::new(socket, address, Some(Path::new("/dev/full"))).unwrap();
+
+        transport.send_to(b"lockstep", address).unwrap();
+        transport.flush().unwrap();
+        assert!(!transport.is_capturing());
+
+        let mut buffer = [0u8; 16];
+        transport.wait_readable(Duration::from_secs(1)).unwrap();
+        let (size, source) = transport.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"lockstep");
+        assert_eq!(source, address);
+    }
+
+    #[test]
+    fn reads_what_it_writes() {
+        let source = "127.0.0.1:1234".parse().unwrap();
+        let destination = "[::1]:5678".parse().unwrap();
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer
+            .write_datagram(SystemTime::now(), source, destination, b"lockstep")
+            .unwrap();
+
+        let mut reader = PcapReader::new(writer.writer.as_slice()).unwrap();
+        let datagram = reader.next_datagram().unwrap().unwrap();
+        assert_eq!(datagram.source, "[::ffff:127.0.0.1]:1234".parse().unwrap());
+        assert_eq!(datagram.destination, destination);
+        assert_eq!(datagram.payload, b"lockstep");
+        assert!(reader.next_datagram().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_records_larger_than_the_snaplen() {
+        let mut file = PcapWriter::new(Vec::new()).unwrap().writer;
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut reader = PcapReader::new(file.as_slice()).unwrap();
+        let error = reader.next_datagram().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
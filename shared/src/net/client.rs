@@ -1,102 +1,272 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::{
+    collections::VecDeque,
+    net::{SocketAddr, UdpSocket},
+    time::Instant,
+};
+
+use tracing::{debug, info, trace_span, warn};
 
 use crate::{
-    moving_average::MovingAverage,
     net::{
         buffer::Buffer,
+        config::NetConfig,
+        metrics::{ConnectionInfo, EndpointMetrics, MetricsSink, NetworkMetrics},
         network::{
-            ConnectionAcceptedPacket, PacketType, MAX_CLIENT_BYTES_PER_SECOND, PACKET_BUFFER_SIZE,
+            request_nonce, ConnectionAcceptedPacket, ConnectionDeniedPacket,
+            ConnectionRequestPacket, DenyReason, DirectMessagePacket, PacketType, Role,
+            VersionRange, DIRECT_MESSAGE_VERSION, PACKET_BUFFER_SIZE, PROTOCOL_VERSION,
+            SPECTATOR_VERSION,
         },
-        reliable_ordered::{EndpointState, ReliableOrderedDatagramEndpoint},
+        reliable_ordered::{EndpointState, ReliableOrderedDatagramEndpoint, TimeoutWarning},
         stream::{ReadStream, Stream, Streamable},
+        transport::Transport,
     },
     timing::FrameDurationAccumulator,
 };
 
-use super::network::PRINT_NETWORK_STATS;
-
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum ClientState {
     ConnectionRequest,
     Connecting,
     Connected,
+    /// NOTE: the client stops sending once denied, which it only gives in to when the handshake
+    /// times out, as the server may still accept a resent request
+    Denied,
 }
 
-pub struct Client {
+pub struct Client<T: Transport = UdpSocket> {
     pub index: u8,
-    socket: UdpSocket,
+    role: Role,
+    pub(crate) socket: T,
+    config: NetConfig,
     swap_buffer: Buffer,
     endpoint: ReliableOrderedDatagramEndpoint,
-    timing: FrameDurationAccumulator,
+    pub(crate) timing: FrameDurationAccumulator,
     pub state: ClientState,
-    pub tx_per_frame_avg: f64,
-    pub rx_per_frame_avg: f64,
+    /// of our outstanding request, which denials must echo
+    request_nonce: u32,
+    /// the latest denial of our outstanding request, reported if the handshake times out
+    denial: Option<(Option<DenyReason>, VersionRange)>,
+    metrics: NetworkMetrics,
+    metrics_sinks: Vec<Box<dyn MetricsSink + Send>>,
+    /// NOTE: a call may both connect and warn of a connection problem, for example
+    events: VecDeque<ClientEvent>,
+}
+
+/// Who a message is from; messages of other clients are relayed by the server, in order with its
+/// own.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Sender {
+    Server,
+    Player(u8),
 }
 
 pub enum ClientEvent {
     Connected,
     ConnectionTimeout,
+    /// NOTE: the connection is close to timing out, and may or may not recover
+    ConnectionProblem,
+    ConnectionRecovered,
+    ConnectionDenied {
+        /// NOTE: None if the server gave a reason we don't know
+        reason: Option<DenyReason>,
+        /// the protocol versions the server speaks
+        server_versions: VersionRange,
+    },
 }
 
-impl Client {
-    pub fn new(socket: UdpSocket, server_addr: SocketAddr, fps: f64) -> Client {
+impl<T: Transport> Client<T> {
+    /// NOTE: `server_addr` usually has `config.server_port` as its port
+    pub fn new(socket: T, server_addr: SocketAddr, config: NetConfig) -> Client<T> {
+        Self::with_role(socket, server_addr, Role::Player, config)
+    }
+
+    /// Like `new`, but to connect as a spectator, for example.
+    pub fn with_role(
+        socket: T,
+        server_addr: SocketAddr,
+        role: Role,
+        config: NetConfig,
+    ) -> Client<T> {
         Client {
             index: 0,
+            role,
             socket,
             swap_buffer: Buffer::with_capacity(PACKET_BUFFER_SIZE),
-            endpoint: ReliableOrderedDatagramEndpoint::new(server_addr),
-            timing: FrameDurationAccumulator::with_fps(fps, 0.25),
+            endpoint: ReliableOrderedDatagramEndpoint::new(
+                server_addr,
+                config.client_bytes_per_second,
+                &config,
+            ),
+            timing: FrameDurationAccumulator::with_fps(config.fps, 0.25),
             state: ClientState::ConnectionRequest,
-            tx_per_frame_avg: 0.,
-            rx_per_frame_avg: 0.,
+            request_nonce: 0,
+            denial: None,
+            metrics: NetworkMetrics::with_capacity(1),
+            metrics_sinks: Vec::new(),
+            events: VecDeque::new(),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &NetConfig {
+        &self.config
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// The snapshot as of the latest network frame; the server is the only endpoint.
+    pub fn metrics(&self) -> &NetworkMetrics {
+        &self.metrics
+    }
+
+    /// Hands the metrics snapshot to `sink` every network frame.
+    pub fn add_metrics_sink<M: MetricsSink + Send + 'static>(&mut self, sink: M) {
+        self.metrics_sinks.push(Box::new(sink));
+    }
+
+    /// RTT, loss, bandwidth and quality of the connection to the server, such as for ping bars.
+    pub fn connection_info(&self) -> ConnectionInfo {
+        let traffic = self.metrics.endpoints[0].as_ref().map(|m| &m.traffic);
+        self.endpoint.connection_info(self.state, traffic)
+    }
+
+    /// The protocol version negotiated with the server once connected; our newest until then.
+    pub fn version(&self) -> u16 {
+        self.endpoint.version
+    }
+
+    /// Blocks until a datagram arrives, the next network frame is due or the deadline passes,
+    /// whichever comes first.
+    pub fn wait_for_activity(&self, deadline: Option<Instant>) {
+        let network_deadline = self.timing.next_frame_deadline();
+        let deadline = deadline.map_or(network_deadline, |d| d.min(network_deadline));
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if !timeout.is_zero() {
+            self.socket
+                .wait_readable(timeout)
+                .expect("socket poll io error");
         }
     }
 
     pub fn process_packets(&mut self) -> Option<ClientEvent> {
-        let mut event = None;
+        // NOTE: the request must be the first packet we create, as the server only accepts
+        // requests with the first sequence number
+        if self.state == ClientState::ConnectionRequest {
+            let role = self.role;
+            let nonce = request_nonce();
+            self.endpoint
+                .write_packet(PacketType::ConnectionRequest, |w| {
+                    ConnectionRequestPacket::write_padded(w, role, nonce)
+                });
+            self.request_nonce = nonce;
+            self.denial = None;
+            self.state = ClientState::Connecting;
+        }
 
         self.timing.run_frame(|frame| {
-            match self.endpoint.send_outstanding(&self.socket) {
-                EndpointState::Ok(stats) => {
-                    if PRINT_NETWORK_STATS {
-                        // NOTE: this acts as a low pass filter
-                        self.tx_per_frame_avg.exponential_moving_average(stats.total_bytes_sent as f64, 0.1);
-                        self.rx_per_frame_avg.exponential_moving_average(stats.total_bytes_received as f64, 0.1);
-
-                        let txps = self.tx_per_frame_avg / frame.dt;
-                        let rxps = self.rx_per_frame_avg / frame.dt;
-
-                        println!(
-                            "| Bps% {prxps:5.1}↓ {ptxps:5.1}↑ | Bps {rxps:6.0}↓ {txps:6.0}↑ | B {rx:5}↓ {tx:5}↑ | {fps} fps |",
-                            fps = 1. / frame.dt,
-                            rx = stats.total_bytes_received,
-                            tx = stats.total_bytes_sent,
-                            txps = txps,
-                            rxps = rxps,
-                            ptxps = txps / MAX_CLIENT_BYTES_PER_SECOND * 100.,
-                            prxps = rxps / MAX_CLIENT_BYTES_PER_SECOND * 100.
-                        );
-                    }
+            let _span = trace_span!("network_frame", frame = frame.index).entered();
+
+            if self.state == ClientState::Denied {
+                return;
+            }
+
+            let state = self.endpoint.send_outstanding(&self.socket);
+
+            if let Err(e) = self.socket.flush() {
+                warn!(error = %e, "failed to send datagrams");
+            }
+
+            match state {
+                EndpointState::Ok(stats, warning) => {
+                    let metrics = self.metrics.endpoints[0].get_or_insert_with(|| {
+                        EndpointMetrics::new(self.index, self.endpoint.address)
+                    });
+                    metrics.index = self.index;
+                    let budget = self.endpoint.bytes_per_second;
+                    metrics.record_frame(&stats, frame.dt, budget);
+                    self.metrics
+                        .record_frame(frame.index, frame.dt, &stats, budget);
+
+                    let address = self.endpoint.address;
+                    let warning = warning.map(|warning| match warning {
+                        TimeoutWarning::Raised => {
+                            warn!(%address, "connection problem");
+                            ClientEvent::ConnectionProblem
+                        }
+                        TimeoutWarning::Cleared => {
+                            info!(%address, "connection recovered");
+                            ClientEvent::ConnectionRecovered
+                        }
+                    });
+                    self.events.extend(warning);
                 }
-                EndpointState::ConnectionTimeout => {
-                    self.state = ClientState::ConnectionRequest;
-                    assert!(event.is_none(), "we don't need a client event queue");
-                    event = Some(ClientEvent::ConnectionTimeout);
+                EndpointState::ConnectionTimeout(timeout) => {
+                    self.metrics.endpoints[0] = None;
+                    if let Some((reason, server_versions)) = self.denial.take() {
+                        self.state = ClientState::Denied;
+                        self.events.push_back(ClientEvent::ConnectionDenied {
+                            reason,
+                            server_versions,
+                        });
+                    } else {
+                        warn!(address = %self.endpoint.address, ?timeout, "connection timed out");
+                        self.state = ClientState::ConnectionRequest;
+                        self.events.push_back(ClientEvent::ConnectionTimeout);
+                    }
                 }
             }
+
+            for sink in &mut self.metrics_sinks {
+                sink.record(&self.metrics);
+            }
         });
 
-        if let Some((header, address)) =
-            ReadStream(&mut self.swap_buffer).receive_packet(&self.socket)
+        if let Some((header, address)) = ReadStream(&mut self.swap_buffer, PROTOCOL_VERSION)
+            .receive_packet(&self.socket, self.config.protocol_id)
         {
             if address == self.endpoint.address {
                 match header.packet_type {
                     // NOTE: not for the client to handle
-                    PacketType::ConnectionRequest => {}
+                    PacketType::ConnectionRequest
+                    | PacketType::RendezvousRegister
+                    | PacketType::RendezvousPeers
+                    | PacketType::Punch
+                    | PacketType::Relay => {}
+
+                    // NOTE: sent without an endpoint, so it bypasses the receive buffer. We keep
+                    // resending our request until the handshake times out, in case the server
+                    // frees a seat, or the denial was replayed by a third party.
+                    PacketType::ConnectionDenied => {
+                        if self.state == ClientState::Connecting
+                            && header.ack_bits == self.request_nonce
+                        {
+                            let denied: ConnectionDeniedPacket =
+                                ReadStream(&mut self.swap_buffer, header.version).stream_new();
+                            let reason = denied.reason();
+                            let server_versions = denied.versions;
+                            if self.denial.is_none() {
+                                match reason {
+                                    Some(reason) => {
+                                        warn!(%address, %reason, %server_versions, "connection denied")
+                                    }
+                                    None => {
+                                        warn!(%address, reason = denied.reason, %server_versions, "connection denied")
+                                    }
+                                }
+                            }
+                            self.denial = Some((reason, server_versions));
+                        } else {
+                            debug!(%address, "dropped denial of another request");
+                        }
+                    }
 
                     PacketType::ConnectionAccepted
                     | PacketType::ConnectionKeepAlive
-                    | PacketType::UserPayload => {
+                    | PacketType::UserPayload
+                    | PacketType::DirectMessage => {
                         self.endpoint.receive_swap(header, &mut self.swap_buffer);
                     }
                 }
@@ -104,11 +274,6 @@ impl Client {
         }
 
         match self.state {
-            ClientState::ConnectionRequest => {
-                self.endpoint.create_packet(PacketType::ConnectionRequest);
-                self.state = ClientState::Connecting;
-            }
-
             ClientState::Connecting => {
                 while let Some((header, mut read_stream)) = self.endpoint.peek_message() {
                     assert!(
@@ -116,61 +281,313 @@ impl Client {
                         "should only receive accept packets in connecting state"
                     );
                     let accepted: ConnectionAcceptedPacket = read_stream.stream_new();
+                    let version = header.version;
+                    self.endpoint.mark_handled();
+                    self.denial = None;
+                    // NOTE: we request spectator versions only, so only a server that ignored
+                    // that gets here; it seated us as a player, which we must not play along with
+                    if self.role == Role::Spectator && version < SPECTATOR_VERSION {
+                        let address = self.endpoint.address;
+                        warn!(%address, version, "server has no spectators, and seated us as a player");
+                        self.state = ClientState::Denied;
+                        self.events.push_back(ClientEvent::ConnectionDenied {
+                            reason: Some(DenyReason::UnsupportedVersion),
+                            server_versions: VersionRange {
+                                min: version,
+                                max: version,
+                            },
+                        });
+                        break;
+                    }
                     self.index = accepted.index;
                     self.state = ClientState::Connected;
-                    println!("connected with index {}", self.index);
-                    assert!(event.is_none(), "we don't need a client event queue");
-                    event = Some(ClientEvent::Connected);
-                    self.endpoint.mark_handled();
+                    info!(index = self.index, address = %self.endpoint.address, version, "connected");
+                    self.events.push_back(ClientEvent::Connected);
                 }
             }
 
-            ClientState::Connected => {}
+            ClientState::ConnectionRequest | ClientState::Connected | ClientState::Denied => {}
         }
 
-        event
+        self.events.pop_front()
     }
 
-    pub fn read_into<T: Streamable>(&mut self, target: &mut T) -> bool {
+    /// The next message to read of any sender, past the header of direct messages.
+    fn peek_user_message(&mut self) -> Option<ReadStream<'_>> {
         assert!(
             self.state == ClientState::Connected,
             "user should only read in connected state"
         );
-        if let Some((header, mut read_stream)) = self.endpoint.peek_message() {
-            assert!(
-                header.packet_type == PacketType::UserPayload,
-                "user should only read user packets, not {:?}",
-                header.packet_type
+        let (header, mut read_stream) = self.endpoint.peek_message()?;
+        match header.packet_type {
+            PacketType::UserPayload => {}
+            PacketType::DirectMessage => {
+                let _: DirectMessagePacket = read_stream.stream_new();
+            }
+            packet_type => panic!("user should only read user packets, not {packet_type:?}"),
+        }
+        Some(read_stream)
+    }
+
+    /// The next message of the server to read, dropping the direct messages in front of it.
+    fn peek_server_message(&mut self) -> Option<ReadStream<'_>> {
+        while let Some(Sender::Player(index)) = self.peek_sender() {
+            debug!(
+                index,
+                "dropped direct message, read with read_from to receive them"
             );
-            read_stream.stream_with(target);
             self.endpoint.mark_handled();
-            true
-        } else {
-            false
         }
+        self.peek_user_message()
     }
 
-    pub fn read_new<T: Streamable>(&mut self) -> Option<T> {
+    /// Who the next message to read is from, if there is one.
+    pub fn peek_sender(&mut self) -> Option<Sender> {
         assert!(
             self.state == ClientState::Connected,
             "user should only read in connected state"
         );
-        if let Some((header, mut read_stream)) = self.endpoint.peek_message() {
-            assert!(
-                header.packet_type == PacketType::UserPayload,
-                "user should only read user packets, not {:?}",
-                header.packet_type
-            );
-            let message: T = read_stream.stream_new();
+        let (header, read_stream) = self.endpoint.peek_message()?;
+        if header.packet_type == PacketType::DirectMessage {
+            // NOTE: peeked rather than read, as the reading functions skip the whole header
+            Some(Sender::Player(read_stream.0.unread_slice()[0]))
+        } else {
+            Some(Sender::Server)
+        }
+    }
+
+    /// Reads the next message of the server; messages of other clients are dropped, as they may
+    /// send anything, see `read_from`.
+    pub fn read_into<S: Streamable>(&mut self, target: &mut S) -> bool {
+        if let Some(mut read_stream) = self.peek_server_message() {
+            read_stream.stream_with(target);
+            self.endpoint.mark_handled();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Like `read_into`.
+    pub fn read_new<S: Streamable>(&mut self) -> Option<S> {
+        if let Some(mut read_stream) = self.peek_server_message() {
+            let message: S = read_stream.stream_new();
             self.endpoint.mark_handled();
             return Some(message);
         }
         None
     }
 
-    pub fn write<T: Streamable>(&mut self, value: &mut T) {
+    /// Copies the payload of the next message of the server into `target`, ready for reading;
+    /// like `read_into`, messages of other clients are dropped.
+    pub fn read_payload(&mut self, target: &mut Buffer) -> bool {
+        if let Some(read_stream) = self.peek_server_message() {
+            target.reset_reader_from(read_stream.0.unread_slice());
+            self.endpoint.mark_handled();
+            return true;
+        }
+        false
+    }
+
+    /// Copies the payload of the next message of the server or of another client into `target`,
+    /// ready for reading, and tells who it's from.
+    ///
+    /// NOTE: other clients may send anything, so read their payloads with a `CheckedReadStream`,
+    /// such as through `LobbyMessage::read_checked`
+    pub fn read_from(&mut self, target: &mut Buffer) -> Option<Sender> {
+        let sender = self.peek_sender()?;
+        let read_stream = self.peek_user_message()?;
+        target.reset_reader_from(read_stream.0.unread_slice());
+        self.endpoint.mark_handled();
+        Some(sender)
+    }
+
+    /// Drops the next message of any sender unread, such as one from a sender the game doesn't
+    /// expect.
+    pub fn skip_message(&mut self) -> bool {
+        if self.peek_user_message().is_some() {
+            self.endpoint.mark_handled();
+            return true;
+        }
+        false
+    }
+
+    pub fn write<S: Streamable>(&mut self, value: &mut S) {
+        self.endpoint.write_packet(PacketType::UserPayload, |w| {
+            value.stream(w);
+        });
+    }
+
+    pub fn write_payload(&mut self, payload: &[u8]) {
         self.endpoint.write_packet(PacketType::UserPayload, |w| {
+            w.0.write_slice(payload);
+        });
+    }
+
+    /// Sends a message to another client through the server, which may filter it out. Returns
+    /// false if the server's protocol version has no direct messages.
+    pub fn send_to_player<S: Streamable>(&mut self, index: u8, value: &mut S) -> bool {
+        if self.endpoint.version < DIRECT_MESSAGE_VERSION {
+            return false;
+        }
+        self.endpoint.write_packet(PacketType::DirectMessage, |w| {
+            DirectMessagePacket { index }.stream(w);
             value.stream(w);
         });
+        true
+    }
+
+    /// Like `send_to_player`, with a payload written in our negotiated protocol version.
+    pub fn send_payload_to_player(&mut self, index: u8, payload: &[u8]) -> bool {
+        if self.endpoint.version < DIRECT_MESSAGE_VERSION {
+            return false;
+        }
+        self.endpoint.write_packet(PacketType::DirectMessage, |w| {
+            DirectMessagePacket { index }.stream(w);
+            w.0.write_slice(payload);
+        });
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::net::{
+        network::{bind_socket, NetworkSeq, PacketHeader},
+        server::Server,
+        stream::WriteStream,
+    };
+
+    /// Runs the client, and the server if given, until the client has an event other than a
+    /// connection problem, or fails after a few seconds.
+    fn next_event(
+        mut server: Option<&mut Server<UdpSocket>>,
+        client: &mut Client<UdpSocket>,
+    ) -> ClientEvent {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        loop {
+            assert!(Instant::now() < deadline, "timed out");
+            if let Some(server) = &mut server {
+                server.process_packets();
+            }
+            match client.process_packets() {
+                Some(ClientEvent::ConnectionProblem | ClientEvent::ConnectionRecovered) | None => {}
+                Some(event) => return event,
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn clients_reconnect_after_timing_out() {
+        let config = NetConfig {
+            idle_timeout: 0.3,
+            unacked_timeout: 0.3,
+            ..NetConfig::default()
+        };
+        let server_socket = bind_socket("127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_address = server_socket.local_addr().unwrap();
+        let mut server = Server::new(server_socket, config.clone());
+        let client_socket = bind_socket("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = Client::new(client_socket, server_address, config);
+
+        assert!(matches!(
+            next_event(Some(&mut server), &mut client),
+            ClientEvent::Connected
+        ));
+        // NOTE: enough traffic to move the sequence numbers well past the first
+        for value in 0..10u32 {
+            client.write(&mut value.clone());
+        }
+        let deadline = Instant::now() + Duration::from_millis(100);
+        while Instant::now() < deadline {
+            server.process_packets();
+            client.process_packets();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // NOTE: a server that stops responding looks gone to the client
+        assert!(matches!(
+            next_event(None, &mut client),
+            ClientEvent::ConnectionTimeout
+        ));
+        assert_eq!(client.state, ClientState::ConnectionRequest);
+
+        assert!(matches!(
+            next_event(Some(&mut server), &mut client),
+            ClientEvent::Connected
+        ));
+        assert_eq!(client.state, ClientState::Connected);
+        client.write(&mut 7u32);
+        let deadline = Instant::now() + Duration::from_secs(3);
+        loop {
+            assert!(Instant::now() < deadline, "timed out");
+            server.process_packets();
+            client.process_packets();
+            if let Some(value) = server.read_new::<u32>(0) {
+                assert_eq!(value, 7);
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn spectators_fail_to_connect_to_servers_without_spectators() {
+        let config = NetConfig::default();
+        let server_socket = bind_socket("127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_address = server_socket.local_addr().unwrap();
+        let client_socket = bind_socket("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = Client::with_role(
+            client_socket,
+            server_address,
+            Role::Spectator,
+            config.clone(),
+        );
+
+        let mut buffer = Buffer::with_capacity(PACKET_BUFFER_SIZE);
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let (header, address): (PacketHeader, _) = loop {
+            assert!(Instant::now() < deadline, "timed out");
+            client.process_packets();
+            if let Some(received) = ReadStream(&mut buffer, PROTOCOL_VERSION)
+                .receive_packet(&server_socket, config.protocol_id)
+            {
+                break received;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(header.packet_type, PacketType::ConnectionRequest);
+        let request: ConnectionRequestPacket = ReadStream(&mut buffer, header.version).stream_new();
+        assert_eq!(request.versions.min, SPECTATOR_VERSION);
+
+        // NOTE: a server that ignores the versions we asked for, and seats us as a player
+        let mut w = WriteStream(&mut buffer, SPECTATOR_VERSION - 1);
+        w.init_packet(
+            config.protocol_id,
+            PacketType::ConnectionAccepted,
+            NetworkSeq::wrap(0),
+            NetworkSeq::wrap(0),
+            0,
+        );
+        ConnectionAcceptedPacket::new(0).stream(&mut w);
+        w.finish_packet();
+        server_socket
+            .send_to(buffer.written_slice(), address)
+            .unwrap();
+
+        let ClientEvent::ConnectionDenied {
+            reason,
+            server_versions,
+        } = next_event(None, &mut client)
+        else {
+            panic!("expected a denial");
+        };
+        assert_eq!(reason, Some(DenyReason::UnsupportedVersion));
+        assert_eq!(server_versions.max, SPECTATOR_VERSION - 1);
+        assert_eq!(client.state, ClientState::Denied);
     }
 }
@@ -0,0 +1,302 @@
+use crate::net::rate_limit::RateLimits;
+
+pub const DEFAULT_PROTOCOL_ID: u32 = protocol_id(b"MAJG");
+pub const DEFAULT_SERVER_PORT: u16 = 4321;
+pub const DEFAULT_NETWORK_FPS: f64 = 100.;
+/// in seconds
+pub const DEFAULT_HANDSHAKE_TIMEOUT: f64 = 5.;
+/// in seconds
+pub const DEFAULT_IDLE_TIMEOUT: f64 = 2.;
+/// in seconds
+pub const DEFAULT_UNACKED_TIMEOUT: f64 = 5.;
+pub const DEFAULT_TIMEOUT_WARNING: f64 = 0.5;
+/// in seconds
+pub const DEFAULT_KEEP_ALIVE_INTERVAL: f64 = 0.1;
+/// in seconds
+pub const DEFAULT_PUNCH_INTERVAL: f64 = 0.1;
+/// in seconds
+pub const DEFAULT_PUNCH_TIMEOUT: f64 = 3.;
+/// in seconds
+pub const DEFAULT_RENDEZVOUS_INTERVAL: f64 = 0.5;
+pub const DEFAULT_PACKET_RESEND_FRAME_INTERVAL: u16 = 10;
+/// Our target max bps usage both up and down for a server
+pub const DEFAULT_MAX_BITS_PER_SECOND: f64 = 1e6;
+pub const DEFAULT_MAX_CLIENTS: u8 = 8;
+pub const DEFAULT_MAX_SPECTATORS: u8 = 4;
+
+/// Turns a four letter tag, such as `b"MAJG"`, into a protocol id.
+pub const fn protocol_id(tag: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*tag)
+}
+
+/// Like `protocol_id`, for tags given as text, such as on the command line.
+pub fn parse_protocol_id(tag: &str) -> Result<u32, String> {
+    let tag: &[u8; 4] = tag
+        .as_bytes()
+        .try_into()
+        .map_err(|_| format!("expected four bytes, got {}", tag.len()))?;
+    Ok(protocol_id(tag))
+}
+
+/// Settings of an application built on the network layer; servers and clients must agree on all
+/// but the budgets and limits.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct NetConfig {
+    /// mixed into the checksum of every packet, so that packets of other applications, or other
+    /// builds of the same one, fail the integrity check
+    #[cfg_attr(feature = "serde", serde(with = "serde_protocol_id"))]
+    pub protocol_id: u32,
+    pub server_port: u16,
+    /// network frames per second
+    pub fps: f64,
+    /// seconds a connection may take to be established, that is until the peer acks a packet
+    pub handshake_timeout: f64,
+    /// seconds an established connection may go without receiving anything
+    pub idle_timeout: f64,
+    /// seconds a packet may go unacked on an established connection
+    pub unacked_timeout: f64,
+    /// share of any timeout after which we warn of a connection problem
+    pub timeout_warning: f64,
+    /// seconds without sending anything after which we send a keep-alive; must be shorter than
+    /// the idle timeout
+    pub keep_alive_interval: f64,
+    /// seconds between punches of a peer behind a NAT
+    pub punch_interval: f64,
+    /// seconds of punching a peer after which we relay through the rendezvous server instead
+    pub punch_timeout: f64,
+    /// seconds between registrations with the rendezvous server, which keep our registration and
+    /// our NAT mapping to it alive
+    pub rendezvous_interval: f64,
+    /// network frames to wait at least for an ack before resending a packet, longer if the round
+    /// trip takes longer
+    pub resend_frame_interval: u16,
+    /// number of server seats
+    pub max_clients: u8,
+    /// number of spectator seats, besides those of players
+    pub max_spectators: u8,
+    /// seconds broadcasts reach spectators after players, so that they can't tip players off;
+    /// 0 disables the delay
+    pub spectator_delay: f64,
+    /// max bytes per second a server sends, including UDP/IP header size; split evenly among
+    /// connected clients
+    pub server_bytes_per_second: f64,
+    /// max bytes per second a client sends, including UDP/IP header size
+    pub client_bytes_per_second: f64,
+    /// NOTE: None derives the limits from the settings above
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub rate_limits: Option<RateLimits>,
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        let server_bytes_per_second = DEFAULT_MAX_BITS_PER_SECOND / 8.;
+        Self {
+            protocol_id: DEFAULT_PROTOCOL_ID,
+            server_port: DEFAULT_SERVER_PORT,
+            fps: DEFAULT_NETWORK_FPS,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            unacked_timeout: DEFAULT_UNACKED_TIMEOUT,
+            timeout_warning: DEFAULT_TIMEOUT_WARNING,
+            keep_alive_interval: DEFAULT_KEEP_ALIVE_INTERVAL,
+            punch_interval: DEFAULT_PUNCH_INTERVAL,
+            punch_timeout: DEFAULT_PUNCH_TIMEOUT,
+            rendezvous_interval: DEFAULT_RENDEZVOUS_INTERVAL,
+            resend_frame_interval: DEFAULT_PACKET_RESEND_FRAME_INTERVAL,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            max_spectators: DEFAULT_MAX_SPECTATORS,
+            spectator_delay: 0.,
+            server_bytes_per_second,
+            // NOTE: a client gets the share of a full server
+            client_bytes_per_second: server_bytes_per_second / DEFAULT_MAX_CLIENTS as f64,
+            rate_limits: None,
+        }
+    }
+}
+
+impl NetConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        positive("fps", self.fps)?;
+        positive("handshake_timeout", self.handshake_timeout)?;
+        positive("idle_timeout", self.idle_timeout)?;
+        positive("unacked_timeout", self.unacked_timeout)?;
+        positive("keep_alive_interval", self.keep_alive_interval)?;
+        if self.keep_alive_interval >= self.idle_timeout {
+            return Err(format!(
+                "keep_alive_interval must be shorter than idle_timeout, got {} >= {}",
+                self.keep_alive_interval, self.idle_timeout
+            ));
+        }
+        let warning = self.timeout_warning;
+        if warning.is_nan() || warning <= 0. || warning >= 1. {
+            return Err(format!(
+                "timeout_warning must be within 0..1, got {warning}"
+            ));
+        }
+        positive("punch_interval", self.punch_interval)?;
+        positive("punch_timeout", self.punch_timeout)?;
+        positive("rendezvous_interval", self.rendezvous_interval)?;
+        positive("server_bytes_per_second", self.server_bytes_per_second)?;
+        positive("client_bytes_per_second", self.client_bytes_per_second)?;
+        if self.resend_frame_interval == 0 {
+            return Err("resend_frame_interval must be nonzero".into());
+        }
+        if self.max_clients == 0 {
+            return Err("max_clients must be nonzero".into());
+        }
+        // NOTE: indices are bytes, and the last one is left for messages of the server itself
+        if self.max_clients as usize + self.max_spectators as usize > u8::MAX as usize {
+            return Err(format!(
+                "max_clients and max_spectators must add up to at most {}, got {} + {}",
+                u8::MAX,
+                self.max_clients,
+                self.max_spectators
+            ));
+        }
+        if !self.spectator_delay.is_finite() || self.spectator_delay < 0. {
+            return Err(format!(
+                "spectator_delay must not be negative, got {}",
+                self.spectator_delay
+            ));
+        }
+        if let Some(rate_limits) = &self.rate_limits {
+            rate_limits.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        self.rate_limits
+            .clone()
+            .unwrap_or_else(|| RateLimits::for_config(self))
+    }
+}
+
+/// NOTE: also fails for NaN and infinity
+pub fn positive(name: &str, value: f64) -> Result<(), String> {
+    if value > 0. && value.is_finite() {
+        Ok(())
+    } else {
+        Err(format!("{name} must be positive, got {value}"))
+    }
+}
+
+/// NOTE: protocol ids read best as their four letter tag in configuration files
+#[cfg(feature = "serde")]
+pub(crate) mod serde_protocol_id {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(id: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(&id.to_le_bytes()) {
+            Ok(tag) => serializer.serialize_str(tag),
+            Err(_) => serializer.serialize_u32(*id),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Id {
+            Tag(String),
+            Number(u32),
+        }
+        match Id::deserialize(deserializer)? {
+            Id::Tag(tag) => super::parse_protocol_id(&tag).map_err(D::Error::custom),
+            Id::Number(id) => Ok(id),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+pub(crate) mod serde_seconds {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let seconds = f64::deserialize(deserializer)?;
+        Duration::try_from_secs_f64(seconds).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Setter<T> = fn(&mut T, f64);
+
+    #[test]
+    fn defaults_are_valid() {
+        NetConfig::default().validate().unwrap();
+        let config = NetConfig {
+            rate_limits: Some(RateLimits::default()),
+            ..NetConfig::default()
+        };
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn non_finite_durations_and_rates_are_rejected() {
+        let fields: [(&str, Setter<NetConfig>); 12] = [
+            ("fps", |c, v| c.fps = v),
+            ("handshake_timeout", |c, v| c.handshake_timeout = v),
+            ("idle_timeout", |c, v| c.idle_timeout = v),
+            ("unacked_timeout", |c, v| c.unacked_timeout = v),
+            ("timeout_warning", |c, v| c.timeout_warning = v),
+            ("keep_alive_interval", |c, v| c.keep_alive_interval = v),
+            ("punch_interval", |c, v| c.punch_interval = v),
+            ("punch_timeout", |c, v| c.punch_timeout = v),
+            ("rendezvous_interval", |c, v| c.rendezvous_interval = v),
+            ("spectator_delay", |c, v| c.spectator_delay = v),
+            ("server_bytes_per_second", |c, v| {
+                c.server_bytes_per_second = v
+            }),
+            ("client_bytes_per_second", |c, v| {
+                c.client_bytes_per_second = v
+            }),
+        ];
+        for (name, set) in fields {
+            for value in [f64::INFINITY, f64::NEG_INFINITY, f64::NAN] {
+                let mut config = NetConfig::default();
+                set(&mut config, value);
+                let error = config.validate().unwrap_err();
+                assert!(error.contains(name), "{name} = {value}: {error}");
+            }
+        }
+    }
+
+    #[test]
+    fn non_finite_rate_limits_are_rejected() {
+        let fields: [(&str, Setter<RateLimits>); 6] = [
+            ("handshakes_per_second", |r, v| r.handshakes_per_second = v),
+            ("handshake_burst", |r, v| r.handshake_burst = v),
+            ("packets_per_second", |r, v| r.packets_per_second = v),
+            ("bytes_per_second", |r, v| r.bytes_per_second = v),
+            ("burst_seconds", |r, v| r.burst_seconds = v),
+            ("violations_forgiven_per_second", |r, v| {
+                r.violations_forgiven_per_second = v
+            }),
+        ];
+        for (name, set) in fields {
+            for value in [f64::INFINITY, f64::NAN, -1.] {
+                let mut rate_limits = RateLimits::default();
+                set(&mut rate_limits, value);
+                let config = NetConfig {
+                    rate_limits: Some(rate_limits),
+                    ..NetConfig::default()
+                };
+                let error = config.validate().unwrap_err();
+                assert!(error.contains(name), "{name} = {value}: {error}");
+            }
+        }
+    }
+}
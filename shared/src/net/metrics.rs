@@ -0,0 +1,668 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    moving_average::MovingAverage,
+    net::{client::ClientState, reliable_ordered::EndpointSendStats},
+};
+
+/// Traffic of one endpoint, or of all endpoints together.
+#[derive(Clone, Debug, Default)]
+pub struct TrafficMetrics {
+    /// bytes sent including UDP/IP header size
+    pub bytes_sent: u64,
+    /// bytes received including UDP/IP header size
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// low pass filtered
+    pub bytes_sent_per_second: f64,
+    /// low pass filtered
+    pub bytes_received_per_second: f64,
+    /// share of the bandwidth budget used for sending, in percent
+    pub sent_budget_percent: f64,
+    /// share of the bandwidth budget used for receiving, in percent
+    pub received_budget_percent: f64,
+}
+
+impl TrafficMetrics {
+    pub fn record_frame(
+        &mut self,
+        stats: &EndpointSendStats,
+        dt: f64,
+        budget_bytes_per_second: f64,
+    ) {
+        self.bytes_sent += stats.total_bytes_sent as u64;
+        self.bytes_received += stats.total_bytes_received as u64;
+        self.packets_sent += stats.packets_sent as u64;
+        self.packets_received += stats.packets_received as u64;
+
+        // NOTE: this acts as a low pass filter
+        self.bytes_sent_per_second
+            .exponential_moving_average(stats.total_bytes_sent as f64 / dt, 0.1);
+        self.bytes_received_per_second
+            .exponential_moving_average(stats.total_bytes_received as f64 / dt, 0.1);
+
+        self.sent_budget_percent = self.bytes_sent_per_second / budget_bytes_per_second * 100.;
+        self.received_budget_percent =
+            self.bytes_received_per_second / budget_bytes_per_second * 100.;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EndpointMetrics {
+    pub index: u8,
+    pub address: SocketAddr,
+    pub traffic: TrafficMetrics,
+    /// average round trip time in seconds
+    pub rtt: f64,
+    /// average deviation of round trip times from their average in seconds
+    pub jitter: f64,
+    /// average share of acked packets that had to be resent
+    pub loss: f64,
+}
+
+impl EndpointMetrics {
+    pub fn new(index: u8, address: SocketAddr) -> Self {
+        Self {
+            index,
+            address,
+            traffic: TrafficMetrics::default(),
+            rtt: 0.,
+            jitter: 0.,
+            loss: 0.,
+        }
+    }
+
+    pub fn record_frame(
+        &mut self,
+        stats: &EndpointSendStats,
+        dt: f64,
+        budget_bytes_per_second: f64,
+    ) {
+        self.traffic
+            .record_frame(stats, dt, budget_bytes_per_second);
+        self.rtt = stats.rtt_avg;
+        self.jitter = stats.jitter_avg;
+        self.loss = stats.loss_avg;
+    }
+}
+
+/// How well a connection does, from best to worst, such as for ping bars.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConnectionQuality {
+    Good,
+    Degraded,
+    Bad,
+}
+
+/// in seconds
+const DEGRADED_RTT: f64 = 0.1;
+const BAD_RTT: f64 = 0.25;
+/// in seconds
+const DEGRADED_JITTER: f64 = 0.03;
+const BAD_JITTER: f64 = 0.1;
+const DEGRADED_LOSS: f64 = 0.02;
+const BAD_LOSS: f64 = 0.1;
+/// NOTE: in shares of the idle timeout, so that players get warned before they time out
+const DEGRADED_SILENCE: f64 = 0.25;
+const BAD_SILENCE: f64 = 0.5;
+
+/// The state of a connection as of the latest network frame, for game code.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    /// NOTE: as seen from the client, so always connected for clients of a server
+    pub state: ClientState,
+    /// average round trip time in seconds
+    pub rtt: f64,
+    /// average deviation of round trip times from their average in seconds
+    pub jitter: f64,
+    /// average share of acked packets that had to be resent
+    pub loss: f64,
+    /// low pass filtered, including UDP/IP header size
+    pub bytes_sent_per_second: f64,
+    /// low pass filtered, including UDP/IP header size
+    pub bytes_received_per_second: f64,
+    /// seconds since the latest packet from the peer; None until one arrives
+    pub since_last_receive: Option<f64>,
+    pub quality: ConnectionQuality,
+}
+
+impl ConnectionInfo {
+    /// Rates the connection by the worst of its round trip time, jitter, loss and silence.
+    pub fn classify(&self, idle_timeout: f64) -> ConnectionQuality {
+        let silence = self.since_last_receive.unwrap_or(0.) / idle_timeout;
+        let rate = |value: f64, degraded: f64, bad: f64| {
+            if value >= bad {
+                ConnectionQuality::Bad
+            } else if value >= degraded {
+                ConnectionQuality::Degraded
+            } else {
+                ConnectionQuality::Good
+            }
+        };
+        [
+            rate(self.rtt, DEGRADED_RTT, BAD_RTT),
+            rate(self.jitter, DEGRADED_JITTER, BAD_JITTER),
+            rate(self.loss, DEGRADED_LOSS, BAD_LOSS),
+            rate(silence, DEGRADED_SILENCE, BAD_SILENCE),
+        ]
+        .into_iter()
+        .max()
+        .unwrap()
+    }
+}
+
+/// A snapshot of the network, updated every network frame.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkMetrics {
+    pub frame: u32,
+    /// network frame duration in seconds
+    pub dt: f64,
+    pub peers: usize,
+    /// traffic of all endpoints together
+    pub total: TrafficMetrics,
+    /// average round trip time over all endpoints in seconds
+    pub rtt: f64,
+    /// average loss over all endpoints
+    pub loss: f64,
+    /// datagrams dropped for exceeding rate limits or coming from banned sources
+    pub rate_limited_packets: u64,
+    /// sources banned for exceeding rate limits
+    pub bans: u64,
+    /// NOTE: indexed by slot, so its length is the peer capacity
+    pub endpoints: Vec<Option<EndpointMetrics>>,
+}
+
+impl NetworkMetrics {
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut endpoints = Vec::with_capacity(capacity);
+        for _ in 0..capacity {
+            endpoints.push(None);
+        }
+        Self {
+            endpoints,
+            ..Default::default()
+        }
+    }
+
+    /// Sums up the aggregate metrics from `total`, the stats of all endpoints this frame.
+    pub(crate) fn record_frame(
+        &mut self,
+        frame: u32,
+        dt: f64,
+        total: &EndpointSendStats,
+        budget_bytes_per_second: f64,
+    ) {
+        self.frame = frame;
+        self.dt = dt;
+        self.total.record_frame(total, dt, budget_bytes_per_second);
+
+        let connected = self.endpoints.iter().flatten();
+        self.peers = connected.clone().count();
+        if self.peers == 0 {
+            self.rtt = 0.;
+            self.loss = 0.;
+        } else {
+            self.rtt = connected.clone().map(|e| e.rtt).sum::<f64>() / self.peers as f64;
+            self.loss = connected.map(|e| e.loss).sum::<f64>() / self.peers as f64;
+        }
+    }
+}
+
+/// Receives the metrics snapshot every network frame.
+pub trait MetricsSink {
+    fn record(&mut self, metrics: &NetworkMetrics);
+}
+
+/// Logs the aggregate metrics as an info event every `interval` network frames.
+pub struct LogMetricsSink {
+    interval: u32,
+}
+
+impl LogMetricsSink {
+    pub fn every(interval: u32) -> Self {
+        assert!(interval > 0, "log interval must be nonzero");
+        Self { interval }
+    }
+}
+
+impl MetricsSink for LogMetricsSink {
+    fn record(&mut self, metrics: &NetworkMetrics) {
+        if !metrics.frame.is_multiple_of(self.interval) {
+            return;
+        }
+
+        let total = &metrics.total;
+        info!(
+            frame = metrics.frame,
+            fps = 1. / metrics.dt,
+            peers = metrics.peers,
+            capacity = metrics.endpoints.len(),
+            received_budget_percent = total.received_budget_percent,
+            sent_budget_percent = total.sent_budget_percent,
+            bytes_received_per_second = total.bytes_received_per_second,
+            bytes_sent_per_second = total.bytes_sent_per_second,
+            packets_received = total.packets_received,
+            packets_sent = total.packets_sent,
+            rtt_ms = metrics.rtt * 1e3,
+            loss_percent = metrics.loss * 100.,
+            rate_limited_packets = metrics.rate_limited_packets,
+            bans = metrics.bans,
+            "network stats"
+        );
+    }
+}
+
+/// time a scrape may take in all, as scrapes are served one at a time
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Serves the latest snapshot in the Prometheus text format over HTTP, on any path.
+pub struct PrometheusExporter {
+    address: SocketAddr,
+    latest: Arc<Mutex<NetworkMetrics>>,
+}
+
+impl PrometheusExporter {
+    /// NOTE: the serving thread lives as long as the process
+    pub fn bind(address: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let latest = Arc::new(Mutex::new(NetworkMetrics::default()));
+
+        {
+            let latest = latest.clone();
+            std::thread::spawn(move || {
+                let mut body = String::new();
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    body.clear();
+                    render_prometheus(&latest.lock().unwrap(), &mut body);
+                    if let Err(e) = respond(stream, &body) {
+                        warn!(error = %e, "metrics request failed");
+                    }
+                }
+            });
+        }
+
+        Ok(Self { address, latest })
+    }
+
+    /// The address served on, such as for the actual port when bound to port 0.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl MetricsSink for PrometheusExporter {
+    fn record(&mut self, metrics: &NetworkMetrics) {
+        // NOTE: clone_from reuses the endpoint allocation
+        self.latest.lock().unwrap().clone_from(metrics);
+    }
+}
+
+/// NOTE: the timeout covers the whole exchange, so that a client trickling its request or
+/// not reading the response can't hold up the next scrape for long
+fn respond(mut stream: TcpStream, body: &str) -> io::Result<()> {
+    let deadline = Instant::now() + SCRAPE_TIMEOUT;
+    let remaining = || match deadline.checked_duration_since(Instant::now()) {
+        Some(remaining) if !remaining.is_zero() => Ok(Some(remaining)),
+        _ => Err(io::Error::from(io::ErrorKind::TimedOut)),
+    };
+
+    // NOTE: we answer every request the same, so we only read until the end of the head
+    let mut request = [0; 1024];
+    let mut size = 0;
+    while size < request.len() && !request[..size].windows(4).any(|w| w == b"\r\n\r\n") {
+        stream.set_read_timeout(remaining()?)?;
+        match stream.read(&mut request[size..])? {
+            0 => break,
+            n => size += n,
+        }
+    }
+
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let mut response = response.as_bytes();
+    while !response.is_empty() {
+        stream.set_write_timeout(remaining()?)?;
+        match stream.write(response)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => response = &response[n..],
+        }
+    }
+    Ok(())
+}
+
+type TrafficMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&TrafficMetrics) -> f64,
+);
+
+const TRAFFIC_METRICS: [TrafficMetric; 8] = [
+    (
+        "sent_bytes_total",
+        "counter",
+        "bytes sent including UDP/IP headers",
+        |t| t.bytes_sent as f64,
+    ),
+    (
+        "received_bytes_total",
+        "counter",
+        "bytes received including UDP/IP headers",
+        |t| t.bytes_received as f64,
+    ),
+    ("sent_packets_total", "counter", "packets sent", |t| {
+        t.packets_sent as f64
+    }),
+    (
+        "received_packets_total",
+        "counter",
+        "packets received",
+        |t| t.packets_received as f64,
+    ),
+    (
+        "sent_bytes_per_second",
+        "gauge",
+        "low pass filtered send rate",
+        |t| t.bytes_sent_per_second,
+    ),
+    (
+        "received_bytes_per_second",
+        "gauge",
+        "low pass filtered receive rate",
+        |t| t.bytes_received_per_second,
+    ),
+    (
+        "sent_budget_percent",
+        "gauge",
+        "share of the send bandwidth budget in use",
+        |t| t.sent_budget_percent,
+    ),
+    (
+        "received_budget_percent",
+        "gauge",
+        "share of the receive bandwidth budget in use",
+        |t| t.received_budget_percent,
+    ),
+];
+
+/// Aggregates are named `lockstep_*`, and per endpoint metrics `lockstep_peer_*` with labels.
+pub fn render_prometheus(metrics: &NetworkMetrics, out: &mut String) {
+    let peers = || metrics.endpoints.iter().flatten();
+    let labels =
+        |e: &EndpointMetrics| format!("{{index=\"{}\",address=\"{}\"}}", e.index, e.address);
+
+    family(
+        out,
+        "lockstep_network_frame",
+        "counter",
+        "network frames run",
+    );
+    let _ = writeln!(out, "lockstep_network_frame {}", metrics.frame);
+    family(out, "lockstep_peers", "gauge", "connected peers");
+    let _ = writeln!(out, "lockstep_peers {}", metrics.peers);
+    family(
+        out,
+        "lockstep_peer_capacity",
+        "gauge",
+        "maximum number of peers",
+    );
+    let _ = writeln!(out, "lockstep_peer_capacity {}", metrics.endpoints.len());
+    family(
+        out,
+        "lockstep_rtt_seconds",
+        "gauge",
+        "average round trip time of all peers",
+    );
+    let _ = writeln!(out, "lockstep_rtt_seconds {}", metrics.rtt);
+    family(
+        out,
+        "lockstep_loss_ratio",
+        "gauge",
+        "average share of acked packets that were resent",
+    );
+    let _ = writeln!(out, "lockstep_loss_ratio {}", metrics.loss);
+    family(
+        out,
+        "lockstep_rate_limited_packets_total",
+        "counter",
+        "datagrams dropped by rate limits",
+    );
+    let _ = writeln!(
+        out,
+        "lockstep_rate_limited_packets_total {}",
+        metrics.rate_limited_packets
+    );
+    family(
+        out,
+        "lockstep_bans_total",
+        "counter",
+        "sources banned for exceeding rate limits",
+    );
+    let _ = writeln!(out, "lockstep_bans_total {}", metrics.bans);
+
+    for (name, kind, help, value) in TRAFFIC_METRICS {
+        family(out, &format!("lockstep_{name}"), kind, help);
+        let _ = writeln!(out, "lockstep_{name} {}", value(&metrics.total));
+    }
+
+    for (name, kind, help, value) in TRAFFIC_METRICS {
+        family(out, &format!("lockstep_peer_{name}"), kind, help);
+        for e in peers() {
+            let _ = writeln!(
+                out,
+                "lockstep_peer_{name}{} {}",
+                labels(e),
+                value(&e.traffic)
+            );
+        }
+    }
+
+    family(
+        out,
+        "lockstep_peer_rtt_seconds",
+        "gauge",
+        "average round trip time",
+    );
+    for e in peers() {
+        let _ = writeln!(out, "lockstep_peer_rtt_seconds{} {}", labels(e), e.rtt);
+    }
+
+    family(
+        out,
+        "lockstep_peer_jitter_seconds",
+        "gauge",
+        "average deviation of round trip times",
+    );
+    for e in peers() {
+        let _ = writeln!(
+            out,
+            "lockstep_peer_jitter_seconds{} {}",
+            labels(e),
+            e.jitter
+        );
+    }
+
+    family(
+        out,
+        "lockstep_peer_loss_ratio",
+        "gauge",
+        "average share of acked packets that were resent",
+    );
+    for e in peers() {
+        let _ = writeln!(out, "lockstep_peer_loss_ratio{} {}", labels(e), e.loss);
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> NetworkMetrics {
+        let traffic = TrafficMetrics {
+            bytes_sent: 1000,
+            bytes_received: 2000,
+            packets_sent: 10,
+            packets_received: 20,
+            bytes_sent_per_second: 100.5,
+            bytes_received_per_second: 200.25,
+            sent_budget_percent: 12.5,
+            received_budget_percent: 25.,
+        };
+        NetworkMetrics {
+            frame: 42,
+            dt: 0.01,
+            peers: 1,
+            total: traffic.clone(),
+            rtt: 0.05,
+            loss: 0.25,
+            rate_limited_packets: 3,
+            bans: 1,
+            endpoints: vec![
+                None,
+                Some(EndpointMetrics {
+                    traffic,
+                    rtt: 0.05,
+                    jitter: 0.5,
+                    loss: 0.25,
+                    ..EndpointMetrics::new(1, "127.0.0.1:7000".parse().unwrap())
+                }),
+            ],
+        }
+    }
+
+    #[test]
+    fn snapshots_render_in_the_prometheus_text_format() {
+        let mut out = String::new();
+        render_prometheus(&snapshot(), &mut out);
+        assert_eq!(out, GOLDEN.trim_start());
+    }
+
+    #[test]
+    fn slow_clients_do_not_stall_scrapes() {
+        let mut exporter = PrometheusExporter::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        exporter.record(&snapshot());
+
+        // NOTE: trickles its request in faster than any read times out, without finishing it
+        let mut slow = TcpStream::connect(exporter.address()).unwrap();
+        let trickle = std::thread::spawn(move || {
+            for _ in 0..20 {
+                if slow.write_all(b"X").is_err() {
+                    break;
+                }
+                std::thread::sleep(SCRAPE_TIMEOUT / 5);
+            }
+        });
+        std::thread::sleep(SCRAPE_TIMEOUT / 10);
+
+        let start = Instant::now();
+        let mut scrape = TcpStream::connect(exporter.address()).unwrap();
+        scrape.set_read_timeout(Some(3 * SCRAPE_TIMEOUT)).unwrap();
+        scrape.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        scrape.read_to_string(&mut response).unwrap();
+        assert!(start.elapsed() < 2 * SCRAPE_TIMEOUT);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with(GOLDEN.trim_start()));
+        trickle.join().unwrap();
+    }
+
+    const GOLDEN: &str = r#"
+# HELP lockstep_network_frame network frames run
+# TYPE lockstep_network_frame counter
+lockstep_network_frame 42
+# HELP lockstep_peers connected peers
+# TYPE lockstep_peers gauge
+lockstep_peers 1
+# HELP lockstep_peer_capacity maximum number of peers
+# TYPE lockstep_peer_capacity gauge
+lockstep_peer_capacity 2
+# HELP lockstep_rtt_seconds average round trip time of all peers
+# TYPE lockstep_rtt_seconds gauge
+lockstep_rtt_seconds 0.05
+# HELP lockstep_loss_ratio average share of acked packets that were resent
+# TYPE lockstep_loss_ratio gauge
+lockstep_loss_ratio 0.25
+# HELP lockstep_rate_limited_packets_total datagrams dropped by rate limits
+# TYPE lockstep_rate_limited_packets_total counter
+lockstep_rate_limited_packets_total 3
+# HELP lockstep_bans_total sources banned for exceeding rate limits
+# TYPE lockstep_bans_total counter
+lockstep_bans_total 1
+# HELP lockstep_sent_bytes_total bytes sent including UDP/IP headers
+# TYPE lockstep_sent_bytes_total counter
+lockstep_sent_bytes_total 1000
+# HELP lockstep_received_bytes_total bytes received including UDP/IP headers
+# TYPE lockstep_received_bytes_total counter
+lockstep_received_bytes_total 2000
+# HELP lockstep_sent_packets_total packets sent
+# TYPE lockstep_sent_packets_total counter
+lockstep_sent_packets_total 10
+# HELP lockstep_received_packets_total packets received
+# TYPE lockstep_received_packets_total counter
+lockstep_received_packets_total 20
+# HELP lockstep_sent_bytes_per_second low pass filtered send rate
+# TYPE lockstep_sent_bytes_per_second gauge
+lockstep_sent_bytes_per_second 100.5
+# HELP lockstep_received_bytes_per_second low pass filtered receive rate
+# TYPE lockstep_received_bytes_per_second gauge
+lockstep_received_bytes_per_second 200.25
+# HELP lockstep_sent_budget_percent share of the send bandwidth budget in use
+# TYPE lockstep_sent_budget_percent gauge
+lockstep_sent_budget_percent 12.5
+# HELP lockstep_received_budget_percent share of the receive bandwidth budget in use
+# TYPE lockstep_received_budget_percent gauge
+lockstep_received_budget_percent 25
+# HELP lockstep_peer_sent_bytes_total bytes sent including UDP/IP headers
+# TYPE lockstep_peer_sent_bytes_total counter
+lockstep_peer_sent_bytes_total{index="1",address="127.0.0.1:7000"} 1000
+# HELP lockstep_peer_received_bytes_total bytes received including UDP/IP headers
+# TYPE lockstep_peer_received_bytes_total counter
+lockstep_peer_received_bytes_total{index="1",address="127.0.0.1:7000"} 2000
+# HELP lockstep_peer_sent_packets_total packets sent
+# TYPE lockstep_peer_sent_packets_total counter
+lockstep_peer_sent_packets_total{index="1",address="127.0.0.1:7000"} 10
+# HELP lockstep_peer_received_packets_total packets received
+# TYPE lockstep_peer_received_packets_total counter
+lockstep_peer_received_packets_total{index="1",address="127.0.0.1:7000"} 20
+# HELP lockstep_peer_sent_bytes_per_second low pass filtered send rate
+# TYPE lockstep_peer_sent_bytes_per_second gauge
+lockstep_peer_sent_bytes_per_second{index="1",address="127.0.0.1:7000"} 100.5
+# HELP lockstep_peer_received_bytes_per_second low pass filtered receive rate
+# TYPE lockstep_peer_received_bytes_per_second gauge
+lockstep_peer_received_bytes_per_second{index="1",address="127.0.0.1:7000"} 200.25
+# HELP lockstep_peer_sent_budget_percent share of the send bandwidth budget in use
+# TYPE lockstep_peer_sent_budget_percent gauge
+lockstep_peer_sent_budget_percent{index="1",address="127.0.0.1:7000"} 12.5
+# HELP lockstep_peer_received_budget_percent share of the receive bandwidth budget in use
+# TYPE lockstep_peer_received_budget_percent gauge
+lockstep_peer_received_budget_percent{index="1",address="127.0.0.1:7000"} 25
+# HELP lockstep_peer_rtt_seconds average round trip time
+# TYPE lockstep_peer_rtt_seconds gauge
+lockstep_peer_rtt_seconds{index="1",address="127.0.0.1:7000"} 0.05
+# HELP lockstep_peer_jitter_seconds average deviation of round trip times
+# TYPE lockstep_peer_jitter_seconds gauge
+lockstep_peer_jitter_seconds{index="1",address="127.0.0.1:7000"} 0.5
+# HELP lockstep_peer_loss_ratio average share of acked packets that were resent
+# TYPE lockstep_peer_loss_ratio gauge
+lockstep_peer_loss_ratio{index="1",address="127.0.0.1:7000"} 0.25
+"#;
+}
@@ -1,6 +1,20 @@
+pub mod access;
+#[cfg(feature = "tokio")]
+pub mod async_io;
+pub mod batched;
 pub mod buffer;
+pub mod capture;
 pub mod client;
+pub mod config;
+pub mod metrics;
+pub mod nat;
 pub mod network;
+pub mod peer;
+pub mod rate_limit;
 pub mod reliable_ordered;
+pub mod rendezvous;
 pub mod server;
+pub mod spsc;
 pub mod stream;
+pub mod threaded;
+pub mod transport;
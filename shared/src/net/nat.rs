@@ -0,0 +1,223 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashSet,
+    io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+};
+
+use crate::net::{network::bind_socket, transport::Transport};
+
+/// How a NAT maps and filters, as far as punching through it is concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NatKind {
+    /// a single public port for all destinations, admitting only sources we've sent to; the most
+    /// common kind of home router, which punching gets through
+    PortRestrictedCone,
+    /// a public port per destination, each admitting only its destination; punching can't get
+    /// through, so peers have to relay
+    Symmetric,
+}
+
+struct Mapping {
+    /// NOTE: None for the single mapping of a cone NAT
+    destination: Option<SocketAddr>,
+    socket: UdpSocket,
+}
+
+/// Emulates a NAT in front of a local socket, so that punching and relaying can be tested on a
+/// single machine; the public port of a mapping is the port of its local socket.
+///
+/// NOTE: mappings never expire, unlike those of real NATs
+pub struct NatTransport {
+    kind: NatKind,
+    ip: IpAddr,
+    mappings: RefCell<Vec<Mapping>>,
+    /// destinations we've sent to, the only sources a cone NAT admits
+    sent_to: RefCell<HashSet<SocketAddr>>,
+    /// datagrams the NAT filtered out
+    filtered: Cell<u64>,
+}
+
+impl NatTransport {
+    /// NOTE: `ip` is that of the local sockets backing the mappings, such as 127.0.0.1
+    pub fn bind(ip: IpAddr, kind: NatKind) -> io::Result<Self> {
+        let mut mappings = Vec::new();
+        if kind == NatKind::PortRestrictedCone {
+            mappings.push(Mapping {
+                destination: None,
+                socket: bind_socket(SocketAddr::new(ip, 0))?,
+            });
+        }
+        Ok(Self {
+            kind,
+            ip,
+            mappings: RefCell::new(mappings),
+            sent_to: RefCell::new(HashSet::new()),
+            filtered: Cell::new(0),
+        })
+    }
+
+    pub fn kind(&self) -> NatKind {
+        self.kind
+    }
+
+    pub fn filtered(&self) -> u64 {
+        self.filtered.get()
+    }
+
+    fn admits(&self, mapping: &Mapping, source: SocketAddr) -> bool {
+        match mapping.destination {
+            Some(destination) => source == destination,
+            None => self.sent_to.borrow().contains(&source),
+        }
+    }
+}
+
+impl Transport for NatTransport {
+    fn send_to(&self, buffer: &[u8], address: SocketAddr) -> io::Result<usize> {
+        let mut mappings = self.mappings.borrow_mut();
+        let index = match self.kind {
+            NatKind::PortRestrictedCone => {
+                self.sent_to.borrow_mut().insert(address);
+                0
+            }
+            NatKind::Symmetric => {
+                match mappings.iter().position(|m| m.destination == Some(address)) {
+                    Some(index) => index,
+                    None => {
+                        mappings.push(Mapping {
+                            destination: Some(address),
+                            socket: bind_socket(SocketAddr::new(self.ip, 0))?,
+                        });
+                        mappings.len() - 1
+                    }
+                }
+            }
+        };
+        mappings[index].socket.send_to(buffer, address)
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        for mapping in self.mappings.borrow().iter() {
+            loop {
+                match mapping.socket.recv_from(buffer) {
+                    Ok((size, source)) if self.admits(mapping, source) => {
+                        return Ok((size, source));
+                    }
+                    Ok(_) => self.filtered.set(self.filtered.get() + 1),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::net::{
+        client::ClientState,
+        config::NetConfig,
+        peer::{Peer, PeerEvent},
+        rendezvous::{RendezvousConfig, RendezvousServer},
+    };
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    /// Connects two players behind NATs of the given kind through a loopback rendezvous server.
+    fn connect(
+        kind: NatKind,
+        rendezvous_config: RendezvousConfig,
+    ) -> (RendezvousServer, Vec<Peer<NatTransport>>) {
+        let socket = bind_socket(SocketAddr::new(LOCALHOST, 0)).unwrap();
+        let address = socket.local_addr().unwrap();
+        let mut rendezvous = RendezvousServer::new(socket, rendezvous_config);
+
+        // NOTE: players learn of each other when they next register, which must come well before
+        // punching times out
+        let config = NetConfig {
+            punch_timeout: 0.5,
+            rendezvous_interval: 0.05,
+            ..NetConfig::default()
+        };
+        let mut peers: Vec<_> = (0..2)
+            .map(|index| {
+                let transport = NatTransport::bind(LOCALHOST, kind).unwrap();
+                Peer::with_rendezvous(transport, address, 1234, index, 2, config.clone())
+            })
+            .collect();
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let mut connected = [false; 2];
+        while !connected.iter().all(|&c| c) {
+            assert!(Instant::now() < deadline, "timed out");
+            rendezvous.process_packets();
+            for (index, peer) in peers.iter_mut().enumerate() {
+                while let Some(event) = peer.process_packets() {
+                    match event {
+                        PeerEvent::PeerConnected(_) => connected[index] = true,
+                        event => panic!("unexpected event {event:?}"),
+                    }
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        (rendezvous, peers)
+    }
+
+    #[test]
+    fn punching_gets_through_port_restricted_cones() {
+        let (rendezvous, peers) = connect(NatKind::PortRestrictedCone, RendezvousConfig::default());
+        assert_eq!(peers[0].state(1), Some(ClientState::Connected));
+        assert_eq!(peers[1].state(0), Some(ClientState::Connected));
+        assert!(!peers[0].is_relayed(1));
+        assert!(!peers[1].is_relayed(0));
+        assert_eq!(rendezvous.relayed_packets, 0);
+    }
+
+    #[test]
+    fn symmetric_nats_fall_back_to_relaying() {
+        let (rendezvous, peers) = connect(NatKind::Symmetric, RendezvousConfig::default());
+        assert_eq!(peers[0].state(1), Some(ClientState::Connected));
+        assert_eq!(peers[1].state(0), Some(ClientState::Connected));
+        assert!(peers[0].is_relayed(1));
+        assert!(peers[1].is_relayed(0));
+        assert!(rendezvous.relayed_packets > 0);
+    }
+
+    #[test]
+    fn relays_beyond_the_budget_of_a_player_are_dropped() {
+        let config = RendezvousConfig {
+            relay_bytes_per_second: 1000.,
+            relay_byte_burst: 8000.,
+            ..RendezvousConfig::default()
+        };
+        let (mut rendezvous, mut peers) = connect(NatKind::Symmetric, config);
+        let start = Instant::now();
+        let relayed_bytes = rendezvous.relayed_bytes;
+
+        for _ in 0..40 {
+            peers[0].write_payload(1, &[0; 400]);
+        }
+        while start.elapsed() < Duration::from_millis(200) {
+            rendezvous.process_packets();
+            for peer in &mut peers {
+                while peer.process_packets().is_some() {}
+                peer.drop_incoming();
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        assert!(rendezvous.relays_dropped > 0);
+        // NOTE: each player has a budget of its own
+        let budget = 2. * (8000. + 1000. * start.elapsed().as_secs_f64());
+        assert!(((rendezvous.relayed_bytes - relayed_bytes) as f64) < budget);
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    hash::{BuildHasher, RandomState},
     io,
     mem::size_of,
     net::{SocketAddr, UdpSocket},
@@ -10,28 +11,63 @@ use crate::{
     endian::Endian,
     net::{
         buffer::Buffer,
-        stream::{Stream, Streamable},
+        rendezvous::{PeersPacket, PunchPacket, RelayPacket},
+        stream::{Stream, Streamable, WriteStream},
     },
 };
 
 /// in bytes
 pub const UDP_IP_HEADER_SIZE: u32 = 28;
 
-pub const PROTOCOL_ID: u32 = u32::from_le_bytes(*b"MAJG");
-pub const PROTOCOL_VERSION: u16 = 1;
-pub const SERVER_PORT: u16 = 4321;
+/// the newest protocol version we speak, used until a connection negotiates one
+pub const PROTOCOL_VERSION: u16 = 7;
+/// the oldest protocol version we still speak; raise it to drop support for old peers
+/// NOTE: some version 4 peers sequence their keep-alives, which we'd never ack, stalling delivery
+pub const MIN_PROTOCOL_VERSION: u16 = 5;
+/// the first protocol version with direct messages between clients
+pub const DIRECT_MESSAGE_VERSION: u16 = 5;
+/// the first protocol version with spectators; older servers seat them as players
+pub const SPECTATOR_VERSION: u16 = 7;
+pub const SUPPORTED_VERSIONS: VersionRange = VersionRange {
+    min: MIN_PROTOCOL_VERSION,
+    max: PROTOCOL_VERSION,
+};
 /// NOTE: lower than PMTU limitation 548
 pub const PACKET_BUFFER_SIZE: usize = 512;
-pub const CONNECTION_TIMEOUT_DURATION: f64 = 1.;
-pub const NETWORK_FPS: f64 = 100.;
-pub const PACKET_RESEND_FRAME_INTERVAL: u16 = 10;
-
-/// Our target max Bps usage both up and down for a server
-pub const MAX_BITS_PER_SECOND: f64 = 1e6;
-pub const MAX_SERVER_BYTES_PER_SECOND: f64 = MAX_BITS_PER_SECOND / 8.;
-pub const MAX_CLIENTS: u8 = 8;
-pub const MAX_CLIENT_BYTES_PER_SECOND: f64 = MAX_SERVER_BYTES_PER_SECOND / MAX_CLIENTS as f64;
-pub const PRINT_NETWORK_STATS: bool = true;
+pub const PACKET_HEADER_SIZE: usize = 16;
+/// NOTE: requests are padded to the largest handshake reply, so that no response to an
+/// unverified address is larger than the request provoking it
+pub const CONNECTION_REQUEST_SIZE: usize = PACKET_HEADER_SIZE
+    + max(
+        size_of::<ConnectionRequestPacket>(),
+        max(
+            size_of::<ConnectionDeniedPacket>(),
+            size_of::<ConnectionAcceptedPacket>(),
+        ),
+    );
+/// NOTE: registrations are padded to a full packet, as the reply lists the peers of a session
+pub const RENDEZVOUS_REGISTER_SIZE: usize = PACKET_BUFFER_SIZE;
+/// header of relayed packets, in front of the packet being relayed
+pub const RELAY_HEADER_SIZE: usize = PACKET_HEADER_SIZE + size_of::<RelayPacket>();
+/// NOTE: relayed packets are larger than the packets they carry, but still lower than PMTU
+/// limitation 548
+pub const MAX_DATAGRAM_SIZE: usize = RELAY_HEADER_SIZE + PACKET_BUFFER_SIZE;
+/// header of direct messages, in front of their payload
+pub const DIRECT_MESSAGE_HEADER_SIZE: usize = PACKET_HEADER_SIZE + 8;
+/// byte offset of `PacketHeader::packet_type`
+pub const PACKET_TYPE_OFFSET: usize = 6;
+/// byte offset of `PacketHeader::ack`
+pub const PACKET_ACK_OFFSET: usize = 10;
+/// byte offset of `PacketHeader::ack_bits`
+pub const PACKET_ACK_BITS_OFFSET: usize = 12;
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
 
 pub fn bind_socket(address: SocketAddr) -> io::Result<UdpSocket> {
     let socket = UdpSocket::bind(address)?;
@@ -39,6 +75,109 @@ pub fn bind_socket(address: SocketAddr) -> io::Result<UdpSocket> {
     Ok(socket)
 }
 
+/// An inclusive range of protocol versions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VersionRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl VersionRange {
+    pub fn contains(&self, version: u16) -> bool {
+        (self.min..=self.max).contains(&version)
+    }
+
+    /// The highest version both sides speak, if any.
+    pub fn negotiate(&self, other: &VersionRange) -> Option<u16> {
+        let version = self.max.min(other.max);
+        (version >= self.min.max(other.min)).then_some(version)
+    }
+}
+
+impl std::fmt::Display for VersionRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}..={}", self.min, self.max)
+    }
+}
+
+impl Streamable for VersionRange {
+    fn stream<S: Stream>(&mut self, s: &mut S) {
+        s.copy(&mut self.min);
+        s.copy(&mut self.max);
+    }
+}
+
+/// What a client connects as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Role {
+    Player = 0,
+    /// NOTE: takes no player seat, and only listens; the server drops what spectators send
+    Spectator = 1,
+}
+
+/// NOTE: the layout of requests, as that of the header, must never change between versions, as
+/// it's read before a version is negotiated; the rest of the request is padding, which fields
+/// may only be added into if zero means what older versions did.
+pub struct ConnectionRequestPacket {
+    pub versions: VersionRange,
+    /// NOTE: kept as a byte, as an unknown role must not be undefined behaviour
+    pub role: u8,
+    /// echoed by denials, so that third parties can't deny the request; zero in older versions
+    pub nonce: u32,
+}
+
+impl ConnectionRequestPacket {
+    /// Writes a request for our supported versions, padded to `CONNECTION_REQUEST_SIZE`.
+    pub fn write_padded(w: &mut WriteStream, role: Role, nonce: u32) {
+        let versions = match role {
+            Role::Player => SUPPORTED_VERSIONS,
+            // NOTE: older servers don't know the role, and would seat us as a player, so they
+            // must deny us instead
+            Role::Spectator => VersionRange {
+                min: SPECTATOR_VERSION,
+                max: SUPPORTED_VERSIONS.max,
+            },
+        };
+        ConnectionRequestPacket {
+            versions,
+            role: role as u8,
+            nonce,
+        }
+        .stream(w);
+        w.0.write_slice(
+            &[0; CONNECTION_REQUEST_SIZE
+                - PACKET_HEADER_SIZE
+                - size_of::<ConnectionRequestPacket>()],
+        );
+    }
+
+    pub fn role(&self) -> Option<Role> {
+        match self.role {
+            0 => Some(Role::Player),
+            1 => Some(Role::Spectator),
+            _ => None,
+        }
+    }
+}
+
+impl Streamable for ConnectionRequestPacket {
+    fn stream<S: Stream>(&mut self, s: &mut S) {
+        self.versions.stream(s);
+        s.copy(&mut self.role);
+        s.copy(&mut 0u8); // NOTE: padding, to match the in-memory size
+        s.copy(&mut 0u16);
+        s.copy(&mut self.nonce);
+    }
+}
+
+/// A nonzero number for a request to be identified by, which third parties can't predict.
+pub fn request_nonce() -> u32 {
+    let nonce = RandomState::new().hash_one(Instant::now()) as u32;
+    nonce.max(1)
+}
+
+/// NOTE: the negotiated version is the header version of the accepting packet
 pub struct ConnectionAcceptedPacket {
     pub index: u8,
 }
@@ -55,28 +194,148 @@ impl Streamable for ConnectionAcceptedPacket {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DenyReason {
+    ServerFull = 0,
+    Banned = 1,
+    NotAllowed = 2,
+    UnsupportedVersion = 3,
+    UnknownRole = 4,
+    SpectatorsFull = 5,
+}
+
+impl std::fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DenyReason::ServerFull => write!(f, "server is full"),
+            DenyReason::Banned => write!(f, "address is banned"),
+            DenyReason::NotAllowed => write!(f, "address is not on the allow list"),
+            DenyReason::UnsupportedVersion => write!(f, "no protocol version in common"),
+            DenyReason::UnknownRole => write!(f, "unknown role"),
+            DenyReason::SpectatorsFull => write!(f, "no spectator seats left"),
+        }
+    }
+}
+
+/// NOTE: like requests, the layout of denials must never change between versions
+pub struct ConnectionDeniedPacket {
+    /// NOTE: kept as a byte, as an unknown reason must not be undefined behaviour
+    pub reason: u8,
+    /// the versions the server speaks
+    pub versions: VersionRange,
+}
+
+impl ConnectionDeniedPacket {
+    pub fn new(reason: DenyReason) -> Self {
+        Self {
+            reason: reason as u8,
+            versions: SUPPORTED_VERSIONS,
+        }
+    }
+
+    pub fn reason(&self) -> Option<DenyReason> {
+        match self.reason {
+            0 => Some(DenyReason::ServerFull),
+            1 => Some(DenyReason::Banned),
+            2 => Some(DenyReason::NotAllowed),
+            3 => Some(DenyReason::UnsupportedVersion),
+            4 => Some(DenyReason::UnknownRole),
+            5 => Some(DenyReason::SpectatorsFull),
+            _ => None,
+        }
+    }
+}
+
+impl Streamable for ConnectionDeniedPacket {
+    fn stream<S: Stream>(&mut self, s: &mut S) {
+        s.copy(&mut self.reason);
+        s.copy(&mut 0u8); // NOTE: padding, to match the in-memory size
+        self.versions.stream(s);
+    }
+}
+
+/// Followed by the payload of a message between two clients, relayed by the server.
+///
+/// NOTE: padded to 8 bytes, so that the payload is as aligned as that of user payloads
+pub struct DirectMessagePacket {
+    /// player index of the receiver from a client, and of the sender from the server
+    pub index: u8,
+}
+
+impl Streamable for DirectMessagePacket {
+    fn stream<S: Stream>(&mut self, s: &mut S) {
+        s.copy(&mut self.index);
+        s.copy(&mut 0u8); // NOTE: padding
+        s.copy(&mut 0u16);
+        s.copy(&mut 0u32);
+    }
+}
+
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 #[repr(u8)]
 pub enum PacketType {
     #[default]
     ConnectionRequest = 0,
+    /// NOTE: unreliable, as it's sent without an endpoint
+    ConnectionDenied = 1,
     // ConnectionChallenge = 2,
     ConnectionAccepted = 3,
     ConnectionKeepAlive = 4,
     UserPayload = 5,
     // Disconnect = 6
+    /// NOTE: padded like requests, so that the reply of a rendezvous server is never larger
+    RendezvousRegister = 7,
+    RendezvousPeers = 8,
+    Punch = 9,
+    /// NOTE: carries a whole packet between peers, through the rendezvous server
+    Relay = 10,
+    /// NOTE: reliable and ordered along with user payloads, as the server forwards it
+    DirectMessage = 11,
 }
 
 impl PacketType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PacketType::ConnectionRequest),
+            1 => Some(PacketType::ConnectionDenied),
+            3 => Some(PacketType::ConnectionAccepted),
+            4 => Some(PacketType::ConnectionKeepAlive),
+            5 => Some(PacketType::UserPayload),
+            7 => Some(PacketType::RendezvousRegister),
+            8 => Some(PacketType::RendezvousPeers),
+            9 => Some(PacketType::Punch),
+            10 => Some(PacketType::Relay),
+            11 => Some(PacketType::DirectMessage),
+            _ => None,
+        }
+    }
+
     pub fn valid_size_range(self) -> (usize, usize) {
         match self {
-            PacketType::ConnectionRequest => (16, 16),
+            // NOTE: older versions padded requests to a full packet
+            PacketType::ConnectionRequest => (CONNECTION_REQUEST_SIZE, PACKET_BUFFER_SIZE),
+            PacketType::ConnectionDenied => (
+                PACKET_HEADER_SIZE + size_of::<ConnectionDeniedPacket>(),
+                PACKET_HEADER_SIZE + size_of::<ConnectionDeniedPacket>(),
+            ),
             PacketType::ConnectionAccepted => (
-                16 + size_of::<ConnectionAcceptedPacket>(),
-                16 + size_of::<ConnectionAcceptedPacket>(),
+                PACKET_HEADER_SIZE + size_of::<ConnectionAcceptedPacket>(),
+                PACKET_HEADER_SIZE + size_of::<ConnectionAcceptedPacket>(),
+            ),
+            PacketType::ConnectionKeepAlive => (PACKET_HEADER_SIZE, PACKET_HEADER_SIZE),
+            PacketType::UserPayload => (PACKET_HEADER_SIZE, PACKET_BUFFER_SIZE), // TODO: upper bound
+            PacketType::RendezvousRegister => (RENDEZVOUS_REGISTER_SIZE, RENDEZVOUS_REGISTER_SIZE),
+            PacketType::RendezvousPeers => (
+                PACKET_HEADER_SIZE + size_of::<PeersPacket>(),
+                PACKET_BUFFER_SIZE,
+            ),
+            PacketType::Punch => (
+                PACKET_HEADER_SIZE + size_of::<PunchPacket>(),
+                PACKET_HEADER_SIZE + size_of::<PunchPacket>(),
             ),
-            PacketType::ConnectionKeepAlive => (16, 16),
-            PacketType::UserPayload => (16, 512), // TODO: upper bound
+            PacketType::Relay => (RELAY_HEADER_SIZE + PACKET_HEADER_SIZE, MAX_DATAGRAM_SIZE),
+            PacketType::DirectMessage => (DIRECT_MESSAGE_HEADER_SIZE, PACKET_BUFFER_SIZE),
         }
     }
 
@@ -153,10 +412,11 @@ impl PartialOrd for NetworkSeq {
 
 #[derive(Debug, Default)]
 pub struct PacketHeader {
-    /// crc32 checksum of whole packet with this field set to PROTOCOL_ID; must match in packet
+    /// crc32 checksum of whole packet with this field set to the protocol id; must match in packet
     /// integrity check
     pub checksum: u32,
-    /// protocol version, must match in packet integrity check
+    /// protocol version the packet is written in; payloads of unsupported versions fail the packet
+    /// integrity check
     pub version: u16,
     /// helps determine valid packet size
     pub packet_type: PacketType,
@@ -170,14 +430,16 @@ pub struct PacketHeader {
 
 impl PacketHeader {
     pub fn new(
+        protocol_id: u32,
+        version: u16,
         packet_type: PacketType,
         seq: NetworkSeq,
         ack: NetworkSeq,
         ack_bits: u32,
     ) -> PacketHeader {
         PacketHeader {
-            checksum: PROTOCOL_ID,
-            version: PROTOCOL_VERSION,
+            checksum: protocol_id,
+            version,
             packet_type,
             seq,
             ack,
@@ -198,8 +460,20 @@ impl Streamable for PacketHeader {
     }
 }
 
+/// crc32 of a whole packet, as if its checksum field was set to `protocol_id`
+pub fn packet_checksum(packet: &[u8], protocol_id: u32) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&protocol_id.to_le_bytes());
+    hasher.update(&packet[size_of::<u32>()..]);
+    hasher.finalize()
+}
+
 pub struct SendPacket {
     pub first_send_time: Option<Instant>,
+    /// when the packet was last sent, from which its resend interval runs
+    pub last_send_time: Option<Instant>,
+    /// number of times the packet has been sent
+    pub send_count: u16,
     pub buffer: Buffer,
 }
 
@@ -207,6 +481,8 @@ impl Default for SendPacket {
     fn default() -> Self {
         Self {
             first_send_time: None,
+            last_send_time: None,
+            send_count: 0,
             buffer: Buffer::with_capacity(PACKET_BUFFER_SIZE),
         }
     }
@@ -254,14 +530,16 @@ impl<T: DefaultArray> SequenceBuffer<T> {
     }
 
     pub fn dbg_print(&self) {
-        for i in 0..NetworkSeq::COUNT {
-            if self.contains(NetworkSeq(i as u16)) {
-                print!("1");
-            } else {
-                print!("0");
-            }
-        }
-        println!();
+        let sequences = (0..NetworkSeq::COUNT)
+            .map(|i| {
+                if self.contains(NetworkSeq(i as u16)) {
+                    '1'
+                } else {
+                    '0'
+                }
+            })
+            .collect::<String>();
+        tracing::debug!(%sequences, "sequence buffer");
     }
 
     pub fn contains(&self, seq: NetworkSeq) -> bool {
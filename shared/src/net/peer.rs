@@ -0,0 +1,1233 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    mem::size_of,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    time::Instant,
+};
+
+use tracing::{debug, info, trace, trace_span, warn};
+
+use crate::{
+    net::{
+        buffer::Buffer,
+        client::ClientState,
+        config::NetConfig,
+        metrics::{ConnectionInfo, EndpointMetrics, MetricsSink, NetworkMetrics},
+        network::{
+            request_nonce, ConnectionAcceptedPacket, ConnectionDeniedPacket,
+            ConnectionRequestPacket, DenyReason, PacketHeader, PacketType, Role, VersionRange,
+            MAX_DATAGRAM_SIZE, PACKET_BUFFER_SIZE, PROTOCOL_VERSION, SUPPORTED_VERSIONS,
+        },
+        reliable_ordered::{
+            EndpointSendStats, EndpointState, ReliableOrderedDatagramEndpoint, TimeoutWarning,
+        },
+        rendezvous::{
+            send_unsequenced, AddressPacket, PeersPacket, PunchPacket, RegisterPacket, RelayPacket,
+            RelayTransport, MAX_SESSION_PLAYERS,
+        },
+        server::send_denial,
+        stream::{ReadStream, Stream, Streamable},
+        transport::Transport,
+    },
+    timing::FrameDurationAccumulator,
+};
+
+/// How our packets reach another player.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Route {
+    /// waiting for the rendezvous server to tell us the public address of the player
+    Unknown,
+    /// punching the public address of the player, until one of its packets gets through our NAT
+    Punching {
+        since: Instant,
+    },
+    Direct,
+    /// through the rendezvous server, after punching timed out
+    Relayed,
+}
+
+/// Our connection to another player of the session.
+struct Link {
+    /// NOTE: a link we accept waits in `Connecting` until the request arrives
+    state: ClientState,
+    /// whether we request the connection, rather than accept it
+    requesting: bool,
+    /// of our outstanding request, which denials must echo
+    request_nonce: u32,
+    route: Route,
+    last_punch: Option<Instant>,
+    endpoint: ReliableOrderedDatagramEndpoint,
+}
+
+impl Link {
+    fn new(
+        address: SocketAddr,
+        requesting: bool,
+        route: Route,
+        bytes_per_second: f64,
+        config: &NetConfig,
+    ) -> Self {
+        Self {
+            state: if requesting {
+                ClientState::ConnectionRequest
+            } else {
+                ClientState::Connecting
+            },
+            requesting,
+            request_nonce: 0,
+            route,
+            last_punch: None,
+            endpoint: ReliableOrderedDatagramEndpoint::new(address, bytes_per_second, config),
+        }
+    }
+
+    /// Whether we have anything to send yet; a link we accept stays silent until requested.
+    fn is_active(&self) -> bool {
+        if let Route::Unknown | Route::Punching { .. } = self.route {
+            return false;
+        }
+        match self.state {
+            ClientState::ConnectionRequest | ClientState::Connected => true,
+            ClientState::Connecting => self.requesting,
+            ClientState::Denied => false,
+        }
+    }
+
+    /// Whether to punch the player; once we hear from it, a link we accept keeps punching until
+    /// the request arrives, as the player may not have heard from us yet.
+    fn is_punching(&self) -> bool {
+        match self.route {
+            Route::Punching { .. } => true,
+            Route::Direct => !self.requesting && self.state == ClientState::Connecting,
+            Route::Unknown | Route::Relayed => false,
+        }
+    }
+}
+
+/// Our registration with the rendezvous server, which tells us the public addresses of the other
+/// players, and relays to those we can't punch through to.
+struct Rendezvous {
+    address: SocketAddr,
+    /// chosen by the players of a session, such as a lobby code
+    session: u32,
+    last_register: Option<Instant>,
+    /// NOTE: in a RefCell, as transports send through a shared reference
+    relay_buffer: RefCell<Buffer>,
+}
+
+impl Rendezvous {
+    fn relay<'a, T: Transport>(
+        &'a self,
+        socket: &'a T,
+        protocol_id: u32,
+        from: u8,
+        to: usize,
+    ) -> RelayTransport<'a, T> {
+        RelayTransport {
+            socket,
+            rendezvous: self.address,
+            protocol_id,
+            relay: RelayPacket {
+                session: self.session,
+                from,
+                to: to as u8,
+            },
+            buffer: &self.relay_buffer,
+        }
+    }
+}
+
+/// Connects every player of a session directly to every other player, without a game server in
+/// between.
+///
+/// NOTE: of each pair, the player with the higher index requests the connection and the other one
+/// accepts it, so that each pair handshakes exactly once.
+pub struct Peer<T: Transport = UdpSocket> {
+    /// our player index in the session
+    pub index: u8,
+    config: NetConfig,
+    /// max bytes per second to send, including UDP/IP header size
+    bytes_per_second: f64,
+    /// NOTE: like the server budget, split evenly among connected peers
+    pub peer_bytes_per_second: f64,
+    pub(crate) socket: T,
+    swap_buffer: Buffer,
+    /// NOTE: relayed datagrams are larger than the swap buffer, so every datagram lands here
+    /// first
+    datagram_buffer: Buffer,
+    /// NOTE: indexed by player index, with None at our own
+    links: Vec<Option<Link>>,
+    /// player index of each peer address
+    slots: HashMap<SocketAddr, usize>,
+    /// NOTE: None for sessions of known addresses
+    rendezvous: Option<Rendezvous>,
+    pub(crate) timing: FrameDurationAccumulator,
+    metrics: NetworkMetrics,
+    metrics_sinks: Vec<Box<dyn MetricsSink + Send>>,
+    /// NOTE: several peers may connect or time out within the same call
+    events: VecDeque<PeerEvent>,
+}
+
+#[derive(Debug)]
+pub enum PeerEvent {
+    PeerConnected(u8),
+    PeerTimeout(u8),
+    /// NOTE: the peer is close to timing out, and may or may not recover
+    PeerConnectionProblem(u8),
+    PeerConnectionRecovered(u8),
+    /// NOTE: we stop trying to connect to a peer that denied us
+    PeerDenied {
+        index: u8,
+        /// NOTE: None if the peer gave a reason we don't know
+        reason: Option<DenyReason>,
+        /// the protocol versions the peer speaks
+        peer_versions: VersionRange,
+    },
+}
+
+impl<T: Transport> Peer<T> {
+    /// NOTE: `addresses` holds the address of every player in the session by index, including
+    /// our own at `index`, which is ignored.
+    pub fn new(socket: T, index: u8, addresses: &[SocketAddr], config: NetConfig) -> Peer<T> {
+        let mut peer = Peer::with_links(socket, index, addresses.len(), None, config);
+        for (other, &address) in addresses.iter().enumerate() {
+            peer.set_address(other, address, Route::Direct);
+        }
+        peer
+    }
+
+    /// Like `new`, for players behind NATs; the rendezvous server tells us the public addresses
+    /// of the other players, which we punch through to, or relay to through the rendezvous
+    /// server once punching times out.
+    ///
+    /// NOTE: `session` is chosen by the players, such as a lobby code, and all of them must agree
+    /// on it and on the number of `players`
+    pub fn with_rendezvous(
+        socket: T,
+        rendezvous: SocketAddr,
+        session: u32,
+        index: u8,
+        players: usize,
+        config: NetConfig,
+    ) -> Peer<T> {
+        assert!(
+            players <= MAX_SESSION_PLAYERS,
+            "the rendezvous server takes at most {MAX_SESSION_PLAYERS} players per session"
+        );
+        let rendezvous = Rendezvous {
+            address: rendezvous,
+            session,
+            last_register: None,
+            relay_buffer: RefCell::new(Buffer::with_capacity(MAX_DATAGRAM_SIZE)),
+        };
+        Peer::with_links(socket, index, players, Some(rendezvous), config)
+    }
+
+    fn with_links(
+        socket: T,
+        index: u8,
+        players: usize,
+        rendezvous: Option<Rendezvous>,
+        config: NetConfig,
+    ) -> Peer<T> {
+        assert!(
+            (index as usize) < players,
+            "our index must be within the session"
+        );
+        assert!(
+            players <= u8::MAX as usize,
+            "player indices must fit a byte"
+        );
+        let bytes_per_second = config.server_bytes_per_second;
+
+        // NOTE: addresses are filled in by the caller, or by the rendezvous server
+        let unknown = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        let links = (0..players)
+            .map(|other| {
+                let requesting = index as usize > other;
+                (other != index as usize).then(|| {
+                    Link::new(
+                        unknown,
+                        requesting,
+                        Route::Unknown,
+                        bytes_per_second,
+                        &config,
+                    )
+                })
+            })
+            .collect();
+
+        Peer {
+            index,
+            bytes_per_second,
+            peer_bytes_per_second: bytes_per_second,
+            socket,
+            swap_buffer: Buffer::with_capacity(PACKET_BUFFER_SIZE),
+            datagram_buffer: Buffer::with_capacity(MAX_DATAGRAM_SIZE),
+            links,
+            slots: HashMap::with_capacity(players),
+            rendezvous,
+            timing: FrameDurationAccumulator::with_fps(config.fps, 0.25),
+            metrics: NetworkMetrics::with_capacity(players),
+            metrics_sinks: Vec::new(),
+            events: VecDeque::with_capacity(2 * players),
+            config,
+        }
+    }
+
+    /// Starts over on the link to a player at a new address; only for links that aren't
+    /// connected.
+    fn set_address(&mut self, index: usize, address: SocketAddr, route: Route) {
+        let Some(link) = &mut self.links[index] else {
+            return;
+        };
+        self.slots.remove(&link.endpoint.address);
+        self.slots.insert(address, index);
+        *link = Link::new(
+            address,
+            link.requesting,
+            route,
+            self.peer_bytes_per_second,
+            &self.config,
+        );
+    }
+
+    pub fn config(&self) -> &NetConfig {
+        &self.config
+    }
+
+    /// Number of players in the session, including us.
+    pub fn players(&self) -> usize {
+        self.links.len()
+    }
+
+    /// Overrides `NetConfig::server_bytes_per_second`; more players need a larger budget.
+    pub fn set_bytes_per_second(&mut self, bytes_per_second: f64) {
+        self.bytes_per_second = bytes_per_second;
+        self.rebalance_budget();
+    }
+
+    fn rebalance_budget(&mut self) {
+        let connected = self
+            .links()
+            .filter(|link| link.state == ClientState::Connected)
+            .count();
+        self.peer_bytes_per_second = self.bytes_per_second / connected.max(1) as f64;
+        for link in self.links.iter_mut().flatten() {
+            link.endpoint.bytes_per_second = self.peer_bytes_per_second;
+        }
+    }
+
+    fn links(&self) -> impl Iterator<Item = &Link> {
+        self.links.iter().flatten()
+    }
+
+    fn connected_link(&mut self, index: usize) -> Option<&mut Link> {
+        self.links
+            .get_mut(index)?
+            .as_mut()
+            .filter(|link| link.state == ClientState::Connected)
+    }
+
+    /// The snapshot as of the latest network frame, with endpoints indexed by player index.
+    pub fn metrics(&self) -> &NetworkMetrics {
+        &self.metrics
+    }
+
+    /// Hands the metrics snapshot to `sink` every network frame.
+    pub fn add_metrics_sink<M: MetricsSink + Send + 'static>(&mut self, sink: M) {
+        self.metrics_sinks.push(Box::new(sink));
+    }
+
+    /// The state of our connection to a player; None for ourselves.
+    pub fn state(&self, index: usize) -> Option<ClientState> {
+        Some(self.links.get(index)?.as_ref()?.state)
+    }
+
+    /// Whether our packets to a player go through the rendezvous server.
+    pub fn is_relayed(&self, index: usize) -> bool {
+        self.links
+            .get(index)
+            .and_then(Option::as_ref)
+            .is_some_and(|link| link.route == Route::Relayed)
+    }
+
+    /// The protocol version negotiated with a connected peer.
+    pub fn version(&self, index: usize) -> Option<u16> {
+        let link = self.links.get(index)?.as_ref()?;
+        (link.state == ClientState::Connected).then_some(link.endpoint.version)
+    }
+
+    /// RTT, loss, bandwidth and quality of the connection to a player, such as for ping bars;
+    /// None for ourselves.
+    pub fn connection_info(&self, index: usize) -> Option<ConnectionInfo> {
+        let link = self.links.get(index)?.as_ref()?;
+        let traffic = self.metrics.endpoints[index].as_ref().map(|m| &m.traffic);
+        Some(link.endpoint.connection_info(link.state, traffic))
+    }
+
+    /// Blocks until a datagram arrives, the next network frame is due or the deadline passes,
+    /// whichever comes first.
+    pub fn wait_for_activity(&self, deadline: Option<Instant>) {
+        let network_deadline = self.timing.next_frame_deadline();
+        let deadline = deadline.map_or(network_deadline, |d| d.min(network_deadline));
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if !timeout.is_zero() {
+            self.socket
+                .wait_readable(timeout)
+                .expect("socket poll io error");
+        }
+    }
+
+    pub fn process_packets(&mut self) -> Option<PeerEvent> {
+        self.punch_and_register();
+
+        // NOTE: the request must be the first packet we create on a link, as requests are only
+        // accepted with the first sequence number
+        for link in self.links.iter_mut().flatten() {
+            if link.state == ClientState::ConnectionRequest {
+                // NOTE: peers are all players
+                let nonce = request_nonce();
+                link.endpoint
+                    .write_packet(PacketType::ConnectionRequest, |w| {
+                        ConnectionRequestPacket::write_padded(w, Role::Player, nonce)
+                    });
+                link.request_nonce = nonce;
+                link.state = ClientState::Connecting;
+            }
+        }
+
+        let mut timed_out = false;
+
+        self.timing.run_frame(|frame| {
+            let _span = trace_span!("network_frame", frame = frame.index).entered();
+
+            let mut stats = EndpointSendStats::default();
+
+            for (index, slot) in self.links.iter_mut().enumerate() {
+                let Some(link) = slot else {
+                    continue;
+                };
+                if !link.is_active() {
+                    continue;
+                }
+
+                let address = link.endpoint.address;
+                let state = match (link.route, &self.rendezvous) {
+                    (Route::Relayed, Some(rendezvous)) => link.endpoint.send_outstanding(
+                        &rendezvous.relay(&self.socket, self.config.protocol_id, self.index, index),
+                    ),
+                    _ => link.endpoint.send_outstanding(&self.socket),
+                };
+                match state {
+                    EndpointState::Ok(endpoint_stats, warning) => {
+                        stats += &endpoint_stats;
+                        if let Some(metrics) = &mut self.metrics.endpoints[index] {
+                            metrics.record_frame(
+                                &endpoint_stats,
+                                frame.dt,
+                                link.endpoint.bytes_per_second,
+                            );
+                        }
+                        match warning {
+                            Some(TimeoutWarning::Raised) => {
+                                warn!(index, %address, "peer connection problem");
+                                self.events
+                                    .push_back(PeerEvent::PeerConnectionProblem(index as u8));
+                            }
+                            Some(TimeoutWarning::Cleared) => {
+                                info!(index, %address, "peer connection recovered");
+                                self.events
+                                    .push_back(PeerEvent::PeerConnectionRecovered(index as u8));
+                            }
+                            None => {}
+                        }
+                    }
+                    EndpointState::ConnectionTimeout(timeout) => {
+                        warn!(index, %address, ?timeout, "peer timed out");
+                        self.events.push_back(PeerEvent::PeerTimeout(index as u8));
+                        timed_out = true;
+                        self.metrics.endpoints[index] = None;
+                        // NOTE: a fresh link, so that the handshake starts over from the first
+                        // sequence number; behind NATs, we punch again, as the mappings may
+                        // have changed
+                        let route = match self.rendezvous {
+                            Some(_) => Route::Punching {
+                                since: Instant::now(),
+                            },
+                            None => Route::Direct,
+                        };
+                        *link = Link::new(
+                            address,
+                            link.requesting,
+                            route,
+                            self.peer_bytes_per_second,
+                            &self.config,
+                        );
+                    }
+                }
+            }
+
+            if let Err(e) = self.socket.flush() {
+                warn!(error = %e, "failed to send datagrams");
+            }
+
+            self.metrics
+                .record_frame(frame.index, frame.dt, &stats, self.bytes_per_second);
+            for sink in &mut self.metrics_sinks {
+                sink.record(&self.metrics);
+            }
+        });
+
+        if timed_out {
+            self.rebalance_budget();
+        }
+
+        if let Some((header, index, relayed)) = self.receive_session_packet() {
+            self.handle_packet(header, index, relayed);
+        }
+
+        self.accept_connections();
+
+        self.events.pop_front()
+    }
+
+    /// Registers with the rendezvous server and punches the players we haven't heard from, when
+    /// due.
+    ///
+    /// NOTE: neither counts against the send budget, as both are small and infrequent
+    fn punch_and_register(&mut self) {
+        let Some(rendezvous) = &mut self.rendezvous else {
+            return;
+        };
+        let now = Instant::now();
+        let protocol_id = self.config.protocol_id;
+        let session = rendezvous.session;
+
+        if rendezvous.last_register.is_none_or(|last| {
+            now.duration_since(last).as_secs_f64() >= self.config.rendezvous_interval
+        }) {
+            rendezvous.last_register = Some(now);
+            let mut request = RegisterPacket {
+                session,
+                index: self.index,
+                players: self.links.len() as u8,
+            };
+            send_unsequenced(
+                &self.socket,
+                &mut self.datagram_buffer,
+                protocol_id,
+                PacketType::RendezvousRegister,
+                rendezvous.address,
+                |w| request.write_padded(w),
+            );
+        }
+
+        for (index, slot) in self.links.iter_mut().enumerate() {
+            let Some(link) = slot else {
+                continue;
+            };
+            let address = link.endpoint.address;
+            if let Route::Punching { since } = link.route {
+                if now.duration_since(since).as_secs_f64() >= self.config.punch_timeout {
+                    warn!(index, %address, "punching timed out, relaying through the rendezvous server");
+                    link.route = Route::Relayed;
+                    continue;
+                }
+            }
+            if !link.is_punching()
+                || link.last_punch.is_some_and(|last| {
+                    now.duration_since(last).as_secs_f64() < self.config.punch_interval
+                })
+            {
+                continue;
+            }
+            link.last_punch = Some(now);
+            let own_index = self.index;
+            send_unsequenced(
+                &self.socket,
+                &mut self.datagram_buffer,
+                protocol_id,
+                PacketType::Punch,
+                address,
+                |w| {
+                    PunchPacket {
+                        session,
+                        index: own_index,
+                    }
+                    .stream(w)
+                },
+            );
+        }
+    }
+
+    /// Receives a datagram from a player of the session, directly or relayed, and validates it.
+    fn receive_session_packet(&mut self) -> Option<(PacketHeader, usize, bool)> {
+        let address = ReadStream(&mut self.datagram_buffer, PROTOCOL_VERSION)
+            .receive_datagram(&self.socket)?;
+
+        if self
+            .rendezvous
+            .as_ref()
+            .is_some_and(|rendezvous| rendezvous.address == address)
+        {
+            return self.receive_from_rendezvous(address);
+        }
+
+        // NOTE: only players of the session are of interest, so we drop anything else before
+        // spending a checksum on it
+        let Some(&index) = self.slots.get(&address) else {
+            trace!(%address, "dropped packet from outside the session");
+            return None;
+        };
+
+        // NOTE: only relayed datagrams may be larger than a packet
+        let size = self.datagram_buffer.read_size();
+        if size > PACKET_BUFFER_SIZE {
+            debug!(index, %address, size, "dropped oversized packet");
+            return None;
+        }
+
+        self.swap_buffer
+            .reset_reader_from(self.datagram_buffer.read_slice());
+        let header = ReadStream(&mut self.swap_buffer, PROTOCOL_VERSION)
+            .read_valid_packet(address, self.config.protocol_id)?;
+        Some((header, index, false))
+    }
+
+    /// Takes in the addresses the rendezvous server tells us, and unwraps relayed packets.
+    fn receive_from_rendezvous(
+        &mut self,
+        address: SocketAddr,
+    ) -> Option<(PacketHeader, usize, bool)> {
+        let session = self.rendezvous.as_ref()?.session;
+        let header = ReadStream(&mut self.datagram_buffer, PROTOCOL_VERSION)
+            .read_valid_packet(address, self.config.protocol_id)?;
+
+        match header.packet_type {
+            PacketType::RendezvousPeers => {
+                let peers: PeersPacket =
+                    ReadStream(&mut self.datagram_buffer, header.version).stream_new();
+                let players = peers.players as usize;
+                if peers.session != session
+                    || players != self.links.len()
+                    || self.datagram_buffer.unread_slice().len()
+                        < players * size_of::<AddressPacket>()
+                {
+                    warn!(%address, session = peers.session, players, "dropped peers of another session");
+                    return None;
+                }
+                for index in 0..players {
+                    let peer: AddressPacket =
+                        ReadStream(&mut self.datagram_buffer, header.version).stream_new();
+                    if let Some(peer_address) = peer.address() {
+                        self.update_address(index, peer_address);
+                    }
+                }
+                None
+            }
+
+            PacketType::Relay => {
+                let relay: RelayPacket =
+                    ReadStream(&mut self.datagram_buffer, header.version).stream_new();
+                let index = relay.from as usize;
+                if relay.session != session
+                    || relay.to != self.index
+                    || self.links.get(index).is_none_or(Option::is_none)
+                {
+                    debug!(
+                        session = relay.session,
+                        from = relay.from,
+                        to = relay.to,
+                        "dropped relay of another session"
+                    );
+                    return None;
+                }
+                self.swap_buffer
+                    .reset_reader_from(self.datagram_buffer.unread_slice());
+                let header = ReadStream(&mut self.swap_buffer, PROTOCOL_VERSION)
+                    .read_valid_packet(address, self.config.protocol_id)?;
+                Some((header, index, true))
+            }
+
+            // NOTE: not for a peer to handle
+            _ => None,
+        }
+    }
+
+    /// Starts punching a player at the public address the rendezvous server tells us, if it's
+    /// new to us.
+    fn update_address(&mut self, index: usize, address: SocketAddr) {
+        let Some(link) = &self.links[index] else {
+            return;
+        };
+        // NOTE: a player may move to a new address when it restarts, or when its NAT mapping
+        // changes, in which case our link to it times out first
+        if link.state == ClientState::Connected
+            || (link.route != Route::Unknown && link.endpoint.address == address)
+        {
+            return;
+        }
+        let route = match link.route {
+            Route::Relayed => Route::Relayed,
+            _ => Route::Punching {
+                since: Instant::now(),
+            },
+        };
+        info!(index, %address, "peer address received from the rendezvous server");
+        self.set_address(index, address, route);
+    }
+
+    fn handle_packet(&mut self, header: PacketHeader, index: usize, relayed: bool) {
+        let Some(link) = self.links[index].as_mut() else {
+            return;
+        };
+        let address = link.endpoint.address;
+
+        if header.packet_type == PacketType::Punch {
+            let punch: PunchPacket = ReadStream(&mut self.swap_buffer, header.version).stream_new();
+            let session = self
+                .rendezvous
+                .as_ref()
+                .map(|rendezvous| rendezvous.session);
+            if Some(punch.session) != session || punch.index as usize != index {
+                debug!(index, %address, session = punch.session, "dropped punch of another session");
+                return;
+            }
+        }
+
+        // NOTE: any packet of the player tells us which route works, up until we're connected
+        match link.route {
+            Route::Punching { .. } if !relayed => {
+                info!(index, %address, "punched through to peer");
+                link.route = Route::Direct;
+            }
+            Route::Unknown | Route::Punching { .. } | Route::Direct
+                if relayed && link.state != ClientState::Connected =>
+            {
+                info!(index, %address, "peer relays through the rendezvous server");
+                link.route = Route::Relayed;
+            }
+            _ => {}
+        }
+
+        match header.packet_type {
+            PacketType::ConnectionRequest => {
+                // NOTE: duplicates and requests of a peer that restarted are ignored; the latter
+                // gets accepted once its old link times out
+                if link.requesting
+                    || link.state != ClientState::Connecting
+                    || header.seq.unwrap() != 0
+                {
+                    return;
+                }
+
+                let request: ConnectionRequestPacket =
+                    ReadStream(&mut self.swap_buffer, header.version).stream_new();
+                let Some(version) = SUPPORTED_VERSIONS.negotiate(&request.versions) else {
+                    info!(
+                        index,
+                        %address,
+                        peer_versions = %request.versions,
+                        "denied peer, no protocol version in common"
+                    );
+                    let protocol_id = self.config.protocol_id;
+                    let reason = DenyReason::UnsupportedVersion;
+                    match (link.route, &self.rendezvous) {
+                        (Route::Relayed, Some(rendezvous)) => send_denial(
+                            &rendezvous.relay(&self.socket, protocol_id, self.index, index),
+                            &mut self.swap_buffer,
+                            protocol_id,
+                            address,
+                            request.nonce,
+                            reason,
+                        ),
+                        _ => send_denial(
+                            &self.socket,
+                            &mut self.swap_buffer,
+                            protocol_id,
+                            address,
+                            request.nonce,
+                            reason,
+                        ),
+                    }
+                    return;
+                };
+
+                let endpoint = &mut link.endpoint;
+                endpoint.version = version;
+                endpoint.receive_swap(header, &mut self.swap_buffer);
+                endpoint.mark_handled();
+                let own_index = self.index as usize;
+                endpoint.write_packet(PacketType::ConnectionAccepted, |w| {
+                    ConnectionAcceptedPacket::new(own_index).stream(w);
+                });
+                link.state = ClientState::Connected;
+                self.connected(index, version);
+            }
+
+            // NOTE: sent without an endpoint, so it bypasses the receive buffer
+            PacketType::ConnectionDenied => {
+                if !link.requesting
+                    || link.state != ClientState::Connecting
+                    || header.ack_bits != link.request_nonce
+                {
+                    return;
+                }
+                let denied: ConnectionDeniedPacket =
+                    ReadStream(&mut self.swap_buffer, header.version).stream_new();
+                let reason = denied.reason();
+                let peer_versions = denied.versions;
+                match reason {
+                    Some(reason) => {
+                        warn!(index, %address, %reason, %peer_versions, "denied by peer")
+                    }
+                    None => {
+                        warn!(index, %address, reason = denied.reason, %peer_versions, "denied by peer")
+                    }
+                }
+                link.state = ClientState::Denied;
+                self.events.push_back(PeerEvent::PeerDenied {
+                    index: index as u8,
+                    reason,
+                    peer_versions,
+                });
+            }
+
+            // NOTE: only the requesting side waits for an accept; any other is dropped, as
+            // readers take it for a user packet
+            PacketType::ConnectionAccepted => {
+                if link.requesting && link.state == ClientState::Connecting && link.is_active() {
+                    link.endpoint.receive_swap(header, &mut self.swap_buffer);
+                } else {
+                    debug!(index, %address, "dropped unexpected accept");
+                }
+            }
+
+            // NOTE: if a payload arrives before the request, we drop it, as it will be resent
+            // anyway until acked
+            PacketType::ConnectionKeepAlive | PacketType::UserPayload => {
+                if link.is_active() {
+                    link.endpoint.receive_swap(header, &mut self.swap_buffer);
+                }
+            }
+
+            // NOTE: punches only open the route, which is done above
+            PacketType::Punch => {}
+
+            // NOTE: not for a peer to handle, other than from the rendezvous server
+            PacketType::RendezvousRegister | PacketType::RendezvousPeers | PacketType::Relay => {}
+
+            // NOTE: peers message each other directly, there is no server to forward
+            PacketType::DirectMessage => {}
+        }
+    }
+
+    /// Completes the handshakes of links we requested, once their accepts are up for reading.
+    fn accept_connections(&mut self) {
+        for index in 0..self.links.len() {
+            let Some(link) = self.links[index].as_mut() else {
+                continue;
+            };
+            if !link.requesting || link.state != ClientState::Connecting {
+                continue;
+            }
+            let Some((header, mut read_stream)) = link.endpoint.peek_message() else {
+                continue;
+            };
+            // NOTE: the peer may send anything in place of its accept
+            if header.packet_type != PacketType::ConnectionAccepted {
+                debug!(index, packet_type = ?header.packet_type, "dropped packet before the accept");
+                link.endpoint.mark_handled();
+                continue;
+            }
+            let version = header.version;
+            let accepted: ConnectionAcceptedPacket = read_stream.stream_new();
+            if accepted.index as usize != index {
+                warn!(
+                    index,
+                    accepted_index = accepted.index,
+                    "peer accepted with another index"
+                );
+            }
+            link.endpoint.version = version;
+            link.endpoint.mark_handled();
+            link.state = ClientState::Connected;
+            self.connected(index, version);
+        }
+    }
+
+    fn connected(&mut self, index: usize, version: u16) {
+        let address = self.links[index].as_ref().unwrap().endpoint.address;
+        info!(index, %address, version, "peer connected");
+        self.metrics.endpoints[index] = Some(EndpointMetrics::new(index as u8, address));
+        self.events.push_back(PeerEvent::PeerConnected(index as u8));
+        self.rebalance_budget();
+    }
+
+    /// The next message of a connected peer, dropping anything but user packets in front of it.
+    fn peek_user_message(&mut self, index: usize) -> Option<ReadStream<'_>> {
+        let link = self.connected_link(index)?;
+        loop {
+            let packet_type = link.endpoint.peek_message()?.0.packet_type;
+            if packet_type == PacketType::UserPayload {
+                break;
+            }
+            debug!(index, ?packet_type, "dropped unexpected packet");
+            link.endpoint.mark_handled();
+        }
+        link.endpoint
+            .peek_message()
+            .map(|(_, read_stream)| read_stream)
+    }
+
+    fn mark_handled(&mut self, index: usize) {
+        if let Some(link) = self.connected_link(index) {
+            link.endpoint.mark_handled();
+        }
+    }
+
+    pub fn read_into<S: Streamable>(&mut self, index: usize, target: &mut S) -> bool {
+        let Some(mut read_stream) = self.peek_user_message(index) else {
+            return false;
+        };
+        read_stream.stream_with(target);
+        self.mark_handled(index);
+        true
+    }
+
+    pub fn read_new<S: Streamable>(&mut self, index: usize) -> Option<S> {
+        let message: S = self.peek_user_message(index)?.stream_new();
+        self.mark_handled(index);
+        Some(message)
+    }
+
+    /// Copies the payload of the next message from the peer into `target`, ready for reading.
+    pub fn read_payload(&mut self, index: usize, target: &mut Buffer) -> bool {
+        let Some(read_stream) = self.peek_user_message(index) else {
+            return false;
+        };
+        target.reset_reader_from(read_stream.0.unread_slice());
+        self.mark_handled(index);
+        true
+    }
+
+    pub fn drop_incoming(&mut self) {
+        for link in self.links.iter_mut().flatten() {
+            if link.state != ClientState::Connected {
+                continue;
+            }
+            while link.endpoint.peek_message().is_some() {
+                link.endpoint.mark_handled();
+            }
+        }
+    }
+
+    /// NOTE: only connected peers get messages, as the accept must be the first one we send
+    pub fn write<S: Streamable>(&mut self, index: usize, value: &mut S) {
+        if let Some(link) = self.connected_link(index) {
+            link.endpoint.write_packet(PacketType::UserPayload, |w| {
+                value.stream(w);
+            });
+        }
+    }
+
+    pub fn broadcast<S: Streamable>(&mut self, value: &mut S) {
+        for link in self.links.iter_mut().flatten() {
+            if link.state != ClientState::Connected {
+                continue;
+            }
+            link.endpoint.write_packet(PacketType::UserPayload, |w| {
+                value.stream(w);
+            });
+        }
+    }
+
+    pub fn write_payload(&mut self, index: usize, payload: &[u8]) {
+        if let Some(link) = self.connected_link(index) {
+            link.endpoint.write_packet(PacketType::UserPayload, |w| {
+                w.0.write_slice(payload);
+            });
+        }
+    }
+
+    pub fn broadcast_payload(&mut self, payload: &[u8]) {
+        for link in self.links.iter_mut().flatten() {
+            if link.state != ClientState::Connected {
+                continue;
+            }
+            link.endpoint.write_packet(PacketType::UserPayload, |w| {
+                w.0.write_slice(payload);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, time::Duration};
+
+    use super::*;
+    use crate::net::{
+        network::{
+            bind_socket, NetworkSeq, CONNECTION_REQUEST_SIZE, MIN_PROTOCOL_VERSION,
+            PACKET_HEADER_SIZE,
+        },
+        stream::WriteStream,
+    };
+
+    fn loopback_socket() -> UdpSocket {
+        bind_socket("127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    fn mesh(players: usize) -> Vec<Peer<UdpSocket>> {
+        let sockets: Vec<_> = (0..players).map(|_| loopback_socket()).collect();
+        let addresses: Vec<_> = sockets.iter().map(|s| s.local_addr().unwrap()).collect();
+        sockets
+            .into_iter()
+            .enumerate()
+            .map(|(index, socket)| Peer::new(socket, index as u8, &addresses, NetConfig::default()))
+            .collect()
+    }
+
+    /// Runs all peers until `done` holds, or fails after a few seconds.
+    fn run_until<F: FnMut(&mut [Peer<UdpSocket>]) -> bool>(
+        peers: &mut [Peer<UdpSocket>],
+        mut done: F,
+    ) {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while !done(peers) {
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn connect(peers: &mut [Peer<UdpSocket>]) {
+        let players = peers.len();
+        let mut connected = vec![0; players];
+        run_until(peers, |peers| {
+            for (index, peer) in peers.iter_mut().enumerate() {
+                while let Some(event) = peer.process_packets() {
+                    match event {
+                        PeerEvent::PeerConnected(_) => connected[index] += 1,
+                        event => panic!("unexpected event {event:?}"),
+                    }
+                }
+            }
+            connected.iter().all(|&count| count == players - 1)
+        });
+    }
+
+    #[test]
+    fn mesh_handshake_connects_every_pair() {
+        let mut peers = mesh(3);
+        connect(&mut peers);
+        for peer in &peers {
+            for other in 0..peers.len() {
+                if other == peer.index as usize {
+                    assert_eq!(peer.state(other), None);
+                } else {
+                    assert_eq!(peer.state(other), Some(ClientState::Connected));
+                    assert_eq!(peer.version(other), Some(PROTOCOL_VERSION));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn messages_arrive_in_order_from_every_peer() {
+        const COUNT: u32 = 300;
+        let mut peers = mesh(3);
+        connect(&mut peers);
+
+        let players = peers.len();
+        let mut written = 0;
+        // NOTE: indexed by receiver and sender
+        let mut received = vec![vec![0; players]; players];
+        run_until(&mut peers, |peers| {
+            // NOTE: like a game, we stay well within the window of sequence numbers, which still
+            // wrap around a few times
+            let slowest = received
+                .iter()
+                .enumerate()
+                .flat_map(|(index, counts)| {
+                    let others = counts
+                        .iter()
+                        .enumerate()
+                        .filter(move |&(other, _)| other != index);
+                    others.map(|(_, &count)| count)
+                })
+                .min()
+                .unwrap();
+            if written < COUNT && written - slowest < 32 {
+                for peer in peers.iter_mut() {
+                    for other in 0..players {
+                        peer.write(other, &mut written.clone());
+                    }
+                }
+                written += 1;
+            }
+            for (peer, counts) in peers.iter_mut().zip(&mut received) {
+                assert!(peer.process_packets().is_none());
+                for (other, count) in counts.iter_mut().enumerate() {
+                    while let Some(message) = peer.read_new::<u32>(other) {
+                        assert_eq!(message, *count, "from {other} to {}", peer.index);
+                        *count += 1;
+                    }
+                }
+            }
+            (0..players).all(|index| {
+                (0..players).all(|other| index == other || received[index][other] == COUNT)
+            })
+        });
+    }
+
+    /// Sends a request for `versions` to `peer` from `socket`, as the player with the higher index.
+    fn request(peer: &Peer<UdpSocket>, socket: &UdpSocket, versions: VersionRange, nonce: u32) {
+        let mut buffer = Buffer::with_capacity(PACKET_BUFFER_SIZE);
+        let mut w = WriteStream(&mut buffer, PROTOCOL_VERSION);
+        w.init_packet(
+            peer.config.protocol_id,
+            PacketType::ConnectionRequest,
+            NetworkSeq::wrap(0),
+            NetworkSeq::wrap(0),
+            0,
+        );
+        ConnectionRequestPacket {
+            versions,
+            role: Role::Player as u8,
+            nonce,
+        }
+        .stream(&mut w);
+        w.0.write_slice(
+            &[0; CONNECTION_REQUEST_SIZE
+                - PACKET_HEADER_SIZE
+                - size_of::<ConnectionRequestPacket>()],
+        );
+        w.finish_packet();
+        let address = peer.socket.local_addr().unwrap();
+        socket.send_to(buffer.written_slice(), address).unwrap();
+    }
+
+    /// Runs `peer` until `socket` receives a packet of it.
+    fn reply(peer: &mut Peer<UdpSocket>, socket: &UdpSocket, buffer: &mut Buffer) -> PacketHeader {
+        let protocol_id = peer.config.protocol_id;
+        let mut header = None;
+        run_until(std::slice::from_mut(peer), |peers| {
+            peers[0].process_packets();
+            header = ReadStream(buffer, PROTOCOL_VERSION)
+                .receive_packet(socket, protocol_id)
+                .map(|(header, _)| header);
+            header.is_some()
+        });
+        header.unwrap()
+    }
+
+    /// A peer of index 0, and the socket of the player of index 1, which requests from it.
+    fn pair() -> (Peer<UdpSocket>, UdpSocket) {
+        let peer_socket = loopback_socket();
+        let socket = loopback_socket();
+        let addresses = [
+            peer_socket.local_addr().unwrap(),
+            socket.local_addr().unwrap(),
+        ];
+        let peer = Peer::new(peer_socket, 0, &addresses, NetConfig::default());
+        (peer, socket)
+    }
+
+    /// Sends `peer` a sequenced packet of the player of index 1.
+    fn send<F: FnOnce(&mut WriteStream)>(
+        peer: &Peer<UdpSocket>,
+        socket: &UdpSocket,
+        packet_type: PacketType,
+        seq: u16,
+        f: F,
+    ) {
+        let mut buffer = Buffer::with_capacity(PACKET_BUFFER_SIZE);
+        let mut w = WriteStream(&mut buffer, PROTOCOL_VERSION);
+        w.init_packet(
+            peer.config.protocol_id,
+            packet_type,
+            NetworkSeq::wrap(seq),
+            NetworkSeq::wrap(0),
+            0,
+        );
+        f(&mut w);
+        w.finish_packet();
+        let address = peer.socket.local_addr().unwrap();
+        socket.send_to(buffer.written_slice(), address).unwrap();
+    }
+
+    #[test]
+    fn accepts_sent_to_the_accepting_side_are_dropped() {
+        let (mut peer, socket) = pair();
+        request(&peer, &socket, SUPPORTED_VERSIONS, 1);
+        let mut buffer = Buffer::with_capacity(PACKET_BUFFER_SIZE);
+        assert_eq!(
+            reply(&mut peer, &socket, &mut buffer).packet_type,
+            PacketType::ConnectionAccepted
+        );
+        assert_eq!(peer.state(1), Some(ClientState::Connected));
+
+        send(&peer, &socket, PacketType::ConnectionAccepted, 1, |w| {
+            ConnectionAcceptedPacket::new(1).stream(w);
+        });
+        // NOTE: the dropped accept doesn't take up its sequence number
+        send(&peer, &socket, PacketType::UserPayload, 1, |w| {
+            7u32.stream(w);
+        });
+        let mut message = None;
+        run_until(std::slice::from_mut(&mut peer), |peers| {
+            peers[0].process_packets();
+            message = peers[0].read_new::<u32>(1);
+            message.is_some()
+        });
+        assert_eq!(message, Some(7));
+    }
+
+    #[test]
+    fn oversized_datagrams_are_dropped() {
+        let (mut peer, socket) = pair();
+        let address = peer.socket.local_addr().unwrap();
+        socket.send_to(&[0; MAX_DATAGRAM_SIZE], address).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(peer.process_packets().is_none());
+        assert_eq!(peer.state(1), Some(ClientState::Connecting));
+    }
+
+    #[test]
+    fn peers_negotiate_the_highest_common_version() {
+        let (mut peer, socket) = pair();
+        let versions = VersionRange {
+            min: MIN_PROTOCOL_VERSION,
+            max: MIN_PROTOCOL_VERSION,
+        };
+        request(&peer, &socket, versions, 1);
+
+        let mut buffer = Buffer::with_capacity(PACKET_BUFFER_SIZE);
+        let header = reply(&mut peer, &socket, &mut buffer);
+        assert_eq!(header.packet_type, PacketType::ConnectionAccepted);
+        assert_eq!(header.version, MIN_PROTOCOL_VERSION);
+        assert_eq!(peer.version(1), Some(MIN_PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn peers_without_a_common_version_are_denied() {
+        let (mut peer, socket) = pair();
+        let versions = VersionRange {
+            min: PROTOCOL_VERSION + 1,
+            max: PROTOCOL_VERSION + 1,
+        };
+        request(&peer, &socket, versions, 7);
+
+        let mut buffer = Buffer::with_capacity(PACKET_BUFFER_SIZE);
+        let header = reply(&mut peer, &socket, &mut buffer);
+        assert_eq!(header.packet_type, PacketType::ConnectionDenied);
+        assert_eq!(header.ack_bits, 7, "the denial must echo the nonce");
+        let denied: ConnectionDeniedPacket = ReadStream(&mut buffer, header.version).stream_new();
+        assert_eq!(denied.reason(), Some(DenyReason::UnsupportedVersion));
+        assert_eq!(denied.versions, SUPPORTED_VERSIONS);
+        assert_eq!(peer.state(1), Some(ClientState::Connecting));
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use crate::net::config::{positive, NetConfig};
+
+/// max source addresses to remember; beyond this, idle sources are forgotten
+const MAX_TRACKED_SOURCES: usize = 4096;
+
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct RateLimits {
+    /// connection requests per second per source IP, from addresses without a connection
+    pub handshakes_per_second: f64,
+    pub handshake_burst: f64,
+    /// datagrams per second per connected client
+    pub packets_per_second: f64,
+    /// bytes per second per connected client, excluding UDP/IP header size
+    pub bytes_per_second: f64,
+    /// NOTE: limits on connected clients allow bursts of this many seconds worth
+    pub burst_seconds: f64,
+    /// violations after which a source IP gets banned; None disables banning
+    ///
+    /// NOTE: violations are forgiven over time, so only sustained abuse gets banned
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub ban_after_violations: Option<u32>,
+    pub violations_forgiven_per_second: f64,
+    /// NOTE: in seconds in configuration files
+    #[cfg_attr(feature = "serde", serde(with = "crate::net::config::serde_seconds"))]
+    pub ban_duration: Duration,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self::for_config(&NetConfig::default())
+    }
+}
+
+impl RateLimits {
+    pub fn for_config(config: &NetConfig) -> Self {
+        Self {
+            // NOTE: until accepted, also while the server is full, clients resend their request
+            // at least every `resend_frame_interval` frames, and send a keep-alive whenever they
+            // were quiet for `keep_alive_interval`; twice that leaves room for clients sharing
+            // an address behind a NAT
+            handshakes_per_second: 2.
+                * (config.fps / config.resend_frame_interval as f64
+                    + 1. / config.keep_alive_interval),
+            handshake_burst: 20.,
+            // NOTE: a client sends at most a packet per network frame plus resends, and its own
+            // budget caps its bytes, so twice that leaves plenty of room for honest clients
+            packets_per_second: 4. * config.fps,
+            bytes_per_second: 2. * config.client_bytes_per_second,
+            burst_seconds: 1.,
+            ban_after_violations: None,
+            violations_forgiven_per_second: 1.,
+            ban_duration: Duration::from_secs(60),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        positive(
+            "rate_limits.handshakes_per_second",
+            self.handshakes_per_second,
+        )?;
+        positive("rate_limits.handshake_burst", self.handshake_burst)?;
+        positive("rate_limits.packets_per_second", self.packets_per_second)?;
+        positive("rate_limits.bytes_per_second", self.bytes_per_second)?;
+        positive("rate_limits.burst_seconds", self.burst_seconds)?;
+        let forgiven = self.violations_forgiven_per_second;
+        if !forgiven.is_finite() || forgiven < 0. {
+            return Err(format!(
+                "rate_limits.violations_forgiven_per_second must not be negative, got {forgiven}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn full(burst: f64, now: Instant) -> Self {
+        Self {
+            tokens: burst,
+            last_refill: now,
+        }
+    }
+
+    pub(crate) fn take(&mut self, amount: f64, rate: f64, burst: f64, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        if self.tokens >= amount {
+            self.tokens -= amount;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&self, rate: f64, burst: f64, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens + elapsed * rate >= burst
+    }
+}
+
+struct Source {
+    handshakes: TokenBucket,
+    /// NOTE: as of `last_violation`, and forgiven since at `violations_forgiven_per_second`
+    violations: f64,
+    last_violation: Instant,
+    banned_until: Option<Instant>,
+}
+
+impl Source {
+    fn new(limits: &RateLimits, now: Instant) -> Self {
+        Self {
+            handshakes: TokenBucket::full(limits.handshake_burst, now),
+            violations: 0.,
+            last_violation: now,
+            banned_until: None,
+        }
+    }
+
+    fn violations(&self, forgiven_per_second: f64, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.last_violation).as_secs_f64();
+        (self.violations - elapsed * forgiven_per_second).max(0.)
+    }
+}
+
+struct ClientLimit {
+    packets: TokenBucket,
+    bytes: TokenBucket,
+}
+
+/// Decides whether to process a datagram before spending a checksum on it.
+pub struct RateLimiter {
+    pub limits: RateLimits,
+    sources: HashMap<IpAddr, Source>,
+    /// NOTE: indexed by endpoint slot
+    clients: Vec<Option<ClientLimit>>,
+    /// datagrams dropped for exceeding a limit or coming from a banned source
+    pub dropped: u64,
+    pub bans: u64,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits, capacity: usize) -> Self {
+        let mut clients = Vec::with_capacity(capacity);
+        for _ in 0..capacity {
+            clients.push(None);
+        }
+        Self {
+            limits,
+            sources: HashMap::new(),
+            clients,
+            dropped: 0,
+            bans: 0,
+        }
+    }
+
+    pub fn banned_sources(&self, now: Instant) -> usize {
+        self.sources
+            .values()
+            .filter(|s| s.banned_until.is_some_and(|until| until > now))
+            .count()
+    }
+
+    pub fn connect(&mut self, index: usize, now: Instant) {
+        let packet_burst = self.limits.packets_per_second * self.limits.burst_seconds;
+        let byte_burst = self.limits.bytes_per_second * self.limits.burst_seconds;
+        self.clients[index] = Some(ClientLimit {
+            packets: TokenBucket::full(packet_burst, now),
+            bytes: TokenBucket::full(byte_burst, now),
+        });
+    }
+
+    pub fn disconnect(&mut self, index: usize) {
+        self.clients[index] = None;
+    }
+
+    /// `index` is the endpoint slot of the sender; datagrams from addresses without a connection
+    /// count as handshakes.
+    pub fn admit(&mut self, ip: IpAddr, index: Option<usize>, size: usize, now: Instant) -> bool {
+        if let Some(source) = self.sources.get_mut(&ip) {
+            match source.banned_until {
+                Some(until) if until > now => {
+                    self.dropped += 1;
+                    return false;
+                }
+                Some(_) => {
+                    source.banned_until = None;
+                    source.violations = 0.;
+                }
+                None => {}
+            }
+        }
+
+        let index = index.filter(|&index| self.clients[index].is_some());
+        if index.is_none()
+            && !self.sources.contains_key(&ip)
+            && self.sources.len() >= MAX_TRACKED_SOURCES
+        {
+            self.forget_idle_sources(now);
+            if self.sources.len() >= MAX_TRACKED_SOURCES {
+                // NOTE: we'd rather turn away new sources than run out of memory
+                self.dropped += 1;
+                return false;
+            }
+        }
+
+        let limits = &self.limits;
+        let admitted = match index.and_then(|index| self.clients[index].as_mut()) {
+            Some(client) => {
+                let packet_burst = limits.packets_per_second * limits.burst_seconds;
+                let byte_burst = limits.bytes_per_second * limits.burst_seconds;
+                // NOTE: take bytes only for admitted packets, so a flood doesn't drain both
+                client
+                    .packets
+                    .take(1., limits.packets_per_second, packet_burst, now)
+                    && client
+                        .bytes
+                        .take(size as f64, limits.bytes_per_second, byte_burst, now)
+            }
+            None => {
+                let source = self
+                    .sources
+                    .entry(ip)
+                    .or_insert_with(|| Source::new(limits, now));
+                source.handshakes.take(
+                    1.,
+                    limits.handshakes_per_second,
+                    limits.handshake_burst,
+                    now,
+                )
+            }
+        };
+
+        if !admitted {
+            self.dropped += 1;
+            self.violation(ip, now);
+        }
+        admitted
+    }
+
+    fn violation(&mut self, ip: IpAddr, now: Instant) {
+        let Some(ban_after) = self.limits.ban_after_violations else {
+            return;
+        };
+        let limits = &self.limits;
+        let source = self
+            .sources
+            .entry(ip)
+            .or_insert_with(|| Source::new(limits, now));
+        source.violations = source.violations(limits.violations_forgiven_per_second, now) + 1.;
+        source.last_violation = now;
+        if source.violations >= ban_after as f64 {
+            warn!(%ip, violations = source.violations, "banned source for exceeding rate limits");
+            source.banned_until = Some(now + limits.ban_duration);
+            self.bans += 1;
+        }
+    }
+
+    fn forget_idle_sources(&mut self, now: Instant) {
+        let limits = &self.limits;
+        self.sources.retain(|_, source| {
+            source.banned_until.is_some_and(|until| until > now)
+                || source.violations(limits.violations_forgiven_per_second, now) > 0.
+                || !source.handshakes.is_full(
+                    limits.handshakes_per_second,
+                    limits.handshake_burst,
+                    now,
+                )
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::network::CONNECTION_REQUEST_SIZE;
+
+    fn limits() -> RateLimits {
+        RateLimits {
+            handshakes_per_second: 1.,
+            handshake_burst: 1.,
+            ban_after_violations: Some(3),
+            ..RateLimits::default()
+        }
+    }
+
+    #[test]
+    fn honest_handshakes_are_admitted() {
+        let config = NetConfig::default();
+        let mut limiter = RateLimiter::new(RateLimits::for_config(&config), 0);
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let start = Instant::now();
+        let resend_interval = config.resend_frame_interval as f64 / config.fps;
+        let keep_alive_interval = config.keep_alive_interval;
+        // NOTE: a resend and a keep-alive in every interval, for the whole handshake
+        let mut time = 0.;
+        while time < config.handshake_timeout {
+            let now = start + Duration::from_secs_f64(time);
+            assert!(limiter.admit(ip, None, CONNECTION_REQUEST_SIZE, now));
+            assert!(limiter.admit(ip, None, CONNECTION_REQUEST_SIZE, now));
+            time += resend_interval.min(keep_alive_interval);
+        }
+    }
+
+    #[test]
+    fn sporadic_violations_are_forgiven() {
+        let mut limiter = RateLimiter::new(limits(), 0);
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let start = Instant::now();
+        for second in 0..10 {
+            let now = start + Duration::from_secs(second);
+            assert!(limiter.admit(ip, None, CONNECTION_REQUEST_SIZE, now));
+            assert!(!limiter.admit(ip, None, CONNECTION_REQUEST_SIZE, now));
+        }
+        assert_eq!(limiter.bans, 0);
+    }
+
+    #[test]
+    fn sustained_violations_are_banned() {
+        let mut limiter = RateLimiter::new(limits(), 0);
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let now = Instant::now();
+        assert!(limiter.admit(ip, None, CONNECTION_REQUEST_SIZE, now));
+        for _ in 0..3 {
+            assert!(!limiter.admit(ip, None, CONNECTION_REQUEST_SIZE, now));
+        }
+        assert_eq!(limiter.bans, 1);
+        assert_eq!(limiter.banned_sources(now), 1);
+    }
+}
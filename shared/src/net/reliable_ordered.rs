@@ -1,23 +1,64 @@
 use std::{
-    net::{SocketAddr, UdpSocket},
-    time::Instant,
+    net::SocketAddr,
+    time::{Duration, Instant},
 };
 
+use tracing::{debug, debug_span, trace, warn, Span};
+
 use crate::{
     moving_average::MovingAverage,
     net::{
         buffer::Buffer,
+        client::ClientState,
+        config::NetConfig,
+        metrics::{ConnectionInfo, ConnectionQuality, TrafficMetrics},
         network::{
             NetworkSeq, PacketHeader, PacketType, ReceivePacket, SendPacket, SequenceBuffer,
+            PROTOCOL_VERSION,
         },
         stream::{ReadStream, WriteStream},
+        transport::Transport,
     },
 };
 
-use super::network::{CONNECTION_TIMEOUT_DURATION, PACKET_RESEND_FRAME_INTERVAL, UDP_IP_HEADER_SIZE, MAX_CLIENT_BYTES_PER_SECOND, NETWORK_FPS};
+use super::network::{PACKET_BUFFER_SIZE, PACKET_HEADER_SIZE, UDP_IP_HEADER_SIZE};
 
 pub struct ReliableOrderedDatagramEndpoint {
     pub address: SocketAddr,
+    /// protocol version to write packets in; our newest until one is negotiated
+    pub version: u16,
+    protocol_id: u32,
+    /// network frames per second
+    fps: f64,
+    /// in seconds
+    handshake_timeout: f64,
+    /// in seconds
+    idle_timeout: f64,
+    /// in seconds
+    unacked_timeout: f64,
+    /// share of a timeout after which we warn
+    timeout_warning: f64,
+    keep_alive_interval: Duration,
+    resend_frame_interval: u16,
+    /// NOTE: carries the address, so that events need not repeat it
+    span: Span,
+    /// max bytes per second to send, including UDP/IP header size
+    pub bytes_per_second: f64,
+    /// bytes we may send right now, refilled every network frame
+    send_credit: f64,
+    /// when we first sent a packet, which starts the handshake
+    first_send_time: Option<Instant>,
+    /// whether the peer acked any of our packets, which ends the handshake
+    established: bool,
+    /// whether we warned of a connection problem that hasn't cleared yet
+    warned: bool,
+    last_send_time: Option<Instant>,
+    last_receive_time: Option<Instant>,
+    /// NOTE: keep-alives aren't sequenced, so they're written outside of the send buffer
+    keep_alive_buffer: Buffer,
+    /// bytes we may still send before the peer acks a packet, proving that it receives at its
+    /// address; None once verified, or if verification isn't required
+    unverified_allowance: Option<u32>,
     send_buffer: SequenceBuffer<SendPacket>,
     first_send_seq: NetworkSeq,
     next_send_seq: NetworkSeq,
@@ -26,11 +67,17 @@ pub struct ReliableOrderedDatagramEndpoint {
     first_receive_seq: NetworkSeq,
     /// average round trip time
     rtt_avg: f64,
+    /// average deviation of round trip times from `rtt_avg`
+    jitter_avg: f64,
+    /// average share of sends that went unacked for a whole resend interval
+    loss_avg: f64,
     own_bytes_received_since_last_send: u32,
     total_bytes_received_since_last_send: u32,
     packets_created_since_last_send: u16,
     packets_received_since_last_send: u16,
     new_packets_received_since_last_send: u16,
+    /// whether we received sequenced packets since we last sent one, which carries our acks
+    acks_pending: bool,
 }
 
 #[derive(Default)]
@@ -44,10 +91,13 @@ pub struct EndpointSendStats {
     /// bytes received including UDP/IP header size
     pub total_bytes_received: u32,
     pub packets_created: u16,
+    pub packets_sent: u16,
     pub packets_received: u16,
     pub new_packets_received: u16,
     pub max_rtt: f64,
     pub rtt_avg: f64,
+    pub jitter_avg: f64,
+    pub loss_avg: f64,
 }
 
 impl std::ops::AddAssign<&EndpointSendStats> for EndpointSendStats {
@@ -57,24 +107,63 @@ impl std::ops::AddAssign<&EndpointSendStats> for EndpointSendStats {
         self.own_bytes_received += rhs.own_bytes_received;
         self.total_bytes_received += rhs.total_bytes_received;
         self.packets_created += rhs.packets_created;
+        self.packets_sent += rhs.packets_sent;
         self.packets_received += rhs.packets_received;
         self.new_packets_received += rhs.new_packets_received;
         self.max_rtt = self.max_rtt.max(rhs.max_rtt);
         self.rtt_avg += rhs.rtt_avg;
+        self.jitter_avg += rhs.jitter_avg;
+        self.loss_avg += rhs.loss_avg;
     }
 }
 
+/// A change in whether the connection is close to timing out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutWarning {
+    Raised,
+    Cleared,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timeout {
+    /// the peer didn't ack any packet in time
+    Handshake,
+    /// the peer went silent
+    Idle,
+    /// the peer didn't ack a packet in time
+    Unacked,
+}
+
 pub enum EndpointState {
-    Ok(EndpointSendStats),
-    ConnectionTimeout,
+    Ok(EndpointSendStats, Option<TimeoutWarning>),
+    ConnectionTimeout(Timeout),
 }
 
 impl ReliableOrderedDatagramEndpoint {
-    pub fn new(address: SocketAddr) -> Self {
+    pub fn new(address: SocketAddr, bytes_per_second: f64, config: &NetConfig) -> Self {
         let send_seq = NetworkSeq::wrap(0);
         let send_buffer = SequenceBuffer::new();
         Self {
             address,
+            version: PROTOCOL_VERSION,
+            protocol_id: config.protocol_id,
+            fps: config.fps,
+            handshake_timeout: config.handshake_timeout,
+            idle_timeout: config.idle_timeout,
+            unacked_timeout: config.unacked_timeout,
+            timeout_warning: config.timeout_warning,
+            keep_alive_interval: Duration::from_secs_f64(config.keep_alive_interval),
+            resend_frame_interval: config.resend_frame_interval,
+            span: debug_span!("endpoint", %address),
+            bytes_per_second,
+            send_credit: 0.,
+            first_send_time: None,
+            established: false,
+            warned: false,
+            last_send_time: None,
+            last_receive_time: None,
+            keep_alive_buffer: Buffer::with_capacity(PACKET_HEADER_SIZE),
+            unverified_allowance: None,
             send_buffer,
             first_send_seq: send_seq,
             next_send_seq: send_seq,
@@ -82,14 +171,55 @@ impl ReliableOrderedDatagramEndpoint {
             latest_receive_seq: NetworkSeq::wrap(0),
             first_receive_seq: NetworkSeq::wrap(0),
             rtt_avg: 0.,
+            jitter_avg: 0.,
+            loss_avg: 0.,
             own_bytes_received_since_last_send: 0,
             total_bytes_received_since_last_send: 0,
             packets_created_since_last_send: 0,
             packets_received_since_last_send: 0,
             new_packets_received_since_last_send: 0,
+            acks_pending: false,
+        }
+    }
+
+    /// Caps what we send to the peer by what we receive from it, until it acks a packet, so that
+    /// requests with spoofed addresses can't turn us into an amplifier.
+    /// NOTE: our first sequence numbers are predictable, so a spoofer may still fake an ack; this
+    /// only holds until we handshake with a challenge token.
+    pub fn require_verification(&mut self) {
+        self.unverified_allowance = Some(0);
+    }
+
+    /// Allows sending as many more bytes to an unverified peer, as it sent us `size` bytes that
+    /// we didn't otherwise receive, such as duplicate requests.
+    pub fn credit_unverified(&mut self, size: usize) {
+        if let Some(allowance) = &mut self.unverified_allowance {
+            *allowance += size as u32 + UDP_IP_HEADER_SIZE;
         }
     }
 
+    /// Summarizes the connection for game code; `traffic` is None before the first network frame.
+    pub fn connection_info(
+        &self,
+        state: ClientState,
+        traffic: Option<&TrafficMetrics>,
+    ) -> ConnectionInfo {
+        let mut info = ConnectionInfo {
+            state,
+            rtt: self.rtt_avg,
+            jitter: self.jitter_avg,
+            loss: self.loss_avg,
+            bytes_sent_per_second: traffic.map_or(0., |t| t.bytes_sent_per_second),
+            bytes_received_per_second: traffic.map_or(0., |t| t.bytes_received_per_second),
+            since_last_receive: self
+                .last_receive_time
+                .map(|time| time.elapsed().as_secs_f64()),
+            quality: ConnectionQuality::Good,
+        };
+        info.quality = info.classify(self.idle_timeout);
+        info
+    }
+
     pub fn create_packet(&mut self, packet_type: PacketType) -> NetworkSeq {
         self.write_packet(packet_type, |_| {})
     }
@@ -99,45 +229,54 @@ impl ReliableOrderedDatagramEndpoint {
         packet_type: PacketType,
         f: F,
     ) -> NetworkSeq {
+        let span = self.span.clone();
+        let _span = span.enter();
+
         let seq = self.next_send_seq;
         self.next_send_seq.wrapping_increment();
 
         let packet = self.send_buffer.mark_valid(seq);
         packet.first_send_time = None;
+        packet.last_send_time = None;
+        packet.send_count = 0;
 
-        let mut remote_ack_bits = 0;
-        for bit in 0..32 {
-            let seq = self.latest_receive_seq.wrapping_sub(bit + 1);
-            if self.receive_buffer.contains(seq) {
-                remote_ack_bits |= 1 << bit;
-            }
-        }
-
-        let mut w = WriteStream(&mut packet.buffer);
+        // NOTE: acks are rewritten at every send
+        let mut w = WriteStream(&mut packet.buffer, self.version);
 
-        w.init_packet(packet_type, seq, self.latest_receive_seq, remote_ack_bits);
+        w.init_packet(self.protocol_id, packet_type, seq, NetworkSeq::wrap(0), 0);
 
         f(&mut w);
 
         w.finish_packet();
 
         self.packets_created_since_last_send += 1;
+        trace!(seq = seq.unwrap(), ?packet_type, "created packet");
 
         seq
     }
 
-    pub fn send_outstanding(&mut self, socket: &UdpSocket) -> EndpointState {
-        if self.packets_created_since_last_send == 0 {
-            // NOTE: must do this periodically in order to keep acks going, as acks are written on
-            // packet creation rather than before sending.
-            self.create_packet(PacketType::ConnectionKeepAlive);
+    pub fn send_outstanding<T: Transport>(&mut self, socket: &T) -> EndpointState {
+        let span = self.span.clone();
+        let _span = span.enter();
+
+        // NOTE: credit carries over between frames, so that budgets too small for a packet every
+        // frame still get to send every few frames; bursts are capped at a frame's worth or a full
+        // packet, whichever is larger.
+        {
+            let frame_budget = self.bytes_per_second / self.fps;
+            let max_credit =
+                frame_budget.max((PACKET_BUFFER_SIZE as u32 + UDP_IP_HEADER_SIZE) as f64);
+            self.send_credit = (self.send_credit + frame_budget).min(max_credit);
         }
 
         let mut own_bytes_sent = 0;
         let mut total_bytes_sent = 0;
+        let mut packets_sent = 0;
+        let mut budget_exhausted = false;
         // send unacked packets
         let max_rtt = {
             let update_time = Instant::now();
+            let resend_interval = self.resend_interval();
             let mut min_send_time = update_time;
 
             {
@@ -151,33 +290,32 @@ impl ReliableOrderedDatagramEndpoint {
                             if send_time < min_send_time {
                                 min_send_time = send_time;
                             }
-                            // NOTE: decrease bandwidth usage by only resending every nth frame
-                            let n = PACKET_RESEND_FRAME_INTERVAL;
-                            seq_iter.unwrap() % n == self.first_send_seq.unwrap() % n
+                            // NOTE: resend only once the peer had time to ack the last send
+                            packet.last_send_time.is_none_or(|last_send_time| {
+                                update_time.duration_since(last_send_time) >= resend_interval
+                            })
                         } else {
-                            packet.first_send_time = Some(Instant::now());
-                            true
                             // NOTE: since we're about to initially send, no need to set min_send_time
+                            true
                         };
 
-                        if send {
-                            let buffer = packet.buffer.written_slice();
-                            let size = packet.buffer.written_size() as u32;
-                            // NOTE: limit sent bytes to avoid congestion and excessive bandwidth
-                            // usage.
-                            // TODO: We prioritize retransmission of old packets now, and this
-                            // could pose a problem in high latency scenarios, as old packets will
-                            // be sent multiple times, while new packets are never sent at all.
-                            // This should be mitigated by the resend frame interval, but let's
-                            // monitor this over time!
-                            if size + UDP_IP_HEADER_SIZE + total_bytes_sent < (MAX_CLIENT_BYTES_PER_SECOND / NETWORK_FPS) as u32 {
-                                match socket.send_to(buffer, self.address) {
-                                    Ok(_) => (),
-                                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
-                                    Err(e) => panic!("socket send io error: {e}"),
-                                };
-                                own_bytes_sent += size;
-                                total_bytes_sent += size + UDP_IP_HEADER_SIZE;
+                        // NOTE: limit sent bytes to avoid congestion and excessive bandwidth
+                        // usage.
+                        // TODO: We prioritize retransmission of old packets now, and this
+                        // could pose a problem in high latency scenarios, as old packets will
+                        // be sent multiple times, while new packets are never sent at all.
+                        // This should be mitigated by the resend frame interval, but let's
+                        // monitor this over time!
+                        // NOTE: once a packet doesn't fit, later packets wait too, so that
+                        // large packets don't starve behind small ones
+                        if send && !budget_exhausted {
+                            match self.transmit(socket, seq_iter, update_time) {
+                                Some(size) => {
+                                    own_bytes_sent += size;
+                                    total_bytes_sent += size + UDP_IP_HEADER_SIZE;
+                                    packets_sent += 1;
+                                }
+                                None => budget_exhausted = true,
                             }
                         }
                     }
@@ -185,6 +323,20 @@ impl ReliableOrderedDatagramEndpoint {
                 }
             }
 
+            // NOTE: acks ride along with whatever we send, so a keep-alive is only needed when
+            // nothing went out this frame, and either we have acks to deliver, or we've been
+            // quiet for long enough that the peer may think us gone.
+            let quiet = self
+                .last_send_time
+                .is_none_or(|time| update_time.duration_since(time) >= self.keep_alive_interval);
+            if packets_sent == 0 && !budget_exhausted && (self.acks_pending || quiet) {
+                if let Some(size) = self.send_keep_alive(socket, update_time) {
+                    own_bytes_sent += size;
+                    total_bytes_sent += size + UDP_IP_HEADER_SIZE;
+                    packets_sent += 1;
+                }
+            }
+
             update_time.duration_since(min_send_time).as_millis() as f64 / 1e3
         };
 
@@ -203,28 +355,183 @@ impl ReliableOrderedDatagramEndpoint {
         let packets_created = self.packets_created_since_last_send;
         self.packets_created_since_last_send = 0;
 
-        // TODO: configurable timeout duration
-        if max_rtt >= CONNECTION_TIMEOUT_DURATION {
-            // reset
-            {
-                self.send_buffer.reset();
-                self.receive_buffer.reset();
-                self.rtt_avg = 0.;
+        // NOTE: each as a share of its timeout, so that the one closest to timing out decides
+        let (share, timeout) = {
+            let now = Instant::now();
+            let elapsed =
+                |time: Option<Instant>| time.map_or(0., |t| now.duration_since(t).as_secs_f64());
+            if self.established {
+                // NOTE: keep-alives aren't acked, so an idle peer is only noticed gone by its
+                // silence, and a slow one by its acks
+                let idle = elapsed(self.last_receive_time) / self.idle_timeout;
+                let unacked = max_rtt / self.unacked_timeout;
+                if idle >= unacked {
+                    (idle, Timeout::Idle)
+                } else {
+                    (unacked, Timeout::Unacked)
+                }
+            } else {
+                (
+                    elapsed(self.first_send_time) / self.handshake_timeout,
+                    Timeout::Handshake,
+                )
             }
-            EndpointState::ConnectionTimeout
+        };
+
+        if share >= 1. {
+            debug!(?timeout, max_rtt, "connection timed out");
+            self.reset();
+            EndpointState::ConnectionTimeout(timeout)
         } else {
-            EndpointState::Ok(EndpointSendStats {
+            let warning = match (self.warned, share >= self.timeout_warning) {
+                (false, true) => {
+                    debug!(?timeout, share, "connection problem");
+                    Some(TimeoutWarning::Raised)
+                }
+                (true, false) => {
+                    debug!("connection recovered");
+                    Some(TimeoutWarning::Cleared)
+                }
+                _ => None,
+            };
+            self.warned = share >= self.timeout_warning;
+
+            let stats = EndpointSendStats {
                 own_bytes_sent,
                 total_bytes_sent,
                 own_bytes_received,
                 total_bytes_received,
                 packets_created,
+                packets_sent,
                 packets_received,
                 new_packets_received,
                 max_rtt,
                 rtt_avg: self.rtt_avg,
-            })
+                jitter_avg: self.jitter_avg,
+                loss_avg: self.loss_avg,
+            };
+            EndpointState::Ok(stats, warning)
+        }
+    }
+
+    /// Starts over as a new endpoint to the same address, such as for reconnecting after a
+    /// timeout; the peer only accepts requests with the first sequence number.
+    fn reset(&mut self) {
+        self.version = PROTOCOL_VERSION;
+        self.send_credit = 0.;
+        self.first_send_time = None;
+        self.established = false;
+        self.warned = false;
+        self.last_send_time = None;
+        self.last_receive_time = None;
+        self.send_buffer.reset();
+        self.first_send_seq = NetworkSeq::wrap(0);
+        self.next_send_seq = NetworkSeq::wrap(0);
+        self.receive_buffer.reset();
+        self.latest_receive_seq = NetworkSeq::wrap(0);
+        self.first_receive_seq = NetworkSeq::wrap(0);
+        self.rtt_avg = 0.;
+        self.jitter_avg = 0.;
+        self.loss_avg = 0.;
+        self.own_bytes_received_since_last_send = 0;
+        self.total_bytes_received_since_last_send = 0;
+        self.packets_created_since_last_send = 0;
+        self.packets_received_since_last_send = 0;
+        self.new_packets_received_since_last_send = 0;
+        self.acks_pending = false;
+    }
+
+    /// How long to wait for an ack before resending a packet: at least `resend_frame_interval`
+    /// network frames, and longer on links whose round trip takes longer than that.
+    fn resend_interval(&self) -> Duration {
+        let min_interval = self.resend_frame_interval as f64 / self.fps;
+        Duration::from_secs_f64(min_interval.max(self.rtt_avg + 4. * self.jitter_avg))
+    }
+
+    /// Sends a packet with our latest acks, if the budget allows; returns its size.
+    fn transmit<T: Transport>(
+        &mut self,
+        socket: &T,
+        seq: NetworkSeq,
+        update_time: Instant,
+    ) -> Option<u32> {
+        let remote_ack = self.latest_receive_seq;
+        let remote_ack_bits = self.remote_ack_bits();
+        let packet = self.send_buffer.get_mut(seq)?;
+
+        let size = packet.buffer.written_size() as u32;
+        if !spend(&mut self.send_credit, &mut self.unverified_allowance, size) {
+            trace!(seq = seq.unwrap(), size, "send budget exhausted");
+            return None;
+        }
+
+        // NOTE: acks are written at every send rather than at creation, so that resends don't
+        // carry stale acks
+        WriteStream(&mut packet.buffer, self.version).rewrite_acks(
+            self.protocol_id,
+            remote_ack,
+            remote_ack_bits,
+        );
+        send_datagram(socket, packet.buffer.written_slice(), self.address);
+        self.acks_pending = false;
+        self.first_send_time.get_or_insert(update_time);
+        self.last_send_time = Some(update_time);
+        packet.send_count += 1;
+        packet.last_send_time = Some(update_time);
+        if packet.send_count > 1 {
+            // NOTE: we only resend once the last send went unacked for a whole resend interval
+            self.loss_avg.exponential_moving_average(1., 0.1);
+        }
+        // NOTE: only once actually sent, as packets held back by the budget would otherwise be
+        // treated as resends
+        packet.first_send_time.get_or_insert(update_time);
+        trace!(
+            seq = seq.unwrap(),
+            size,
+            resend = packet.send_count > 1,
+            "sent packet"
+        );
+
+        Some(size)
+    }
+
+    /// Sends an unsequenced, ack-only packet, if the budget allows; returns its size.
+    fn send_keep_alive<T: Transport>(&mut self, socket: &T, update_time: Instant) -> Option<u32> {
+        let size = PACKET_HEADER_SIZE as u32;
+        if !spend(&mut self.send_credit, &mut self.unverified_allowance, size) {
+            trace!(size, "send budget exhausted");
+            return None;
+        }
+
+        let remote_ack_bits = self.remote_ack_bits();
+        let mut w = WriteStream(&mut self.keep_alive_buffer, self.version);
+        // NOTE: the seq is ignored on receipt, as keep-alives aren't resent
+        w.init_packet(
+            self.protocol_id,
+            PacketType::ConnectionKeepAlive,
+            self.next_send_seq,
+            self.latest_receive_seq,
+            remote_ack_bits,
+        );
+        w.finish_packet();
+        send_datagram(socket, self.keep_alive_buffer.written_slice(), self.address);
+        self.acks_pending = false;
+        self.last_send_time = Some(update_time);
+        trace!(size, "sent keep-alive");
+
+        Some(size)
+    }
+
+    /// A bit for each of the 32 sequence numbers before `latest_receive_seq` that we received.
+    fn remote_ack_bits(&self) -> u32 {
+        let mut remote_ack_bits = 0;
+        for bit in 0..32 {
+            let seq = self.latest_receive_seq.wrapping_sub(bit + 1);
+            if self.receive_buffer.contains(seq) {
+                remote_ack_bits |= 1 << bit;
+            }
         }
+        remote_ack_bits
     }
 
     fn ack(&mut self, seq: NetworkSeq) {
@@ -232,9 +539,23 @@ impl ReliableOrderedDatagramEndpoint {
             if let Some(first_send_time) = packet.first_send_time {
                 let rtt = Instant::now().duration_since(first_send_time).as_millis() as f64 / 1e3;
 
+                // NOTE: against the average before this sample, so that a steady rtt has no jitter
+                self.jitter_avg
+                    .exponential_moving_average((rtt - self.rtt_avg).abs(), 0.1);
+
                 // NOTE: this acts as a low pass filter on roundtrip time:
                 self.rtt_avg.exponential_moving_average(rtt, 0.1);
 
+                // NOTE: losses are counted as their resend intervals expire, see `transmit`
+                self.loss_avg.exponential_moving_average(0., 0.1);
+
+                trace!(seq = seq.unwrap(), rtt, "acked packet");
+
+                if self.unverified_allowance.take().is_some() {
+                    debug!("verified address");
+                }
+                self.established = true;
+
                 // NOTE: we reset details at creation
                 self.send_buffer.mark_invalid(seq);
             }
@@ -242,39 +563,58 @@ impl ReliableOrderedDatagramEndpoint {
     }
 
     pub fn receive_swap(&mut self, header: PacketHeader, buffer: &mut Buffer) {
+        let span = self.span.clone();
+        let _span = span.enter();
+
+        trace!(
+            seq = header.seq.unwrap(),
+            ack = header.ack.unwrap(),
+            ack_bits = header.ack_bits,
+            packet_type = ?header.packet_type,
+            duplicate = header.seq < self.first_receive_seq,
+            "received packet"
+        );
         self.packets_received_since_last_send += 1;
+        self.last_receive_time = Some(Instant::now());
         {
             let size = buffer.read_size() as u32;
             self.own_bytes_received_since_last_send += size;
             self.total_bytes_received_since_last_send += size + UDP_IP_HEADER_SIZE;
-        }
-
-        // advance most recently received sequence number
-        {
-            let seq = &mut self.latest_receive_seq;
-            while *seq < header.seq {
-                // NOTE: remove old entries to avoid breaking the ack logic with false acks.
-                // we can't delete immediately in front of us, as message peeking may happen before
-                // receive during wrap-around.
-                // we also can't delete immediately behind us, because that means we create acks
-                // for those packets in the send logic.
-                // 40 is because of the 32 ack bits + the seq + some nice padding.
-                self.receive_buffer
-                    .mark_invalid(header.seq.wrapping_sub(40));
-                seq.wrapping_increment();
-            }
+            self.credit_unverified(size as usize);
         }
 
         let ack = header.ack;
         let ack_bits = header.ack_bits;
 
-        // NOTE: if it's NOT a duplicate, mark it valid and swap it
-        if header.seq >= self.first_receive_seq {
-            self.new_packets_received_since_last_send += 1;
-            // NOTE: by swapping out the input buffer (32B pointing to heap memory), we retain the data
-            let packet = self.receive_buffer.mark_valid(header.seq);
-            std::mem::swap(buffer, &mut packet.buffer);
-            packet.header = header;
+        // NOTE: keep-alives only carry acks
+        if header.packet_type != PacketType::ConnectionKeepAlive {
+            // NOTE: duplicates too, as those hint that the peer missed our acks
+            self.acks_pending = true;
+
+            // advance most recently received sequence number
+            {
+                let seq = &mut self.latest_receive_seq;
+                while *seq < header.seq {
+                    // NOTE: remove old entries to avoid breaking the ack logic with false acks.
+                    // we can't delete immediately in front of us, as message peeking may happen before
+                    // receive during wrap-around.
+                    // we also can't delete immediately behind us, because that means we create acks
+                    // for those packets in the send logic.
+                    // 40 is because of the 32 ack bits + the seq + some nice padding.
+                    self.receive_buffer
+                        .mark_invalid(header.seq.wrapping_sub(40));
+                    seq.wrapping_increment();
+                }
+            }
+
+            // NOTE: if it's NOT a duplicate, mark it valid and swap it
+            if header.seq >= self.first_receive_seq {
+                self.new_packets_received_since_last_send += 1;
+                // NOTE: by swapping out the input buffer (32B pointing to heap memory), we retain the data
+                let packet = self.receive_buffer.mark_valid(header.seq);
+                std::mem::swap(buffer, &mut packet.buffer);
+                packet.header = header;
+            }
         }
 
         // mark packets acked
@@ -301,36 +641,207 @@ impl ReliableOrderedDatagramEndpoint {
         }
     }
 
-    pub fn peek_message(&mut self) -> Option<(&PacketHeader, ReadStream)> {
-        let mut found = false;
+    pub fn peek_message(&mut self) -> Option<(&PacketHeader, ReadStream<'_>)> {
+        // NOTE: keep-alives never make it into the receive buffer
+        let packet = self.receive_buffer.get_mut(self.first_receive_seq)?;
+        Some((
+            &packet.header,
+            ReadStream(&mut packet.buffer, packet.header.version),
+        ))
+    }
+
+    pub fn mark_handled(&mut self) {
+        // self.receive_buffer.mark_invalid(self.first_receive_seq);
+        // NOTE: we're marking invalid in the receive logic instead
+        // NOTE: we don't need to modify any memory, it's simply reused for another later
+        self.first_receive_seq.wrapping_increment();
+    }
+}
 
+/// Takes the cost of sending `size` bytes from the credit and unverified allowance, if both
+/// suffice.
+fn spend(send_credit: &mut f64, unverified_allowance: &mut Option<u32>, size: u32) -> bool {
+    let cost = size + UDP_IP_HEADER_SIZE;
+    let allowed = unverified_allowance.is_none_or(|a| cost <= a);
+    if !allowed || cost as f64 > *send_credit {
+        return false;
+    }
+    *send_credit -= cost as f64;
+    if let Some(allowance) = unverified_allowance {
+        *allowance -= cost;
+    }
+    true
+}
+
+fn send_datagram<T: Transport>(socket: &T, datagram: &[u8], address: SocketAddr) {
+    match socket.send_to(datagram, address) {
+        Ok(_) => (),
+        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
+        Err(e) => warn!(%address, error = %e, "failed to send datagram"),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::*;
+    use crate::net::{network::bind_socket, stream::Stream};
+
+    /// Two endpoints to each other, each with its own loopback socket.
+    fn pair(
+        config: &NetConfig,
+    ) -> (
+        (ReliableOrderedDatagramEndpoint, UdpSocket),
+        (ReliableOrderedDatagramEndpoint, UdpSocket),
+    ) {
+        let a = bind_socket("127.0.0.1:0".parse().unwrap()).unwrap();
+        let b = bind_socket("127.0.0.1:0".parse().unwrap()).unwrap();
+        let to_b = ReliableOrderedDatagramEndpoint::new(b.local_addr().unwrap(), 1e6, config);
+        let to_a = ReliableOrderedDatagramEndpoint::new(a.local_addr().unwrap(), 1e6, config);
+        ((to_b, a), (to_a, b))
+    }
+
+    /// The type, seq, ack and ack bits of a received packet.
+    type Received = (PacketType, u16, u16, u32);
+
+    /// Waits for the next packet on `socket`, and hands it to `endpoint`, unless it's lost.
+    fn deliver(
+        endpoint: &mut ReliableOrderedDatagramEndpoint,
+        socket: &UdpSocket,
+        lost: bool,
+    ) -> Received {
+        let deadline = Instant::now() + Duration::from_secs(3);
         loop {
-            let message = self.receive_buffer.get_mut(self.first_receive_seq);
-            if let Some(packet) = message {
-                if packet.header.packet_type == PacketType::ConnectionKeepAlive {
-                    self.mark_handled();
-                    continue;
+            let mut buffer = Buffer::with_capacity(PACKET_BUFFER_SIZE);
+            if let Some((header, _)) = ReadStream(&mut buffer, PROTOCOL_VERSION)
+                .receive_packet(socket, endpoint.protocol_id)
+            {
+                let received = (
+                    header.packet_type,
+                    header.seq.unwrap(),
+                    header.ack.unwrap(),
+                    header.ack_bits,
+                );
+                if !lost {
+                    endpoint.receive_swap(header, &mut buffer);
                 }
-
-                found = true;
-                break;
-            } else {
-                break;
+                return received;
             }
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(1));
         }
+    }
+
+    fn write(endpoint: &mut ReliableOrderedDatagramEndpoint, value: u32) -> NetworkSeq {
+        endpoint.write_packet(PacketType::UserPayload, |w| w.copy(&mut value.clone()))
+    }
 
-        if found {
-            let packet = self.receive_buffer.get_mut(self.first_receive_seq).unwrap();
-            return Some((&packet.header, ReadStream(&mut packet.buffer)));
+    /// Reads the next message in order, if any.
+    fn read(endpoint: &mut ReliableOrderedDatagramEndpoint) -> Option<u32> {
+        let (_, mut read_stream) = endpoint.peek_message()?;
+        let value = read_stream.stream_new();
+        endpoint.mark_handled();
+        Some(value)
+    }
+
+    #[test]
+    fn sends_carry_the_acks_of_packets_received_since_writing() {
+        let config = NetConfig {
+            resend_frame_interval: 1,
+            ..NetConfig::default()
+        };
+        let ((mut a, a_socket), (mut b, b_socket)) = pair(&config);
+
+        // NOTE: written before a received anything, so it has nothing to ack yet
+        assert_eq!(write(&mut a, 7).unwrap(), 0);
+        for value in 0..3 {
+            write(&mut b, value);
+        }
+        b.send_outstanding(&b_socket);
+        for _ in 0..3 {
+            deliver(&mut a, &a_socket, false);
         }
 
-        None
+        a.send_outstanding(&a_socket);
+        assert_eq!(
+            deliver(&mut b, &b_socket, false),
+            (PacketType::UserPayload, 0, 2, 0b11)
+        );
+        assert_eq!(read(&mut b), Some(7));
+
+        // NOTE: again for a packet written before the first send, which gets lost
+        write(&mut a, 8);
+        write(&mut b, 3);
+        b.send_outstanding(&b_socket);
+        deliver(&mut a, &a_socket, false);
+        a.send_outstanding(&a_socket);
+        assert_eq!(
+            deliver(&mut b, &b_socket, true),
+            (PacketType::UserPayload, 1, 3, 0b111)
+        );
+
+        // NOTE: the resend acks what arrived since the first send
+        write(&mut b, 4);
+        b.send_outstanding(&b_socket);
+        deliver(&mut a, &a_socket, false);
+        std::thread::sleep(a.resend_interval());
+        a.send_outstanding(&a_socket);
+        assert_eq!(
+            deliver(&mut b, &b_socket, false),
+            (PacketType::UserPayload, 1, 4, 0b1111)
+        );
+        assert_eq!(read(&mut b), Some(8));
     }
 
-    pub fn mark_handled(&mut self) {
-        // self.receive_buffer.mark_invalid(self.first_receive_seq);
-        // NOTE: we're marking invalid in the receive logic instead
-        // NOTE: we don't need to modify any memory, it's simply reused for another later
-        self.first_receive_seq.wrapping_increment();
+    #[test]
+    fn keep_alives_hold_idle_connections_up_without_taking_sequence_numbers() {
+        let config = NetConfig {
+            idle_timeout: 0.2,
+            unacked_timeout: 0.2,
+            keep_alive_interval: 0.02,
+            ..NetConfig::default()
+        };
+        let ((mut a, a_socket), (mut b, b_socket)) = pair(&config);
+
+        // NOTE: both establish, as each gets its packet acked
+        write(&mut a, 1);
+        write(&mut b, 2);
+        a.send_outstanding(&a_socket);
+        b.send_outstanding(&b_socket);
+        deliver(&mut a, &a_socket, false);
+        deliver(&mut b, &b_socket, false);
+        assert_eq!(read(&mut a), Some(2));
+        assert_eq!(read(&mut b), Some(1));
+        a.send_outstanding(&a_socket);
+        b.send_outstanding(&b_socket);
+        deliver(&mut a, &a_socket, false);
+        deliver(&mut b, &b_socket, false);
+        assert!(a.established && b.established);
+
+        let mut keep_alives = 0;
+        let deadline = Instant::now() + Duration::from_secs_f64(3. * config.idle_timeout);
+        while Instant::now() < deadline {
+            // NOTE: quiet for long enough that each side sends a keep-alive
+            std::thread::sleep(Duration::from_secs_f64(config.keep_alive_interval));
+            for (endpoint, socket) in [(&mut a, &a_socket), (&mut b, &b_socket)] {
+                assert!(matches!(
+                    endpoint.send_outstanding(socket),
+                    EndpointState::Ok(..)
+                ));
+            }
+            for (endpoint, socket) in [(&mut a, &a_socket), (&mut b, &b_socket)] {
+                let (packet_type, ..) = deliver(endpoint, socket, false);
+                assert_eq!(packet_type, PacketType::ConnectionKeepAlive);
+                keep_alives += 1;
+            }
+        }
+        assert!(keep_alives > 0);
+
+        // NOTE: the next message follows the last one without a gap, so it's read right away
+        assert_eq!(write(&mut a, 3).unwrap(), 1);
+        a.send_outstanding(&a_socket);
+        deliver(&mut b, &b_socket, false);
+        assert_eq!(read(&mut b), Some(3));
     }
 }
@@ -0,0 +1,560 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io,
+    mem::size_of,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, trace, warn};
+
+use crate::net::{
+    buffer::Buffer,
+    network::{
+        NetworkSeq, PacketType, MAX_DATAGRAM_SIZE, PACKET_BUFFER_SIZE, PACKET_HEADER_SIZE,
+        PACKET_TYPE_OFFSET, PROTOCOL_VERSION, RENDEZVOUS_REGISTER_SIZE,
+    },
+    rate_limit::{RateLimiter, RateLimits, TokenBucket},
+    stream::{ReadStream, Stream, Streamable, WriteStream},
+    transport::Transport,
+};
+
+pub const DEFAULT_RENDEZVOUS_PORT: u16 = 4323;
+/// in seconds
+pub const DEFAULT_REGISTRATION_TIMEOUT: f64 = 10.;
+/// NOTE: the addresses of all players of a session must fit a single reply
+pub const MAX_SESSION_PLAYERS: usize =
+    (PACKET_BUFFER_SIZE - PACKET_HEADER_SIZE - size_of::<PeersPacket>())
+        / size_of::<AddressPacket>();
+/// max sessions to remember; beyond this, new sessions are turned away until others expire
+const MAX_SESSIONS: usize = 4096;
+/// how often to look for expired registrations
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+/// max datagrams to handle per `process_packets`, so that a flood can't keep us from expiring
+/// registrations; the rest wait for the next call
+const MAX_DATAGRAMS_PER_PROCESS: usize = 1024;
+
+/// NOTE: like requests, the layout of rendezvous packets must never change between versions, as
+/// they're sent without a negotiated version.
+pub struct RegisterPacket {
+    /// chosen by the players of a session, such as a lobby code
+    pub session: u32,
+    pub index: u8,
+    /// number of players in the session, including the one registering
+    pub players: u8,
+}
+
+impl RegisterPacket {
+    /// Writes the registration, padded to `RENDEZVOUS_REGISTER_SIZE`.
+    pub fn write_padded(&mut self, w: &mut WriteStream) {
+        self.stream(w);
+        w.0.write_slice(
+            &[0; RENDEZVOUS_REGISTER_SIZE - PACKET_HEADER_SIZE - size_of::<RegisterPacket>()],
+        );
+    }
+}
+
+impl Streamable for RegisterPacket {
+    fn stream<S: Stream>(&mut self, s: &mut S) {
+        s.copy(&mut self.session);
+        s.copy(&mut self.index);
+        s.copy(&mut self.players);
+        s.copy(&mut 0u16); // NOTE: padding, to match the in-memory size
+    }
+}
+
+/// The reply to a registration, followed by an `AddressPacket` per player of the session.
+pub struct PeersPacket {
+    pub session: u32,
+    pub players: u8,
+}
+
+impl Streamable for PeersPacket {
+    fn stream<S: Stream>(&mut self, s: &mut S) {
+        s.copy(&mut self.session);
+        s.copy(&mut self.players);
+        s.copy(&mut 0u8); // NOTE: padding, to match the in-memory size
+        s.copy(&mut 0u16);
+    }
+}
+
+/// A public address, as observed by the rendezvous server.
+#[derive(Clone, Copy, Default)]
+pub struct AddressPacket {
+    /// 4 or 6, or 0 for a player that hasn't registered yet
+    pub family: u8,
+    pub port: u16,
+    /// NOTE: IPv4 addresses take the first four octets
+    pub octets: [u8; 16],
+}
+
+impl AddressPacket {
+    pub fn new(address: Option<SocketAddr>) -> Self {
+        let mut packet = AddressPacket::default();
+        let Some(address) = address else {
+            return packet;
+        };
+        packet.port = address.port();
+        match address.ip() {
+            IpAddr::V4(ip) => {
+                packet.family = 4;
+                packet.octets[..4].copy_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                packet.family = 6;
+                packet.octets = ip.octets();
+            }
+        }
+        packet
+    }
+
+    /// NOTE: None for unknown families
+    pub fn address(&self) -> Option<SocketAddr> {
+        let ip = match self.family {
+            4 => IpAddr::V4(Ipv4Addr::new(
+                self.octets[0],
+                self.octets[1],
+                self.octets[2],
+                self.octets[3],
+            )),
+            6 => IpAddr::V6(Ipv6Addr::from(self.octets)),
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, self.port))
+    }
+}
+
+impl Streamable for AddressPacket {
+    fn stream<S: Stream>(&mut self, s: &mut S) {
+        s.copy(&mut self.family);
+        s.copy(&mut 0u8); // NOTE: padding, to match the in-memory size
+        s.copy(&mut self.port);
+        for octet in &mut self.octets {
+            s.copy(octet);
+        }
+    }
+}
+
+/// Sent between peers to open the NATs in between.
+pub struct PunchPacket {
+    pub session: u32,
+    /// the player index of the sender
+    pub index: u8,
+}
+
+impl Streamable for PunchPacket {
+    fn stream<S: Stream>(&mut self, s: &mut S) {
+        s.copy(&mut self.session);
+        s.copy(&mut self.index);
+        s.copy(&mut 0u8); // NOTE: padding, to match the in-memory size
+        s.copy(&mut 0u16);
+    }
+}
+
+/// Followed by the whole packet being relayed, which the rendezvous server forwards as is.
+#[derive(Clone, Copy)]
+pub struct RelayPacket {
+    pub session: u32,
+    /// player index of the sender
+    pub from: u8,
+    /// player index of the receiver
+    pub to: u8,
+}
+
+impl Streamable for RelayPacket {
+    fn stream<S: Stream>(&mut self, s: &mut S) {
+        s.copy(&mut self.session);
+        s.copy(&mut self.from);
+        s.copy(&mut self.to);
+        s.copy(&mut 0u16); // NOTE: padding, to match the in-memory size
+    }
+}
+
+/// Writes a packet that's sent without an endpoint into `buffer`, and sends it.
+pub(crate) fn send_unsequenced<T: Transport, F: FnOnce(&mut WriteStream)>(
+    socket: &T,
+    buffer: &mut Buffer,
+    protocol_id: u32,
+    packet_type: PacketType,
+    address: SocketAddr,
+    f: F,
+) {
+    let mut w = WriteStream(buffer, PROTOCOL_VERSION);
+    w.init_packet(
+        protocol_id,
+        packet_type,
+        NetworkSeq::wrap(0),
+        NetworkSeq::wrap(0),
+        0,
+    );
+    f(&mut w);
+    w.finish_packet();
+    match socket.send_to(buffer.written_slice(), address) {
+        Ok(_) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+        Err(e) => warn!(%address, ?packet_type, error = %e, "failed to send packet"),
+    }
+}
+
+/// Sends datagrams to a peer through the rendezvous server, each wrapped in a relay packet.
+pub(crate) struct RelayTransport<'a, T: Transport> {
+    pub socket: &'a T,
+    pub rendezvous: SocketAddr,
+    pub protocol_id: u32,
+    pub relay: RelayPacket,
+    /// NOTE: in a RefCell, as transports send through a shared reference
+    pub buffer: &'a RefCell<Buffer>,
+}
+
+impl<T: Transport> Transport for RelayTransport<'_, T> {
+    fn send_to(&self, datagram: &[u8], _address: SocketAddr) -> io::Result<usize> {
+        let mut buffer = self.buffer.borrow_mut();
+        let mut w = WriteStream(&mut buffer, PROTOCOL_VERSION);
+        w.init_packet(
+            self.protocol_id,
+            PacketType::Relay,
+            NetworkSeq::wrap(0),
+            NetworkSeq::wrap(0),
+            0,
+        );
+        let mut relay = self.relay;
+        relay.stream(&mut w);
+        w.0.write_slice(datagram);
+        w.finish_packet();
+        self.socket
+            .send_to(buffer.written_slice(), self.rendezvous)?;
+        Ok(datagram.len())
+    }
+
+    fn recv_from(&self, _buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        // NOTE: relayed packets arrive on the socket itself
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+/// Settings of a rendezvous server.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct RendezvousConfig {
+    /// NOTE: must match that of the application, as we verify the checksums of what we relay
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::net::config::serde_protocol_id")
+    )]
+    pub protocol_id: u32,
+    pub port: u16,
+    /// seconds without hearing from a player before its registration expires
+    pub registration_timeout: f64,
+    /// registrations per second per source IP
+    pub registrations_per_second: f64,
+    pub registration_burst: f64,
+    /// bytes per second we relay for each player, to all other players combined
+    pub relay_bytes_per_second: f64,
+    pub relay_byte_burst: f64,
+}
+
+impl Default for RendezvousConfig {
+    fn default() -> Self {
+        Self {
+            protocol_id: crate::net::config::DEFAULT_PROTOCOL_ID,
+            port: DEFAULT_RENDEZVOUS_PORT,
+            registration_timeout: DEFAULT_REGISTRATION_TIMEOUT,
+            // NOTE: players behind the same NAT share an IP, and each registers every
+            // `NetConfig::rendezvous_interval`
+            registrations_per_second: 20.,
+            registration_burst: 40.,
+            relay_bytes_per_second: crate::net::config::DEFAULT_MAX_BITS_PER_SECOND / 8.,
+            relay_byte_burst: crate::net::config::DEFAULT_MAX_BITS_PER_SECOND / 8.,
+        }
+    }
+}
+
+impl RendezvousConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        crate::net::config::positive("registration_timeout", self.registration_timeout)?;
+        crate::net::config::positive("registrations_per_second", self.registrations_per_second)?;
+        crate::net::config::positive("registration_burst", self.registration_burst)?;
+        crate::net::config::positive("relay_bytes_per_second", self.relay_bytes_per_second)?;
+        crate::net::config::positive("relay_byte_burst", self.relay_byte_burst)
+    }
+}
+
+struct Registration {
+    address: SocketAddr,
+    last_seen: Instant,
+    relay_budget: TokenBucket,
+}
+
+/// NOTE: indexed by player index
+struct Session {
+    players: Vec<Option<Registration>>,
+}
+
+/// Tells the players of a session each other's public addresses, so that they can punch through
+/// their NATs, and relays between those that can't.
+///
+/// NOTE: session ids double as secrets, as anyone knowing one can take over a seat of it
+pub struct RendezvousServer<T: Transport = UdpSocket> {
+    config: RendezvousConfig,
+    socket: T,
+    buffer: Buffer,
+    sessions: HashMap<u32, Session>,
+    /// session and player index of each registered address
+    registered: HashMap<SocketAddr, (u32, u8)>,
+    limiter: RateLimiter,
+    last_expiry: Instant,
+    pub relayed_packets: u64,
+    pub relayed_bytes: u64,
+    /// relays dropped for exceeding the budget of their sender
+    pub relays_dropped: u64,
+}
+
+impl<T: Transport> RendezvousServer<T> {
+    pub fn new(socket: T, config: RendezvousConfig) -> Self {
+        let limits = RateLimits {
+            handshakes_per_second: config.registrations_per_second,
+            handshake_burst: config.registration_burst,
+            ..RateLimits::default()
+        };
+        Self {
+            socket,
+            buffer: Buffer::with_capacity(MAX_DATAGRAM_SIZE),
+            sessions: HashMap::new(),
+            registered: HashMap::new(),
+            limiter: RateLimiter::new(limits, 0),
+            last_expiry: Instant::now(),
+            relayed_packets: 0,
+            relayed_bytes: 0,
+            relays_dropped: 0,
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &RendezvousConfig {
+        &self.config
+    }
+
+    pub fn sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Number of registered players across all sessions.
+    pub fn players(&self) -> usize {
+        self.registered.len()
+    }
+
+    /// Blocks until a datagram arrives, or it's time to look for expired registrations.
+    pub fn wait_for_activity(&self) {
+        let timeout =
+            (self.last_expiry + EXPIRY_INTERVAL).saturating_duration_since(Instant::now());
+        if !timeout.is_zero() {
+            self.socket
+                .wait_readable(timeout)
+                .expect("socket poll io error");
+        }
+    }
+
+    /// Handles the datagrams that have arrived, up to `MAX_DATAGRAMS_PER_PROCESS`.
+    pub fn process_packets(&mut self) {
+        for _ in 0..MAX_DATAGRAMS_PER_PROCESS {
+            let Some(address) =
+                ReadStream(&mut self.buffer, PROTOCOL_VERSION).receive_datagram(&self.socket)
+            else {
+                break;
+            };
+            let now = Instant::now();
+
+            // NOTE: we only handle registrations, and relays of registered players, so we drop
+            // anything else before spending a checksum on it
+            let packet_type = self.buffer.read_slice().get(PACKET_TYPE_OFFSET).copied();
+            let admitted = if packet_type == Some(PacketType::RendezvousRegister as u8) {
+                self.limiter
+                    .admit(address.ip(), None, self.buffer.read_size(), now)
+            } else if packet_type == Some(PacketType::Relay as u8) {
+                self.admit_relay(address, now)
+            } else {
+                false
+            };
+            if !admitted {
+                trace!(%address, "dropped packet");
+                continue;
+            }
+
+            let Some(header) = ReadStream(&mut self.buffer, PROTOCOL_VERSION)
+                .read_valid_packet(address, self.config.protocol_id)
+            else {
+                continue;
+            };
+            match header.packet_type {
+                PacketType::RendezvousRegister => self.register(address, header.version, now),
+                PacketType::Relay => self.relay(address, header.version, now),
+                _ => {}
+            }
+        }
+
+        if let Err(e) = self.socket.flush() {
+            warn!(error = %e, "failed to send datagrams");
+        }
+
+        let now = Instant::now();
+        if now.duration_since(self.last_expiry) >= EXPIRY_INTERVAL {
+            self.last_expiry = now;
+            self.expire(now);
+        }
+    }
+
+    fn register(&mut self, address: SocketAddr, version: u16, now: Instant) {
+        let request: RegisterPacket = ReadStream(&mut self.buffer, version).stream_new();
+        let (session_id, index, players) =
+            (request.session, request.index, request.players as usize);
+        if index as usize >= players || players > MAX_SESSION_PLAYERS {
+            debug!(%address, session = session_id, index, players, "dropped invalid registration");
+            return;
+        }
+        if !self.sessions.contains_key(&session_id) && self.sessions.len() >= MAX_SESSIONS {
+            warn!(%address, session = session_id, "dropped registration, too many sessions");
+            return;
+        }
+        if self
+            .sessions
+            .get(&session_id)
+            .is_some_and(|session| session.players.len() != players)
+        {
+            debug!(
+                %address,
+                session = session_id,
+                players,
+                "dropped registration, disagrees on the number of players"
+            );
+            return;
+        }
+
+        // NOTE: an address holds a single seat; moving seats frees the old one
+        if let Some(&(old_session, old_index)) = self.registered.get(&address) {
+            if (old_session, old_index) != (session_id, index) {
+                if let Some(session) = self.sessions.get_mut(&old_session) {
+                    session.players[old_index as usize] = None;
+                }
+            }
+        }
+
+        let session = self.sessions.entry(session_id).or_insert_with(|| Session {
+            players: (0..players).map(|_| None).collect(),
+        });
+        let seat = &mut session.players[index as usize];
+        match seat {
+            Some(registration) if registration.address == address => {
+                registration.last_seen = now;
+            }
+            _ => {
+                // NOTE: a player that restarted, or whose NAT mapping changed, takes its seat over
+                // from its old address
+                if let Some(old) = seat.take() {
+                    self.registered.remove(&old.address);
+                    info!(session = session_id, index, %address, old_address = %old.address, "player moved");
+                } else {
+                    info!(session = session_id, index, %address, "player registered");
+                }
+                *seat = Some(Registration {
+                    address,
+                    last_seen: now,
+                    relay_budget: TokenBucket::full(self.config.relay_byte_burst, now),
+                });
+                self.registered.insert(address, (session_id, index));
+            }
+        }
+
+        let players = &session.players;
+        send_unsequenced(
+            &self.socket,
+            &mut self.buffer,
+            self.config.protocol_id,
+            PacketType::RendezvousPeers,
+            address,
+            |w| {
+                PeersPacket {
+                    session: session_id,
+                    players: players.len() as u8,
+                }
+                .stream(w);
+                for player in players {
+                    AddressPacket::new(player.as_ref().map(|p| p.address)).stream(w);
+                }
+            },
+        );
+    }
+
+    /// Whether a relay comes from a registered player, within its budget.
+    fn admit_relay(&mut self, address: SocketAddr, now: Instant) -> bool {
+        let Some(&(session_id, index)) = self.registered.get(&address) else {
+            return false;
+        };
+        let Some(Some(registration)) = self
+            .sessions
+            .get_mut(&session_id)
+            .map(|session| &mut session.players[index as usize])
+        else {
+            return false;
+        };
+        let admitted = registration.relay_budget.take(
+            self.buffer.read_size() as f64,
+            self.config.relay_bytes_per_second,
+            self.config.relay_byte_burst,
+            now,
+        );
+        if !admitted {
+            self.relays_dropped += 1;
+        }
+        admitted
+    }
+
+    fn relay(&mut self, address: SocketAddr, version: u16, now: Instant) {
+        let relay: RelayPacket = ReadStream(&mut self.buffer, version).stream_new();
+        let (session_id, index) = self.registered[&address];
+        if relay.session != session_id || relay.from != index {
+            debug!(%address, session = relay.session, from = relay.from, "dropped relay of another seat");
+            return;
+        }
+        let session = self.sessions.get_mut(&session_id).unwrap();
+        if let Some(registration) = &mut session.players[index as usize] {
+            registration.last_seen = now;
+        }
+        let Some(Some(target)) = session.players.get(relay.to as usize) else {
+            trace!(%address, session = session_id, to = relay.to, "dropped relay to unregistered player");
+            return;
+        };
+
+        let datagram = self.buffer.read_slice();
+        match self.socket.send_to(datagram, target.address) {
+            Ok(_) => {
+                self.relayed_packets += 1;
+                self.relayed_bytes += datagram.len() as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => warn!(address = %target.address, error = %e, "failed to relay packet"),
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let timeout = Duration::from_secs_f64(self.config.registration_timeout);
+        let registered = &mut self.registered;
+        self.sessions.retain(|&session_id, session| {
+            for (index, seat) in session.players.iter_mut().enumerate() {
+                if seat
+                    .as_ref()
+                    .is_some_and(|r| now.duration_since(r.last_seen) >= timeout)
+                {
+                    let address = seat.take().unwrap().address;
+                    registered.remove(&address);
+                    info!(session = session_id, index, %address, "registration expired");
+                }
+            }
+            session.players.iter().any(Option::is_some)
+        });
+    }
+}
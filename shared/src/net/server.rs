@@ -1,134 +1,305 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, trace, trace_span, warn};
 
 use crate::{
-    moving_average::MovingAverage,
     net::{
+        access::AccessList,
         buffer::Buffer,
+        client::ClientState,
+        config::NetConfig,
+        metrics::{ConnectionInfo, EndpointMetrics, MetricsSink, NetworkMetrics},
         network::{
-            PacketType, MAX_CLIENTS, MAX_CLIENT_BYTES_PER_SECOND, PACKET_BUFFER_SIZE,
-            PRINT_NETWORK_STATS,
+            ConnectionDeniedPacket, ConnectionRequestPacket, DenyReason, DirectMessagePacket,
+            NetworkSeq, PacketHeader, PacketType, Role, DIRECT_MESSAGE_VERSION, PACKET_BUFFER_SIZE,
+            PACKET_TYPE_OFFSET, PROTOCOL_VERSION, SUPPORTED_VERSIONS,
         },
+        rate_limit::{RateLimiter, RateLimits},
         reliable_ordered::{EndpointSendStats, ReliableOrderedDatagramEndpoint},
-        stream::{ReadStream, Stream, Streamable},
+        stream::{ReadStream, Stream, Streamable, WriteStream},
+        transport::Transport,
     },
     timing::FrameDurationAccumulator,
 };
 
-use super::{network::ConnectionAcceptedPacket, reliable_ordered::EndpointState};
+use super::{
+    network::ConnectionAcceptedPacket,
+    reliable_ordered::{EndpointState, TimeoutWarning},
+};
 
-pub struct Server {
+pub struct Server<T: Transport = UdpSocket> {
+    /// number of player seats, which take the lowest indices
     pub capacity: usize,
-    socket: UdpSocket,
+    /// number of spectator seats, indexed after those of players
+    pub spectator_capacity: usize,
+    config: NetConfig,
+    /// max bytes per second to send, including UDP/IP header size
+    bytes_per_second: f64,
+    /// NOTE: the server budget is split evenly among connected clients
+    pub client_bytes_per_second: f64,
+    pub(crate) socket: T,
     swap_buffer: Buffer,
     endpoints: Vec<Option<ReliableOrderedDatagramEndpoint>>,
-    timing: FrameDurationAccumulator,
-    pub tx_per_frame_avg: f64,
-    pub rx_per_frame_avg: f64,
+    /// index of the endpoint slot of each connected address
+    slots: HashMap<SocketAddr, usize>,
+    /// NOTE: a stack, handing out the lowest indices first until slots are freed
+    free_slots: Vec<usize>,
+    free_spectator_slots: Vec<usize>,
+    /// number of connections in each slot, so that messages queued for or from a client can be
+    /// told from those of the next client in its slot
+    generations: Vec<u32>,
+    /// when each spectator connected, indexed by slot past the player seats
+    spectators_since: Vec<Option<Instant>>,
+    /// NOTE: oldest first, as they're all delayed by the same amount
+    delayed: VecDeque<DelayedBroadcast>,
+    /// NOTE: recycled, so that buffers are only allocated while the delay queue grows
+    free_buffers: Vec<Buffer>,
+    rate_limiter: RateLimiter,
+    access: AccessList,
+    pub(crate) timing: FrameDurationAccumulator,
+    metrics: NetworkMetrics,
+    metrics_sinks: Vec<Box<dyn MetricsSink + Send>>,
+    /// NOTE: several clients may connect or time out within the same call
+    events: VecDeque<ServerEvent>,
+    /// pairs of sender and receiver whose direct messages get dropped
+    blocked: HashSet<(u8, u8)>,
+    direct_message_filter: Option<Box<dyn DirectMessageFilter + Send>>,
+}
+
+/// Decides which direct messages between clients the server forwards, such as to mute players
+/// or drop messages of unexpected sizes.
+pub trait DirectMessageFilter {
+    /// NOTE: `payload` is written in the protocol version of both clients
+    fn forward(&mut self, from: u8, to: u8, payload: &[u8]) -> bool;
+}
+
+impl<F: FnMut(u8, u8, &[u8]) -> bool> DirectMessageFilter for F {
+    fn forward(&mut self, from: u8, to: u8, payload: &[u8]) -> bool {
+        self(from, to, payload)
+    }
+}
+
+/// A broadcast held back from spectators by `NetConfig::spectator_delay`.
+struct DelayedBroadcast {
+    time: Instant,
+    /// NOTE: None if the payload is for spectators of any version
+    version: Option<u16>,
+    payload: Buffer,
 }
 
 #[derive(Debug)]
 pub enum ServerEvent {
     ClientTimeout(u8),
     ClientConnected(u8),
+    SpectatorTimeout(u8),
+    SpectatorConnected(u8),
+    /// NOTE: the client is close to timing out, and may or may not recover
+    ClientConnectionProblem(u8),
+    ClientConnectionRecovered(u8),
+}
+
+impl ServerEvent {
+    /// The seat of the client the event is about.
+    pub fn index(&self) -> u8 {
+        match *self {
+            ServerEvent::ClientTimeout(index)
+            | ServerEvent::ClientConnected(index)
+            | ServerEvent::SpectatorTimeout(index)
+            | ServerEvent::SpectatorConnected(index)
+            | ServerEvent::ClientConnectionProblem(index)
+            | ServerEvent::ClientConnectionRecovered(index) => index,
+        }
+    }
 }
 
-impl Server {
-    pub fn new(socket: UdpSocket, max_peer_count: u8, fps: f64) -> Server {
-        let capacity = max_peer_count as usize;
-        let mut endpoints = Vec::with_capacity(capacity);
-        for _ in 0..capacity {
+impl<T: Transport> Server<T> {
+    pub fn new(socket: T, config: NetConfig) -> Server<T> {
+        assert!(config.max_clients > 0, "server needs at least one seat");
+        let capacity = config.max_clients as usize;
+        let spectator_capacity = config.max_spectators as usize;
+        let total = capacity + spectator_capacity;
+        let mut endpoints = Vec::with_capacity(total);
+        for _ in 0..total {
             endpoints.push(None);
         }
 
         Server {
             capacity,
+            spectator_capacity,
+            bytes_per_second: config.server_bytes_per_second,
+            client_bytes_per_second: config.server_bytes_per_second,
             socket,
             swap_buffer: Buffer::with_capacity(PACKET_BUFFER_SIZE),
             endpoints,
-            timing: FrameDurationAccumulator::with_fps(fps, 0.25),
-            tx_per_frame_avg: 0.0,
-            rx_per_frame_avg: 0.0,
+            slots: HashMap::with_capacity(total),
+            free_slots: (0..capacity).rev().collect(),
+            free_spectator_slots: (capacity..total).rev().collect(),
+            generations: vec![0; total],
+            spectators_since: vec![None; spectator_capacity],
+            delayed: VecDeque::new(),
+            free_buffers: Vec::new(),
+            rate_limiter: RateLimiter::new(config.rate_limits(), total),
+            access: AccessList::default(),
+            timing: FrameDurationAccumulator::with_fps(config.fps, 0.25),
+            metrics: NetworkMetrics::with_capacity(total),
+            metrics_sinks: Vec::new(),
+            events: VecDeque::with_capacity(2 * total),
+            blocked: HashSet::new(),
+            direct_message_filter: None,
+            config,
         }
     }
 
+    pub fn config(&self) -> &NetConfig {
+        &self.config
+    }
+
+    /// Overrides `NetConfig::server_bytes_per_second`; more players need a larger budget.
+    pub fn set_bytes_per_second(&mut self, bytes_per_second: f64) {
+        self.bytes_per_second = bytes_per_second;
+        self.rebalance_budget();
+    }
+
+    fn rebalance_budget(&mut self) {
+        let players = self.capacity - self.free_slots.len();
+        let spectators = self.spectator_capacity - self.free_spectator_slots.len();
+        let connected = (players + spectators).max(1);
+        self.client_bytes_per_second = self.bytes_per_second / connected as f64;
+        for endpoint in self.endpoints.iter_mut().flatten() {
+            endpoint.bytes_per_second = self.client_bytes_per_second;
+        }
+    }
+
+    /// The snapshot as of the latest network frame.
+    pub fn metrics(&self) -> &NetworkMetrics {
+        &self.metrics
+    }
+
+    /// Hands the metrics snapshot to `sink` every network frame.
+    pub fn add_metrics_sink<M: MetricsSink + Send + 'static>(&mut self, sink: M) {
+        self.metrics_sinks.push(Box::new(sink));
+    }
+
     fn index_of(&self, address: SocketAddr) -> Option<usize> {
-        self.endpoints
-            .iter()
-            .enumerate()
-            .find(|(_, e)| e.is_some() && e.as_ref().unwrap().address == address)
-            .map(|(index, _)| index)
+        self.slots.get(&address).copied()
+    }
+
+    /// Whether the seat at `index` is that of a spectator, connected or not.
+    pub fn is_spectator(&self, index: usize) -> bool {
+        index >= self.capacity
+    }
+
+    /// Counts the connections in the seat at `index`, including the current one.
+    pub fn generation(&self, index: usize) -> u32 {
+        self.generations.get(index).copied().unwrap_or(0)
+    }
+
+    /// The protocol version negotiated with a connected client.
+    pub fn version(&self, index: usize) -> Option<u16> {
+        Some(self.endpoints.get(index)?.as_ref()?.version)
+    }
+
+    /// RTT, loss, bandwidth and quality of the connection to a connected client, such as for
+    /// ping bars.
+    pub fn connection_info(&self, index: usize) -> Option<ConnectionInfo> {
+        let endpoint = self.endpoints.get(index)?.as_ref()?;
+        let traffic = self.metrics.endpoints[index].as_ref().map(|m| &m.traffic);
+        Some(endpoint.connection_info(ClientState::Connected, traffic))
+    }
+
+    /// Blocks until a datagram arrives, the next network frame is due or the deadline passes,
+    /// whichever comes first.
+    pub fn wait_for_activity(&self, deadline: Option<Instant>) {
+        let network_deadline = self.timing.next_frame_deadline();
+        let deadline = deadline.map_or(network_deadline, |d| d.min(network_deadline));
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if !timeout.is_zero() {
+            self.socket
+                .wait_readable(timeout)
+                .expect("socket poll io error");
+        }
     }
 
     pub fn process_packets(&mut self) -> Option<ServerEvent> {
-        let mut event = None;
+        let mut disconnected = Vec::new();
+
+        // NOTE: bans and allow list changes apply to connected clients too
+        for (index, slot) in self.endpoints.iter_mut().enumerate() {
+            if let Some(endpoint) = slot {
+                if let Err(reason) = self.access.check(endpoint.address.ip()) {
+                    info!(index, address = %endpoint.address, %reason, "disconnected client");
+                    disconnected.push((index, endpoint.address));
+                    *slot = None;
+                }
+            }
+        }
+
+        // NOTE: queued ahead of the frame, so that they go out with it
+        self.send_delayed_broadcasts(Instant::now());
 
         self.timing.run_frame(|frame| {
-            let mut stats = EndpointSendStats::default();
+            let _span = trace_span!("network_frame", frame = frame.index).entered();
 
-            // TODO: no vec allocation every frame
-            let mut states = Vec::with_capacity(self.capacity);
+            let mut stats = EndpointSendStats::default();
 
-            for index in 0..self.capacity {
+            for index in 0..self.endpoints.len() {
                 let slot = &mut self.endpoints[index];
 
                 if let Some(endpoint) = slot {
                     let state = endpoint.send_outstanding(&self.socket);
 
                     match state {
-                        EndpointState::Ok(endpoint_stats) => {
+                        EndpointState::Ok(endpoint_stats, warning) => {
                             stats += &endpoint_stats;
-                            states.push(Some(endpoint_stats));
+                            if let Some(metrics) = &mut self.metrics.endpoints[index] {
+                                metrics.record_frame(&endpoint_stats, frame.dt, endpoint.bytes_per_second);
+                            }
+                            match warning {
+                                Some(TimeoutWarning::Raised) => {
+                                    warn!(index, address = %endpoint.address, "client connection problem");
+                                    self.events.push_back(ServerEvent::ClientConnectionProblem(index as u8));
+                                }
+                                Some(TimeoutWarning::Cleared) => {
+                                    info!(index, address = %endpoint.address, "client connection recovered");
+                                    self.events.push_back(ServerEvent::ClientConnectionRecovered(index as u8));
+                                }
+                                None => {}
+                            }
                         }
-                        EndpointState::ConnectionTimeout => {
-                            assert!(event.is_none(), "we don't need a server event queue for now; {:?}", event);
-                            event = Some(ServerEvent::ClientTimeout(index as u8));
-                            states.push(None);
+                        EndpointState::ConnectionTimeout(timeout) => {
+                            warn!(index, address = %endpoint.address, ?timeout, "client timed out");
+                            disconnected.push((index, endpoint.address));
                             *slot = None;
                         }
                     }
-                } else {
-                    states.push(None);
                 }
             }
 
-            if PRINT_NETWORK_STATS {
-                // NOTE: this acts as a low pass filter
-                self.tx_per_frame_avg.exponential_moving_average(stats.total_bytes_sent as f64, 0.1);
-                self.rx_per_frame_avg.exponential_moving_average(stats.total_bytes_received as f64, 0.1);
-
-                let txps = self.tx_per_frame_avg / frame.dt;
-                let rxps = self.rx_per_frame_avg / frame.dt;
-                // TODO: precompute count
-                let n = self.endpoints.iter().flatten().map(|_| 1.).sum::<f64>();
-
-                println!(
-                    "| Bps% {prxps:5.1}↓ {ptxps:5.1}↑ | Bps {rxps:6.0}↓ {txps:6.0}↑ | B {rx:5}↓ {tx:5}↑ | p {nprx:2}/{prx:<2}↓ {ptx:2}↑ | peers {n}/{nmax} | rtt ms {rtt} | frame {frame:7} @ {fps}/s|",
-                    frame = frame.index,
-                    n = n,
-                    nmax = MAX_CLIENTS,
-                    fps = 1. / frame.dt,
-                    rx = stats.total_bytes_received,
-                    tx = stats.total_bytes_sent,
-                    txps = txps,
-                    rxps = rxps,
-                    ptxps = if n == 0. { 0. } else { txps / n } / MAX_CLIENT_BYTES_PER_SECOND * 100.,
-                    prxps = if n == 0. { 0. } else { rxps / n } / MAX_CLIENT_BYTES_PER_SECOND * 100.,
-                    nprx = stats.new_packets_received,
-                    prx = stats.packets_received,
-                    ptx = stats.packets_created,
-                    rtt = states.iter().map(|e| {
-                        match e {
-                            Some(stats) => format!("{:3.0}", stats.rtt_avg * 1e3),
-                            None => "  x".to_string(),
-                        }
-                    }).collect::<Vec<_>>().join(",")
-                );
+            // NOTE: the datagrams that failed are lost like any other, and resent if reliable
+            if let Err(e) = self.socket.flush() {
+                warn!(error = %e, "failed to send datagrams");
+            }
+
+            self.metrics.rate_limited_packets = self.rate_limiter.dropped;
+            self.metrics.bans = self.rate_limiter.bans;
+            self.metrics.record_frame(frame.index, frame.dt, &stats, self.bytes_per_second);
+            for sink in &mut self.metrics_sinks {
+                sink.record(&self.metrics);
             }
         });
 
-        if let Some((header, address)) =
-            ReadStream(&mut self.swap_buffer).receive_packet(&self.socket)
-        {
+        if !disconnected.is_empty() {
+            for (index, address) in disconnected {
+                self.free_slot(index, address);
+            }
+            self.rebalance_budget();
+        }
+
+        if let Some((header, address)) = self.receive_admitted_packet() {
             match header.packet_type {
                 PacketType::ConnectionRequest => {
                     // NOTE: this is being processed ahead of being queued, as we have no endpoint,
@@ -137,119 +308,708 @@ impl Server {
                     // However, in the future, we will need to handle this differently, in case IP
                     // address changes over time or we need to simply handle temporary drops and
                     // reconnects.
-                    if self.index_of(address).is_none() {
-                        let mut index = self.capacity; // NOTE: invalid value
+                    if let Some(index) = self.index_of(address) {
+                        // NOTE: we can safely ignore duplicate packets, but they still count
+                        // toward what we may send to an unverified address
+                        let size = self.swap_buffer.read_size();
+                        if let Some(endpoint) = &mut self.endpoints[index] {
+                            endpoint.credit_unverified(size);
+                        }
+                    } else {
+                        let request: ConnectionRequestPacket =
+                            ReadStream(&mut self.swap_buffer, header.version).stream_new();
+                        if let Err(reason) = self.access.check(address.ip()) {
+                            // NOTE: checked before allocating anything for the address
+                            info!(%address, %reason, "denied connection request");
+                            self.deny(address, request.nonce, reason);
+                            return self.events.pop_front();
+                        }
+                        let Some(version) = SUPPORTED_VERSIONS.negotiate(&request.versions) else {
+                            info!(
+                                %address,
+                                client_versions = %request.versions,
+                                "denied connection request, no protocol version in common"
+                            );
+                            self.deny(address, request.nonce, DenyReason::UnsupportedVersion);
+                            return self.events.pop_front();
+                        };
+
+                        let Some(role) = request.role() else {
+                            info!(%address, role = request.role, "denied connection request, unknown role");
+                            self.deny(address, request.nonce, DenyReason::UnknownRole);
+                            return self.events.pop_front();
+                        };
+
+                        let mut index = None;
                         if header.seq.unwrap() == 0 {
-                            if let Some(free) = self
-                                .endpoints
-                                .iter()
-                                .enumerate()
-                                .find(|(_, e)| e.is_none())
-                                .map(|(index, _)| index)
-                            {
-                                index = free;
-                                self.endpoints[free] =
-                                    Some(ReliableOrderedDatagramEndpoint::new(address));
-                            } else {
-                                assert!(index == self.endpoints.len(), "we're pre-pushing None to fill capacity");
-                                // let next = self.endpoints.len();
-                                // if next < self.capacity {
-                                //     self.endpoints
-                                //         .push(Some(ReliableOrderedDatagramEndpoint::new(address)));
-                                //     index = next;
-                                // }
+                            let free_slots = match role {
+                                Role::Player => &mut self.free_slots,
+                                Role::Spectator => &mut self.free_spectator_slots,
+                            };
+                            if let Some(free) = free_slots.pop() {
+                                index = Some(free);
+                                self.generations[free] = self.generations[free].wrapping_add(1);
+                                self.slots.insert(address, free);
+                                self.rate_limiter.connect(free, Instant::now());
+                                self.endpoints[free] = Some(ReliableOrderedDatagramEndpoint::new(
+                                    address,
+                                    self.client_bytes_per_second,
+                                    &self.config,
+                                ));
+                                self.metrics.endpoints[free] =
+                                    Some(EndpointMetrics::new(free as u8, address));
                             }
                         }
 
-                        if index != self.capacity {
+                        if let Some(index) = index {
                             let endpoint = self.endpoints[index].as_mut().unwrap();
+                            endpoint.version = version;
+                            endpoint.require_verification();
                             endpoint.receive_swap(header, &mut self.swap_buffer);
                             endpoint.mark_handled();
                             endpoint.write_packet(PacketType::ConnectionAccepted, |w| {
                                 ConnectionAcceptedPacket::new(index).stream(w);
                             });
-                            assert!(
-                                event.is_none(),
-                                "we don't need a server event queue for now; {:?}",
-                                event
-                            );
-                            event = Some(ServerEvent::ClientConnected(index as u8));
-                        } else {
-                            // NOTE: we silently deny for now; maybe we shouldn't, but this is simpler
+                            info!(index, %address, version, ?role, "client connected");
+                            self.events.push_back(match role {
+                                Role::Player => ServerEvent::ClientConnected(index as u8),
+                                Role::Spectator => {
+                                    self.spectators_since[index - self.capacity] =
+                                        Some(Instant::now());
+                                    ServerEvent::SpectatorConnected(index as u8)
+                                }
+                            });
+                            self.rebalance_budget();
+                        } else if header.seq.unwrap() == 0 {
+                            let reason = match role {
+                                Role::Player => DenyReason::ServerFull,
+                                Role::Spectator => DenyReason::SpectatorsFull,
+                            };
+                            debug!(%address, %reason, "denied connection request");
+                            self.deny(address, request.nonce, reason);
                         }
-                    } else {
-                        // NOTE: we can safely ignore duplicate packets
                     }
                 }
 
                 // NOTE: not for the server to handle
-                PacketType::ConnectionAccepted => {}
+                PacketType::ConnectionDenied
+                | PacketType::ConnectionAccepted
+                | PacketType::RendezvousRegister
+                | PacketType::RendezvousPeers
+                | PacketType::Punch
+                | PacketType::Relay => {}
 
                 // NOTE: if a payload arrives before a connection request, we assume to drop it, as
                 // it will be resent anyway until acked
-                PacketType::UserPayload | PacketType::ConnectionKeepAlive => {
+                PacketType::UserPayload
+                | PacketType::ConnectionKeepAlive
+                | PacketType::DirectMessage => {
                     if let Some(index) = self.index_of(address) {
                         self.endpoints[index]
                             .as_mut()?
                             .receive_swap(header, &mut self.swap_buffer);
+                        if self.is_spectator(index) {
+                            self.drop_spectator_messages(index);
+                        } else {
+                            self.forward_direct_messages(index);
+                        }
                     };
                 }
             };
         }
 
-        event
+        self.events.pop_front()
+    }
+
+    /// Frees the slot of a client whose endpoint was dropped, as it timed out or may no longer
+    /// connect; either is reported as a timeout.
+    fn free_slot(&mut self, index: usize, address: SocketAddr) {
+        self.slots.remove(&address);
+        if index < self.capacity {
+            self.events
+                .push_back(ServerEvent::ClientTimeout(index as u8));
+            self.free_slots.push(index);
+        } else {
+            self.events
+                .push_back(ServerEvent::SpectatorTimeout(index as u8));
+            self.free_spectator_slots.push(index);
+            self.spectators_since[index - self.capacity] = None;
+        }
+        self.rate_limiter.disconnect(index);
+        self.metrics.endpoints[index] = None;
+        // NOTE: the next client in this slot starts unblocked
+        self.blocked
+            .retain(|&(from, to)| from as usize != index && to as usize != index);
+    }
+
+    /// Receives a datagram, and validates it if it's within the rate limits.
+    fn receive_admitted_packet(&mut self) -> Option<(PacketHeader, SocketAddr)> {
+        let address =
+            ReadStream(&mut self.swap_buffer, PROTOCOL_VERSION).receive_datagram(&self.socket)?;
+        let index = self.index_of(address);
+        let datagram = self.swap_buffer.unread_slice();
+
+        // NOTE: only requests are of interest from addresses without a connection, so we drop
+        // anything else before spending a checksum on it
+        let is_request =
+            datagram.get(PACKET_TYPE_OFFSET) == Some(&(PacketType::ConnectionRequest as u8));
+        if index.is_none() && !is_request {
+            return None;
+        }
+
+        // NOTE: clients banned after connecting are ignored until `process_packets` disconnects them
+        if index.is_some() && self.access.check(address.ip()).is_err() {
+            trace!(%address, "dropped packet from banned client");
+            return None;
+        }
+
+        if !self
+            .rate_limiter
+            .admit(address.ip(), index, datagram.len(), Instant::now())
+        {
+            trace!(%address, "dropped rate limited packet");
+            return None;
+        }
+
+        let header = ReadStream(&mut self.swap_buffer, PROTOCOL_VERSION)
+            .read_valid_packet(address, self.config.protocol_id)?;
+        Some((header, address))
+    }
+
+    /// Tells a requesting address why it may not connect.
+    ///
+    /// NOTE: this is sent once per request without an endpoint, as the client keeps resending its
+    /// request until it times out; the denial is no larger than the padded request.
+    fn deny(&mut self, address: SocketAddr, nonce: u32, reason: DenyReason) {
+        send_denial(
+            &self.socket,
+            &mut self.swap_buffer,
+            self.config.protocol_id,
+            address,
+            nonce,
+            reason,
+        );
+    }
+
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.rate_limiter.limits = limits;
+    }
+
+    /// Bans and allow list rules, checked for every new connection; changes also apply to
+    /// connected clients, who get disconnected by the next `process_packets`.
+    pub fn access_list(&mut self) -> &mut AccessList {
+        &mut self.access
+    }
+
+    pub fn set_access_list(&mut self, access: AccessList) {
+        self.access = access;
+    }
+
+    /// Drops the direct messages of `from` to `to` from now on, until unblocked or either client
+    /// times out.
+    pub fn block_direct_messages(&mut self, from: u8, to: u8) {
+        self.blocked.insert((from, to));
+    }
+
+    pub fn unblock_direct_messages(&mut self, from: u8, to: u8) {
+        self.blocked.remove(&(from, to));
     }
 
-    pub fn read_into<T: Streamable>(&mut self, index: usize, target: &mut T) -> bool {
-        if let Some(Some(endpoint)) = &mut self.endpoints.get_mut(index) {
-            if let Some((header, mut read_stream)) = endpoint.peek_message() {
-                assert!(
-                    header.packet_type == PacketType::UserPayload,
-                    "user should only read user packets, not {:?}",
-                    header.packet_type
-                );
-                read_stream.stream_with(target);
-                endpoint.mark_handled();
-                true
-            } else {
-                false
+    /// Asks `filter` about every direct message that isn't blocked, before forwarding it.
+    pub fn set_direct_message_filter<F: DirectMessageFilter + Send + 'static>(
+        &mut self,
+        filter: F,
+    ) {
+        self.direct_message_filter = Some(Box::new(filter));
+    }
+
+    /// NOTE: spectators only listen, so anything they send is dropped as it arrives
+    fn drop_spectator_messages(&mut self, index: usize) {
+        let Some(Some(endpoint)) = self.endpoints.get_mut(index) else {
+            return;
+        };
+        while let Some((header, _)) = endpoint.peek_message() {
+            debug!(index, packet_type = ?header.packet_type, "dropped message of spectator");
+            endpoint.mark_handled();
+        }
+    }
+
+    /// Forwards the direct messages at the front of a client's queue, so that they don't wait for
+    /// the game to read the messages for the server.
+    fn forward_direct_messages(&mut self, from: usize) {
+        loop {
+            let Some(Some(endpoint)) = self.endpoints.get_mut(from) else {
+                return;
+            };
+            let Some((header, mut read_stream)) = endpoint.peek_message() else {
+                return;
+            };
+            if header.packet_type != PacketType::DirectMessage {
+                return;
             }
+            let version = header.version;
+            let direct: DirectMessagePacket = read_stream.stream_new();
+            self.swap_buffer
+                .reset_reader_from(read_stream.0.unread_slice());
+            endpoint.mark_handled();
+            self.forward_direct_message(from as u8, direct.index, version);
+        }
+    }
+
+    /// Forwards the direct message in the swap buffer, unless it's blocked or filtered out.
+    fn forward_direct_message(&mut self, from: u8, to: u8, version: u16) {
+        let Some(Some(target)) = self.endpoints.get_mut(to as usize) else {
+            debug!(from, to, "dropped direct message to a player not connected");
+            return;
+        };
+        if to == from {
+            debug!(from, "dropped direct message to its own sender");
+            return;
+        }
+        // NOTE: the payload is forwarded as written, so both ends must speak the same version
+        if target.version < DIRECT_MESSAGE_VERSION || target.version != version {
+            debug!(
+                from,
+                to, version, "dropped direct message the receiver can't read"
+            );
+            return;
+        }
+        let payload = self.swap_buffer.unread_slice();
+        if self.blocked.contains(&(from, to))
+            || self
+                .direct_message_filter
+                .as_mut()
+                .is_some_and(|filter| !filter.forward(from, to, payload))
+        {
+            trace!(from, to, "dropped filtered direct message");
+            return;
+        }
+        target.write_packet(PacketType::DirectMessage, |w| {
+            DirectMessagePacket { index: from }.stream(w);
+            w.0.write_slice(payload);
+        });
+    }
+
+    fn peek_user_message(&mut self, index: usize) -> Option<ReadStream<'_>> {
+        let (header, read_stream) = self.endpoints.get_mut(index)?.as_mut()?.peek_message()?;
+        assert!(
+            header.packet_type == PacketType::UserPayload,
+            "user should only read user packets, not {:?}",
+            header.packet_type
+        );
+        Some(read_stream)
+    }
+
+    /// Marks the message read and forwards the direct messages behind it.
+    fn mark_handled(&mut self, index: usize) {
+        if let Some(Some(endpoint)) = self.endpoints.get_mut(index) {
+            endpoint.mark_handled();
+        }
+        self.forward_direct_messages(index);
+    }
+
+    pub fn read_into<S: Streamable>(&mut self, index: usize, target: &mut S) -> bool {
+        if let Some(mut read_stream) = self.peek_user_message(index) {
+            read_stream.stream_with(target);
+            self.mark_handled(index);
+            true
         } else {
             false
         }
     }
 
-    pub fn read_new<T: Streamable>(&mut self, index: usize) -> Option<T> {
+    pub fn read_new<S: Streamable>(&mut self, index: usize) -> Option<S> {
+        let message: S = self.peek_user_message(index)?.stream_new();
+        self.mark_handled(index);
+        Some(message)
+    }
+
+    /// Copies the payload of the next message from the client into `target`, ready for reading.
+    pub fn read_payload(&mut self, index: usize, target: &mut Buffer) -> bool {
+        if let Some(read_stream) = self.peek_user_message(index) {
+            target.reset_reader_from(read_stream.0.unread_slice());
+            self.mark_handled(index);
+            return true;
+        }
+        false
+    }
+
+    /// NOTE: direct messages between clients are still forwarded
+    pub fn drop_incoming(&mut self) {
+        for index in 0..self.endpoints.len() {
+            while self.peek_user_message(index).is_some() {
+                self.mark_handled(index);
+            }
+        }
+    }
+
+    pub fn write<S: Streamable>(&mut self, index: usize, value: &mut S) {
         if let Some(Some(endpoint)) = self.endpoints.get_mut(index) {
-            if let Some((header, mut read_stream)) = endpoint.peek_message() {
-                assert!(
-                    header.packet_type == PacketType::UserPayload,
-                    "user should only read user packets, not {:?}",
-                    header.packet_type
-                );
-                let message: T = read_stream.stream_new();
-                endpoint.mark_handled();
-                return Some(message);
-            }
-            None
+            endpoint.write_packet(PacketType::UserPayload, |w| {
+                value.stream(w);
+            });
+        }
+    }
+
+    /// Players, and spectators unless broadcasts to them are delayed.
+    fn undelayed_endpoints(
+        &mut self,
+    ) -> impl Iterator<Item = &mut ReliableOrderedDatagramEndpoint> {
+        let end = if self.config.spectator_delay > 0. {
+            self.capacity
         } else {
-            None
+            self.endpoints.len()
+        };
+        self.endpoints[..end].iter_mut().flatten()
+    }
+
+    fn free_buffer(&mut self) -> Buffer {
+        let mut buffer = self
+            .free_buffers
+            .pop()
+            .unwrap_or_else(|| Buffer::with_capacity(PACKET_BUFFER_SIZE));
+        buffer.reset_writer();
+        buffer
+    }
+
+    /// Holds back a copy of the payload for spectators, if broadcasts to them are delayed and
+    /// any of them can read it.
+    fn delay_payload(&mut self, version: Option<u16>, payload: &[u8]) {
+        if self.config.spectator_delay <= 0. {
+            return;
         }
+        let readable = (self.capacity..self.endpoints.len()).any(|index| {
+            self.version(index)
+                .is_some_and(|v| version.is_none_or(|version| version == v))
+        });
+        if !readable {
+            return;
+        }
+        let mut buffer = self.free_buffer();
+        buffer.write_slice(payload);
+        self.delayed.push_back(DelayedBroadcast {
+            time: Instant::now(),
+            version,
+            payload: buffer,
+        });
     }
 
-    pub fn drop_incoming(&mut self) {
-        for endpoint in self.endpoints.iter_mut().flatten() {
-            while endpoint.peek_message().is_some() {
-                endpoint.mark_handled();
+    fn send_delayed_broadcasts(&mut self, now: Instant) {
+        let delay = Duration::from_secs_f64(self.config.spectator_delay);
+        while let Some(delayed) = self.delayed.front() {
+            if now.duration_since(delayed.time) < delay {
+                break;
+            }
+            let delayed = self.delayed.pop_front().unwrap();
+            for (index, slot) in self.endpoints.iter_mut().enumerate().skip(self.capacity) {
+                let Some(endpoint) = slot else {
+                    continue;
+                };
+                // NOTE: only to spectators connected at the time of the broadcast, as the game
+                // sends newcomers the state as of their connecting
+                let since = self.spectators_since[index - self.capacity];
+                if since.is_none_or(|since| since > delayed.time)
+                    || delayed
+                        .version
+                        .is_some_and(|version| version != endpoint.version)
+                {
+                    continue;
+                }
+                endpoint.write_packet(PacketType::UserPayload, |w| {
+                    w.0.write_slice(delayed.payload.written_slice());
+                });
             }
+            self.free_buffers.push(delayed.payload);
         }
     }
 
-    pub fn broadcast<T: Streamable>(&mut self, value: &mut T) {
-        for endpoint in self.endpoints.iter_mut().flatten() {
+    /// NOTE: spectators get broadcasts `NetConfig::spectator_delay` after players
+    pub fn broadcast<S: Streamable>(&mut self, value: &mut S) {
+        self.broadcast_since(0, value);
+    }
+
+    /// Like `broadcast`, but only to clients that negotiated `min_version` or later, such as for
+    /// messages that older clients can't read.
+    pub fn broadcast_since<S: Streamable>(&mut self, min_version: u16, value: &mut S) {
+        for endpoint in self.undelayed_endpoints() {
+            if endpoint.version < min_version {
+                continue;
+            }
             endpoint.write_packet(PacketType::UserPayload, |w| {
                 value.stream(w);
             });
         }
+        if self.config.spectator_delay <= 0. {
+            return;
+        }
+        let time = Instant::now();
+        for index in self.capacity..self.endpoints.len() {
+            let Some(version) = self.version(index).filter(|&v| v >= min_version) else {
+                continue;
+            };
+            // NOTE: versions are few, so we skip those already written rather than allocate
+            if (self.capacity..index).any(|other| self.version(other) == Some(version)) {
+                continue;
+            }
+            let mut payload = self.free_buffer();
+            value.stream(&mut WriteStream(&mut payload, version));
+            self.delayed.push_back(DelayedBroadcast {
+                time,
+                version: Some(version),
+                payload,
+            });
+        }
+    }
+
+    pub fn write_payload(&mut self, index: usize, payload: &[u8]) {
+        if let Some(Some(endpoint)) = self.endpoints.get_mut(index) {
+            endpoint.write_packet(PacketType::UserPayload, |w| {
+                w.0.write_slice(payload);
+            });
+        }
+    }
+
+    pub fn broadcast_payload(&mut self, payload: &[u8]) {
+        for endpoint in self.undelayed_endpoints() {
+            endpoint.write_packet(PacketType::UserPayload, |w| {
+                w.0.write_slice(payload);
+            });
+        }
+        self.delay_payload(None, payload);
+    }
+
+    /// Like `broadcast_payload`, but only to clients that negotiated `version`, as the payload
+    /// was written in it.
+    pub fn broadcast_versioned_payload(&mut self, version: u16, payload: &[u8]) {
+        for endpoint in self.undelayed_endpoints() {
+            if endpoint.version != version {
+                continue;
+            }
+            endpoint.write_packet(PacketType::UserPayload, |w| {
+                w.0.write_slice(payload);
+            });
+        }
+        self.delay_payload(Some(version), payload);
+    }
+}
+
+/// Writes a denial into `buffer` and sends it; there is no endpoint to send it reliably, as we
+/// deny before creating one.
+///
+/// NOTE: the nonce of the request is echoed in place of the ack bits, so that the requester can
+/// tell our denial from one forged by a third party, which doesn't know the nonce
+pub(crate) fn send_denial<T: Transport>(
+    socket: &T,
+    buffer: &mut Buffer,
+    protocol_id: u32,
+    address: SocketAddr,
+    nonce: u32,
+    reason: DenyReason,
+) {
+    let mut w = WriteStream(buffer, PROTOCOL_VERSION);
+    w.init_packet(
+        protocol_id,
+        PacketType::ConnectionDenied,
+        NetworkSeq::wrap(0),
+        NetworkSeq::wrap(0),
+        nonce,
+    );
+    ConnectionDeniedPacket::new(reason).stream(&mut w);
+    w.finish_packet();
+    if let Err(e) = socket.send_to(buffer.written_slice(), address) {
+        warn!(%address, error = %e, "failed to send connection denial");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, time::Duration};
+
+    use super::*;
+    use crate::net::{
+        client::{Client, ClientEvent, ClientState, Sender},
+        network::bind_socket,
+    };
+
+    fn loopback(config: NetConfig) -> (Server<UdpSocket>, Client<UdpSocket>) {
+        let server_socket = bind_socket("127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_address = server_socket.local_addr().unwrap();
+        let client_socket = bind_socket("127.0.0.1:0".parse().unwrap()).unwrap();
+        (
+            Server::new(server_socket, config.clone()),
+            Client::new(client_socket, server_address, config),
+        )
+    }
+
+    /// Runs the client, and the server if given, until the client has an event other than a
+    /// connection problem, or fails after a few seconds.
+    fn next_client_event(
+        mut server: Option<&mut Server<UdpSocket>>,
+        client: &mut Client<UdpSocket>,
+    ) -> ClientEvent {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        loop {
+            assert!(Instant::now() < deadline, "timed out");
+            if let Some(server) = &mut server {
+                server.process_packets();
+            }
+            match client.process_packets() {
+                Some(ClientEvent::ConnectionProblem | ClientEvent::ConnectionRecovered) | None => {}
+                Some(event) => return event,
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn short_handshake() -> NetConfig {
+        NetConfig {
+            handshake_timeout: 0.3,
+            ..NetConfig::default()
+        }
+    }
+
+    #[test]
+    fn banning_disconnects_connected_clients() {
+        let (mut server, mut client) = loopback(NetConfig::default());
+        assert!(matches!(
+            next_client_event(Some(&mut server), &mut client),
+            ClientEvent::Connected
+        ));
+
+        server.access_list().ban("127.0.0.1/32".parse().unwrap());
+        assert!(matches!(
+            server.process_packets(),
+            Some(ServerEvent::ClientTimeout(0))
+        ));
+        assert_eq!(server.version(0), None);
+    }
+
+    #[test]
+    fn denials_are_reported_once_the_handshake_times_out() {
+        let (mut server, mut client) = loopback(short_handshake());
+        server.access_list().ban("127.0.0.1/32".parse().unwrap());
+        let start = Instant::now();
+        assert!(matches!(
+            next_client_event(Some(&mut server), &mut client),
+            ClientEvent::ConnectionDenied {
+                reason: Some(DenyReason::Banned),
+                ..
+            }
+        ));
+        assert!(start.elapsed().as_secs_f64() >= 0.3);
+        assert_eq!(client.state, ClientState::Denied);
+    }
+
+    #[test]
+    fn denials_of_other_requests_are_ignored() {
+        let (server, mut client) = loopback(short_handshake());
+        // NOTE: the first call writes the request, which has a nonce the forger doesn't know
+        assert!(client.process_packets().is_none());
+        send_denial(
+            &server.socket,
+            &mut Buffer::with_capacity(PACKET_BUFFER_SIZE),
+            server.config.protocol_id,
+            client.socket.local_addr().unwrap(),
+            0,
+            DenyReason::Banned,
+        );
+
+        assert!(matches!(
+            next_client_event(None, &mut client),
+            ClientEvent::ConnectionTimeout
+        ));
+    }
+
+    /// Connects a second client to the server, and has it send the first a direct message
+    /// followed by the server sending the first a message of its own.
+    fn direct_then_server_message(server: &mut Server<UdpSocket>, client: &mut Client<UdpSocket>) {
+        let server_address = server.socket.local_addr().unwrap();
+        let other_socket = bind_socket("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut other = Client::new(other_socket, server_address, NetConfig::default());
+        for client in [&mut *client, &mut other] {
+            assert!(matches!(
+                next_client_event(Some(server), client),
+                ClientEvent::Connected
+            ));
+        }
+
+        // NOTE: not a valid message of the server, whose reads mustn't see it
+        assert!(other.send_payload_to_player(0, &[0xff; 4]));
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while client.peek_sender().is_none() {
+            assert!(Instant::now() < deadline, "timed out");
+            other.process_packets();
+            server.process_packets();
+            client.process_packets();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // NOTE: the reliable channel keeps the two in order, so they're both up for reading soon
+        server.write(0, &mut 7u32);
+        let deadline = Instant::now() + Duration::from_millis(200);
+        while Instant::now() < deadline {
+            server.process_packets();
+            client.process_packets();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn client_reads_skip_direct_messages() {
+        let (mut server, mut client) = loopback(NetConfig::default());
+        direct_then_server_message(&mut server, &mut client);
+        assert_eq!(client.read_new::<u32>(), Some(7));
+        assert_eq!(client.read_new::<u32>(), None);
+    }
+
+    #[test]
+    fn client_reads_from_tell_the_sender() {
+        let (mut server, mut client) = loopback(NetConfig::default());
+        direct_then_server_message(&mut server, &mut client);
+        let mut buffer = Buffer::with_capacity(PACKET_BUFFER_SIZE);
+        assert_eq!(client.read_from(&mut buffer), Some(Sender::Player(1)));
+        assert_eq!(buffer.unread_slice(), &[0xff; 4]);
+        assert_eq!(client.read_from(&mut buffer), Some(Sender::Server));
+        assert_eq!(buffer.unread_slice(), &7u32.to_le_bytes());
+        assert_eq!(client.read_from(&mut buffer), None);
+    }
+
+    #[test]
+    fn versioned_broadcasts_reach_spectators_after_the_delay() {
+        let config = NetConfig {
+            spectator_delay: 0.2,
+            ..NetConfig::default()
+        };
+        let (mut server, mut player) = loopback(config.clone());
+        let server_address = server.socket.local_addr().unwrap();
+        let spectator_socket = bind_socket("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut spectator =
+            Client::with_role(spectator_socket, server_address, Role::Spectator, config);
+        for client in [&mut player, &mut spectator] {
+            assert!(matches!(
+                next_client_event(Some(&mut server), client),
+                ClientEvent::Connected
+            ));
+        }
+
+        server.broadcast_since(PROTOCOL_VERSION + 1, &mut 6u32);
+        server.broadcast_since(PROTOCOL_VERSION, &mut 7u32);
+        let start = Instant::now();
+        let mut received = None;
+        while received.is_none() {
+            assert!(start.elapsed() < Duration::from_secs(3), "timed out");
+            server.process_packets();
+            player.process_packets();
+            spectator.process_packets();
+            if let Some(message) = spectator.read_new::<u32>() {
+                received = Some((message, start.elapsed()));
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let (message, elapsed) = received.unwrap();
+        assert_eq!(message, 7);
+        assert!(elapsed.as_secs_f64() >= 0.2);
+        assert_eq!(player.read_new::<u32>(), Some(7));
+        assert_eq!(player.read_new::<u32>(), None);
     }
 }
@@ -0,0 +1,164 @@
+use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// A bounded lock-free single-producer single-consumer queue.
+pub fn channel<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    assert!(capacity > 0, "spsc channel needs a nonzero capacity");
+
+    let mut slots = Vec::with_capacity(capacity);
+    for _ in 0..capacity {
+        slots.push(UnsafeCell::new(MaybeUninit::uninit()));
+    }
+
+    let ring = Arc::new(Ring {
+        slots: slots.into_boxed_slice(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+
+    (Producer { ring: ring.clone() }, Consumer { ring })
+}
+
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// index of the next slot to pop; only written by the consumer
+    head: AtomicUsize,
+    /// index of the next slot to push; only written by the producer
+    tail: AtomicUsize,
+}
+
+// NOTE: each slot is only ever accessed by one side at a time, as handed over by head and tail
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let mut head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+        while head != tail {
+            unsafe {
+                self.slots[head % self.slots.len()]
+                    .get_mut()
+                    .assume_init_drop()
+            };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+pub struct Producer<T> {
+    ring: Arc<Ring<T>>,
+}
+
+impl<T> Producer<T> {
+    /// Hands the value back if the queue is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let ring = &*self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let head = ring.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == ring.slots.len() {
+            return Err(value);
+        }
+
+        unsafe { (*ring.slots[tail % ring.slots.len()].get()).write(value) };
+        ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+pub struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+}
+
+impl<T> Consumer<T> {
+    pub fn pop(&mut self) -> Option<T> {
+        let ring = &*self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+        let tail = ring.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        let value = unsafe { (*ring.slots[head % ring.slots.len()].get()).assume_init_read() };
+        ring.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn wraps_around() {
+        let (mut producer, mut consumer) = channel(3);
+        for i in 0..10 {
+            assert!(producer.push(2 * i).is_ok());
+            assert!(producer.push(2 * i + 1).is_ok());
+            assert_eq!(consumer.pop(), Some(2 * i));
+            assert_eq!(consumer.pop(), Some(2 * i + 1));
+            assert_eq!(consumer.pop(), None);
+        }
+    }
+
+    #[test]
+    fn hands_back_values_when_full() {
+        let (mut producer, mut consumer) = channel(2);
+        assert!(producer.push(1).is_ok());
+        assert!(producer.push(2).is_ok());
+        assert_eq!(producer.push(3), Err(3));
+        assert_eq!(consumer.pop(), Some(1));
+        assert!(producer.push(3).is_ok());
+        assert_eq!(producer.push(4), Err(4));
+        assert_eq!(consumer.pop(), Some(2));
+        assert_eq!(consumer.pop(), Some(3));
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn drops_values_left_in_the_queue() {
+        let value = Rc::new(());
+        {
+            let (mut producer, mut consumer) = channel(4);
+            for _ in 0..3 {
+                assert!(producer.push(value.clone()).is_ok());
+            }
+            drop(consumer.pop());
+            assert_eq!(Rc::strong_count(&value), 3);
+        }
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn delivers_in_order_across_threads() {
+        const COUNT: u64 = if cfg!(miri) { 1_000 } else { 100_000 };
+        let (mut producer, mut consumer) = channel(16);
+        let thread = std::thread::spawn(move || {
+            for mut i in 0..COUNT {
+                while let Err(value) = producer.push(i) {
+                    i = value;
+                    // NOTE: yields rather than spins, so that this also finishes on a single core
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let mut next = 0;
+        while next < COUNT {
+            if let Some(value) = consumer.pop() {
+                assert_eq!(value, next);
+                next += 1;
+            } else {
+                std::thread::yield_now();
+            }
+        }
+        thread.join().unwrap();
+        assert_eq!(consumer.pop(), None);
+    }
+}
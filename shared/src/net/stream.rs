@@ -1,14 +1,16 @@
-use std::{
-    io,
-    mem::MaybeUninit,
-    net::{SocketAddr, UdpSocket},
-};
+use std::{io, mem::MaybeUninit, net::SocketAddr};
+
+use tracing::warn;
 
 use crate::{
     endian::Endian,
     net::{
         buffer::Buffer,
-        network::{NetworkSeq, PacketHeader, PacketType, PROTOCOL_ID, PROTOCOL_VERSION},
+        network::{
+            packet_checksum, NetworkSeq, PacketHeader, PacketType, PACKET_ACK_BITS_OFFSET,
+            PACKET_ACK_OFFSET, PACKET_HEADER_SIZE, PACKET_TYPE_OFFSET, SUPPORTED_VERSIONS,
+        },
+        transport::Transport,
     },
 };
 
@@ -31,6 +33,8 @@ pub trait Stream {
     fn copy<Value: Copy + Endian>(&mut self, value: &mut Value);
     fn write<Value: Copy + Endian>(&mut self, value: Value);
     fn read<Value: Copy + Endian>(&mut self) -> Value;
+    /// The protocol version being streamed, so that layouts can change between versions.
+    fn version(&self) -> u16;
 
     fn stream_with<Value: Streamable>(&mut self, target: &mut Value)
     where
@@ -49,25 +53,45 @@ pub trait Stream {
     }
 }
 
-pub struct WriteStream<'a>(pub &'a mut Buffer);
+/// NOTE: the second field is the protocol version to write in
+pub struct WriteStream<'a>(pub &'a mut Buffer, pub u16);
 
 impl WriteStream<'_> {
     pub fn init_packet(
         &mut self,
+        protocol_id: u32,
         packet_type: PacketType,
         local_sequence: NetworkSeq,
         remote_ack: NetworkSeq,
         remote_ack_bits: u32,
     ) {
         self.0.reset_writer();
-        PacketHeader::new(packet_type, local_sequence, remote_ack, remote_ack_bits).stream(self);
+        let version = self.1;
+        PacketHeader::new(
+            protocol_id,
+            version,
+            packet_type,
+            local_sequence,
+            remote_ack,
+            remote_ack_bits,
+        )
+        .stream(self);
     }
 
     pub fn finish_packet(&mut self) {
-        // NOTE: overwrite protocol id
+        // NOTE: overwrite protocol id written by `init_packet`
         let checksum = crc32fast::hash(self.0.written_slice());
         self.0.write_at(checksum, 0);
     }
+
+    /// Overwrites the acks of a finished packet and updates its checksum, so that resends carry
+    /// our latest acks.
+    pub fn rewrite_acks(&mut self, protocol_id: u32, remote_ack: NetworkSeq, remote_ack_bits: u32) {
+        self.0.write_at(remote_ack.unwrap(), PACKET_ACK_OFFSET);
+        self.0.write_at(remote_ack_bits, PACKET_ACK_BITS_OFFSET);
+        self.0.write_at(protocol_id, 0);
+        self.finish_packet();
+    }
 }
 
 impl Stream for WriteStream<'_> {
@@ -85,9 +109,14 @@ impl Stream for WriteStream<'_> {
     fn read<Value: Copy + Endian>(&mut self) -> Value {
         panic!("unexpected read from write stream, did you forget to check IS_WRITING?");
     }
+
+    fn version(&self) -> u16 {
+        self.1
+    }
 }
 
-pub struct ReadStream<'a>(pub &'a mut Buffer);
+/// NOTE: the second field is the protocol version to read in
+pub struct ReadStream<'a>(pub &'a mut Buffer, pub u16);
 
 impl Stream for ReadStream<'_> {
     const IS_WRITING: bool = false;
@@ -104,49 +133,138 @@ impl Stream for ReadStream<'_> {
     fn read<Value: Copy + Endian>(&mut self) -> Value {
         self.0.read()
     }
+
+    fn version(&self) -> u16 {
+        self.1
+    }
+}
+
+/// Like `ReadStream`, for payloads of senders that may send anything: reads past the end of the
+/// buffer yield zeroes instead of panicking.
+///
+/// NOTE: the third field tells whether anything was read past the end, and so is garbage
+pub struct CheckedReadStream<'a>(pub &'a mut Buffer, pub u16, pub bool);
+
+impl Stream for CheckedReadStream<'_> {
+    const IS_WRITING: bool = false;
+    const IS_READING: bool = true;
+
+    fn copy<Value: Copy + Endian>(&mut self, value: &mut Value) {
+        *value = self.read();
+    }
+
+    fn write<Value: Copy + Endian>(&mut self, _value: Value) {
+        panic!("unexpected write from read stream, did you forget to check IS_READING?");
+    }
+
+    fn read<Value: Copy + Endian>(&mut self) -> Value {
+        self.0.try_read().unwrap_or_else(|| {
+            self.2 = true;
+            // NOTE: as in `stream_new`, streamed types must be valid when zeroed
+            unsafe { MaybeUninit::<Value>::zeroed().assume_init() }
+        })
+    }
+
+    fn version(&self) -> u16 {
+        self.1
+    }
+}
+
+#[derive(Debug)]
+pub enum PacketError {
+    TooShort(usize),
+    InvalidType(u8),
+    InvalidSize(PacketType, usize),
+    InvalidVersion(u16),
+    InvalidChecksum(u32),
+}
+
+impl std::fmt::Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacketError::TooShort(size) => write!(f, "PACKET TOO SHORT: {size}"),
+            PacketError::InvalidType(packet_type) => write!(f, "INVALID TYPE {packet_type}"),
+            PacketError::InvalidSize(packet_type, size) => {
+                write!(f, "INVALID SIZE OF {packet_type:?}: {size}")
+            }
+            PacketError::InvalidVersion(version) => write!(f, "INVALID VERSION {version}"),
+            PacketError::InvalidChecksum(checksum) => write!(f, "INVALID CHECKSUM {checksum}"),
+        }
+    }
 }
 
 impl ReadStream<'_> {
-    fn verify_incoming_packet_integrity(&mut self, header: &PacketHeader) -> bool {
-        if header.packet_type.invalid_size(self.0.read_size()) {
-            eprintln!(
-                "WARNING: INVALID SIZE OF {:?}: {}",
-                header.packet_type,
-                self.0.read_size()
-            );
-            return false;
+    /// Reads the header of the packet in the buffer, and checks the integrity of the packet.
+    pub fn read_packet(&mut self, protocol_id: u32) -> Result<PacketHeader, PacketError> {
+        let size = self.0.read_size();
+        if size < PACKET_HEADER_SIZE {
+            return Err(PacketError::TooShort(size));
+        }
+
+        // NOTE: must check before reading the header, as invalid enum values are UB
+        let packet_type = self.0.read_slice()[PACKET_TYPE_OFFSET];
+        if PacketType::from_u8(packet_type).is_none() {
+            return Err(PacketError::InvalidType(packet_type));
         }
 
-        if header.version != PROTOCOL_VERSION {
-            eprintln!("WARNING: INVALID VERSION {}", header.version);
-            return false;
+        let header: PacketHeader = self.stream_new();
+
+        if header.packet_type.invalid_size(size) {
+            return Err(PacketError::InvalidSize(header.packet_type, size));
         }
 
-        self.0.write_at(PROTOCOL_ID, 0); // NOTE: necessary for correct checksum
+        // NOTE: handshakes and keep-alives keep their layout across versions, and may be sent
+        // before a version is negotiated
+        if matches!(
+            header.packet_type,
+            PacketType::UserPayload | PacketType::DirectMessage
+        ) && !SUPPORTED_VERSIONS.contains(header.version)
+        {
+            return Err(PacketError::InvalidVersion(header.version));
+        }
 
-        let checksum = crc32fast::hash(self.0.read_slice());
-        if checksum != header.checksum {
-            eprintln!("WARNING: INVALID CHECKSUM {}", header.version);
-            return false;
+        if packet_checksum(self.0.read_slice(), protocol_id) != header.checksum {
+            return Err(PacketError::InvalidChecksum(header.checksum));
         }
 
-        true
+        Ok(header)
     }
 
-    pub fn receive_packet(&mut self, socket: &UdpSocket) -> Option<(PacketHeader, SocketAddr)> {
+    /// Receives a datagram into the buffer, ready for `read_packet`.
+    pub fn receive_datagram<T: Transport>(&mut self, socket: &T) -> Option<SocketAddr> {
         match socket.recv_from(self.0.full_slice_mut()) {
             Ok((num_bytes, address)) => {
                 self.0.reset_reader(num_bytes);
-                let header = self.stream_new();
-                if self.verify_incoming_packet_integrity(&header) {
-                    Some((header, address))
-                } else {
-                    // TODO: should bubble up errors from integrity check?
-                    None
-                }
+                Some(address)
             }
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
             Err(e) => panic!("socket recv io error: {e}"),
         }
     }
+
+    /// Like `read_packet`, but drops invalid packets with a warning.
+    pub fn read_valid_packet(
+        &mut self,
+        address: SocketAddr,
+        protocol_id: u32,
+    ) -> Option<PacketHeader> {
+        match self.read_packet(protocol_id) {
+            Ok(header) => Some(header),
+            Err(error) => {
+                warn!(%address, %error, "dropped invalid packet");
+                // TODO: should bubble up errors from integrity check?
+                None
+            }
+        }
+    }
+
+    pub fn receive_packet<T: Transport>(
+        &mut self,
+        socket: &T,
+        protocol_id: u32,
+    ) -> Option<(PacketHeader, SocketAddr)> {
+        let address = self.receive_datagram(socket)?;
+        self.read_valid_packet(address, protocol_id)
+            .map(|header| (header, address))
+    }
 }
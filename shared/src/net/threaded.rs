@@ -0,0 +1,615 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use tracing::{debug, debug_span};
+
+use crate::net::{
+    buffer::Buffer,
+    client::{Client, ClientEvent, ClientState, Sender},
+    network::{DIRECT_MESSAGE_VERSION, PACKET_BUFFER_SIZE, PROTOCOL_VERSION},
+    server::{Server, ServerEvent},
+    spsc::{self, Consumer, Producer},
+    stream::{ReadStream, Stream, Streamable, WriteStream},
+    transport::Transport,
+};
+
+// NOTE: the queues hand buffers back and forth between the game thread and the network thread, so
+// that all buffers are allocated up front, at spawn.
+
+/// max time the network thread waits for the game thread to make room for an event; the game
+/// thread wakes it up sooner as it processes events
+const EVENT_ROOM_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// A filled buffer with some metadata, such as who it's from or for.
+type Filled<M> = (M, Buffer);
+
+/// Both ends of a queue of filled buffers, together with the queue recycling them back.
+struct BufferQueue<M> {
+    filled: (Producer<Filled<M>>, Consumer<Filled<M>>),
+    free: (Producer<Buffer>, Consumer<Buffer>),
+}
+
+impl<M> BufferQueue<M> {
+    fn with_capacity(capacity: usize) -> Self {
+        let filled = spsc::channel(capacity);
+        let mut free = spsc::channel(capacity);
+        for _ in 0..capacity {
+            assert!(free
+                .0
+                .push(Buffer::with_capacity(PACKET_BUFFER_SIZE))
+                .is_ok());
+        }
+        Self { filled, free }
+    }
+}
+
+/// The network thread's ends of the queues.
+struct NetworkSide<E, I, O> {
+    events: Producer<E>,
+    /// NOTE: an event that did not fit the queue; we stop processing packets until it does
+    pending_event: Option<E>,
+    incoming: Producer<Filled<I>>,
+    incoming_free: Consumer<Buffer>,
+    /// NOTE: a free buffer that turned out to not be needed yet
+    spare: Option<Buffer>,
+    outgoing: Consumer<Filled<O>>,
+    outgoing_free: Producer<Buffer>,
+}
+
+impl<E, I, O> NetworkSide<E, I, O> {
+    /// Returns whether it's fine to process packets, as there is room for a new event.
+    fn flush_pending_event(&mut self) -> bool {
+        if let Some(event) = self.pending_event.take() {
+            if let Err(event) = self.events.push(event) {
+                self.pending_event = Some(event);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Blocks until the game thread processes an event, if one is pending; otherwise the socket
+    /// stays readable, as we don't process packets, and waiting on it would spin.
+    fn wait_for_event_room(&self) -> bool {
+        if self.pending_event.is_none() {
+            return false;
+        }
+        std::thread::park_timeout(EVENT_ROOM_TIMEOUT);
+        true
+    }
+
+    fn push_event(&mut self, event: E) {
+        if let Err(event) = self.events.push(event) {
+            self.pending_event = Some(event);
+        }
+    }
+
+    /// Fills a free buffer with `read`, and queues it to the game thread if it succeeds. Returns
+    /// false when there is nothing more to read for now.
+    fn push_incoming<F: FnMut(&mut Buffer) -> Option<I>>(&mut self, mut read: F) -> bool {
+        let Some(mut buffer) = self.spare.take().or_else(|| self.incoming_free.pop()) else {
+            return false;
+        };
+        if let Some(meta) = read(&mut buffer) {
+            if self.incoming.push((meta, buffer)).is_err() {
+                unreachable!("there are as many buffers as there are queue slots");
+            }
+            true
+        } else {
+            self.spare = Some(buffer);
+            false
+        }
+    }
+
+    fn pop_outgoing<F: FnMut(O, &Buffer)>(&mut self, mut write: F) {
+        while let Some((meta, buffer)) = self.outgoing.pop() {
+            write(meta, &buffer);
+            if self.outgoing_free.push(buffer).is_err() {
+                unreachable!("there are as many buffers as there are queue slots");
+            }
+        }
+    }
+}
+
+/// The game thread's ends of the queues.
+struct GameSide<E, I, O> {
+    events: Consumer<E>,
+    incoming: Consumer<Filled<I>>,
+    incoming_free: Producer<Buffer>,
+    outgoing: Producer<Filled<O>>,
+    outgoing_free: Consumer<Buffer>,
+}
+
+impl<E, I, O> GameSide<E, I, O> {
+    /// Copies the next message into `target`, ready for reading.
+    fn read_payload(&mut self, target: &mut Buffer) -> Option<I> {
+        let (meta, buffer) = self.incoming.pop()?;
+        target.reset_reader_from(buffer.unread_slice());
+        self.recycle_incoming(buffer);
+        Some(meta)
+    }
+
+    fn recycle_incoming(&mut self, buffer: Buffer) {
+        if self.incoming_free.push(buffer).is_err() {
+            unreachable!("there are as many buffers as there are queue slots");
+        }
+    }
+
+    fn drop_incoming(&mut self) {
+        while let Some((_, buffer)) = self.incoming.pop() {
+            self.recycle_incoming(buffer);
+        }
+    }
+
+    fn write<S: Streamable>(&mut self, meta: O, version: u16, value: &mut S) -> bool {
+        let Some(mut buffer) = self.outgoing_free.pop() else {
+            return false;
+        };
+        buffer.reset_writer();
+        value.stream(&mut WriteStream(&mut buffer, version));
+        if self.outgoing.push((meta, buffer)).is_err() {
+            unreachable!("there are as many buffers as there are queue slots");
+        }
+        true
+    }
+}
+
+fn split<E, I, O>(queue_capacity: usize) -> (GameSide<E, I, O>, NetworkSide<E, I, O>) {
+    let events = spsc::channel(queue_capacity);
+    let incoming = BufferQueue::with_capacity(queue_capacity);
+    let outgoing = BufferQueue::with_capacity(queue_capacity);
+    (
+        GameSide {
+            events: events.1,
+            incoming: incoming.filled.1,
+            incoming_free: incoming.free.0,
+            outgoing: outgoing.filled.0,
+            outgoing_free: outgoing.free.1,
+        },
+        NetworkSide {
+            events: events.0,
+            pending_event: None,
+            incoming: incoming.filled.0,
+            incoming_free: incoming.free.1,
+            spare: None,
+            outgoing: outgoing.filled.1,
+            outgoing_free: outgoing.free.0,
+        },
+    )
+}
+
+/// Wakes the network thread up if it waits for room in the event queue.
+fn wake(thread: &Option<JoinHandle<()>>) {
+    if let Some(thread) = thread {
+        thread.thread().unpark();
+    }
+}
+
+/// Index, protocol version and connection generation of the client a message is from.
+type Origin = (u8, u16, u32);
+
+#[derive(Clone, Copy)]
+pub enum Recipient {
+    /// a client, as long as the connection of this generation lasts
+    Client(u8, u32),
+    /// all clients that negotiated this protocol version
+    All(u16),
+}
+
+/// Runs a `Server` on its own network thread, so that slow game frames don't delay acks.
+pub struct ThreadedServer {
+    pub capacity: usize,
+    pub spectator_capacity: usize,
+    /// protocol version negotiated with each connected client or spectator, updated as events
+    /// are processed
+    versions: Vec<Option<u16>>,
+    /// connection generation of each seat, updated as events are processed
+    generations: Vec<u32>,
+    /// NOTE: a message of a connection whose event we haven't processed yet
+    held: Option<Filled<Origin>>,
+    /// NOTE: events come with the version and generation of the client, and messages with the
+    /// index, version and generation
+    queues: GameSide<(ServerEvent, Option<u16>, u32), Origin, Recipient>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ThreadedServer {
+    pub fn spawn<T: Transport + Send + 'static>(
+        mut server: Server<T>,
+        queue_capacity: usize,
+    ) -> Self {
+        let capacity = server.capacity;
+        let spectator_capacity = server.spectator_capacity;
+        let (queues, mut network) = split(queue_capacity);
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread = {
+            let running = running.clone();
+            std::thread::spawn(move || {
+                let _span = debug_span!("network_thread", role = "server").entered();
+                while running.load(Ordering::Relaxed) {
+                    network.pop_outgoing(|recipient, buffer| match recipient {
+                        Recipient::Client(index, generation) => {
+                            if server.generation(index as usize) == generation {
+                                server.write_payload(index as usize, buffer.written_slice())
+                            } else {
+                                debug!(index, "dropped message for a client that timed out");
+                            }
+                        }
+                        Recipient::All(version) => {
+                            server.broadcast_versioned_payload(version, buffer.written_slice())
+                        }
+                    });
+
+                    if network.flush_pending_event() {
+                        if let Some(event) = server.process_packets() {
+                            let version = match event {
+                                ServerEvent::ClientConnected(index)
+                                | ServerEvent::SpectatorConnected(index)
+                                | ServerEvent::ClientConnectionProblem(index)
+                                | ServerEvent::ClientConnectionRecovered(index) => {
+                                    server.version(index as usize)
+                                }
+                                ServerEvent::ClientTimeout(_)
+                                | ServerEvent::SpectatorTimeout(_) => None,
+                            };
+                            let generation = server.generation(event.index() as usize);
+                            network.push_event((event, version, generation));
+                        }
+                    }
+
+                    // NOTE: spectators send nothing to read
+                    for index in 0..capacity {
+                        let mut read = |buffer: &mut Buffer| {
+                            let version = server.version(index)?;
+                            let generation = server.generation(index);
+                            server.read_payload(index, buffer).then_some((
+                                index as u8,
+                                version,
+                                generation,
+                            ))
+                        };
+                        while network.push_incoming(&mut read) {}
+                    }
+
+                    if !network.wait_for_event_room() {
+                        server.wait_for_activity(None);
+                    }
+                }
+            })
+        };
+
+        Self {
+            capacity,
+            spectator_capacity,
+            versions: vec![None; capacity + spectator_capacity],
+            generations: vec![0; capacity + spectator_capacity],
+            held: None,
+            queues,
+            running,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn process_events(&mut self) -> Option<ServerEvent> {
+        let (event, version, generation) = self.queues.events.pop()?;
+        wake(&self.thread);
+        match event {
+            ServerEvent::ClientConnected(index)
+            | ServerEvent::ClientTimeout(index)
+            | ServerEvent::SpectatorConnected(index)
+            | ServerEvent::SpectatorTimeout(index) => {
+                self.versions[index as usize] = version;
+                self.generations[index as usize] = generation;
+            }
+            ServerEvent::ClientConnectionProblem(_) | ServerEvent::ClientConnectionRecovered(_) => {
+            }
+        }
+        Some(event)
+    }
+
+    /// The protocol version negotiated with a connected client, as of the latest event.
+    pub fn version(&self, index: u8) -> Option<u16> {
+        self.versions.get(index as usize).copied().flatten()
+    }
+
+    /// Reads the next message of any client, in the order they were received.
+    ///
+    /// NOTE: messages of a client that timed out are dropped once the next client in its seat is
+    /// processed, and those of that next client are held back until then
+    pub fn read_new<S: Streamable>(&mut self) -> Option<(u8, S)> {
+        loop {
+            let ((index, version, generation), mut buffer) =
+                self.held.take().or_else(|| self.queues.incoming.pop())?;
+            let current = self.generations[index as usize];
+            if generation != current {
+                // NOTE: generations only grow, short of wrapping around after billions of
+                // connections
+                if generation.wrapping_sub(current) < u32::MAX / 2 {
+                    self.held = Some(((index, version, generation), buffer));
+                    return None;
+                }
+                debug!(index, "dropped message of a client that timed out");
+                self.queues.recycle_incoming(buffer);
+                continue;
+            }
+            let message = ReadStream(&mut buffer, version).stream_new();
+            self.queues.recycle_incoming(buffer);
+            return Some((index, message));
+        }
+    }
+
+    pub fn drop_incoming(&mut self) {
+        if let Some((_, buffer)) = self.held.take() {
+            self.queues.recycle_incoming(buffer);
+        }
+        self.queues.drop_incoming();
+    }
+
+    /// Returns false if the outgoing queue is full.
+    ///
+    /// NOTE: the message is dropped if the client times out before it's sent, even if another
+    /// client takes its seat
+    pub fn write<S: Streamable>(&mut self, index: u8, value: &mut S) -> bool {
+        let version = self.version(index).unwrap_or(PROTOCOL_VERSION);
+        let generation = self.generations.get(index as usize).copied().unwrap_or(0);
+        self.queues
+            .write(Recipient::Client(index, generation), version, value)
+    }
+
+    /// Writes the message once per protocol version in use. Returns false if the outgoing queue
+    /// is full.
+    pub fn broadcast<S: Streamable>(&mut self, value: &mut S) -> bool {
+        let mut ok = true;
+        for (index, version) in self.versions.iter().enumerate() {
+            let Some(version) = *version else {
+                continue;
+            };
+            // NOTE: versions are few, so we skip those already written rather than allocate
+            if self.versions[..index].contains(&Some(version)) {
+                continue;
+            }
+            ok &= self.queues.write(Recipient::All(version), version, value);
+        }
+        ok
+    }
+}
+
+impl Drop for ThreadedServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        wake(&self.thread);
+        if let Some(thread) = self.thread.take() {
+            thread.join().expect("network thread panicked");
+        }
+    }
+}
+
+/// Runs a `Client` on its own network thread, so that slow game frames don't delay acks.
+pub struct ThreadedClient {
+    pub index: u8,
+    pub state: ClientState,
+    /// protocol version negotiated with the server once connected
+    pub version: u16,
+    /// NOTE: events come with the index and version, messages with the sender and version, and
+    /// outgoing messages with the player they're for, if any
+    queues: GameSide<(ClientEvent, u8, u16), (Sender, u16), Option<u8>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ThreadedClient {
+    pub fn spawn<T: Transport + Send + 'static>(
+        mut client: Client<T>,
+        queue_capacity: usize,
+    ) -> Self {
+        let (queues, mut network) = split(queue_capacity);
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread = {
+            let running = running.clone();
+            std::thread::spawn(move || {
+                let _span = debug_span!("network_thread", role = "client").entered();
+                while running.load(Ordering::Relaxed) {
+                    network.pop_outgoing(|recipient, buffer| match recipient {
+                        None => client.write_payload(buffer.written_slice()),
+                        Some(index) => {
+                            client.send_payload_to_player(index, buffer.written_slice());
+                        }
+                    });
+
+                    if network.flush_pending_event() {
+                        if let Some(event) = client.process_packets() {
+                            network.push_event((event, client.index, client.version()));
+                        }
+                    }
+
+                    if client.state == ClientState::Connected {
+                        let mut read = |buffer: &mut Buffer| {
+                            let version = client.version();
+                            Some((client.read_from(buffer)?, version))
+                        };
+                        while network.push_incoming(&mut read) {}
+                    }
+
+                    if !network.wait_for_event_room() {
+                        client.wait_for_activity(None);
+                    }
+                }
+            })
+        };
+
+        Self {
+            index: 0,
+            state: ClientState::ConnectionRequest,
+            version: PROTOCOL_VERSION,
+            queues,
+            running,
+            thread: Some(thread),
+        }
+    }
+
+    /// NOTE: `index`, `state` and `version` are updated as events are processed.
+    pub fn process_events(&mut self) -> Option<ClientEvent> {
+        let (event, index, version) = self.queues.events.pop()?;
+        wake(&self.thread);
+        match event {
+            ClientEvent::Connected => {
+                self.index = index;
+                self.version = version;
+                self.state = ClientState::Connected;
+            }
+            ClientEvent::ConnectionTimeout => {
+                self.state = ClientState::ConnectionRequest;
+            }
+            ClientEvent::ConnectionDenied { .. } => {
+                self.state = ClientState::Denied;
+            }
+            ClientEvent::ConnectionProblem | ClientEvent::ConnectionRecovered => {}
+        }
+        Some(event)
+    }
+
+    /// Reads the next message of the server; messages of other clients are dropped, as they may
+    /// send anything, see `read_from`.
+    pub fn read_new<S: Streamable>(&mut self) -> Option<S> {
+        loop {
+            let ((sender, version), mut buffer) = self.queues.incoming.pop()?;
+            let message = match sender {
+                Sender::Server => Some(ReadStream(&mut buffer, version).stream_new()),
+                Sender::Player(index) => {
+                    debug!(
+                        index,
+                        "dropped direct message, read with read_from to receive them"
+                    );
+                    None
+                }
+            };
+            self.queues.recycle_incoming(buffer);
+            if message.is_some() {
+                return message;
+            }
+        }
+    }
+
+    /// Copies the payload of the next message of the server or of another client into `target`,
+    /// ready for reading, and tells who it's from and the protocol version it's in.
+    ///
+    /// NOTE: other clients may send anything, so read their payloads with a `CheckedReadStream`,
+    /// such as through `LobbyMessage::read_checked`
+    pub fn read_from(&mut self, target: &mut Buffer) -> Option<(Sender, u16)> {
+        self.queues.read_payload(target)
+    }
+
+    pub fn drop_incoming(&mut self) {
+        self.queues.drop_incoming();
+    }
+
+    /// Returns false if the outgoing queue is full.
+    pub fn write<S: Streamable>(&mut self, value: &mut S) -> bool {
+        self.queues.write(None, self.version, value)
+    }
+
+    /// Sends a message to another client through the server. Returns false if the outgoing queue
+    /// is full, or if the server's protocol version has no direct messages.
+    pub fn send_to_player<S: Streamable>(&mut self, index: u8, value: &mut S) -> bool {
+        if self.version < DIRECT_MESSAGE_VERSION {
+            return false;
+        }
+        self.queues.write(Some(index), self.version, value)
+    }
+}
+
+impl Drop for ThreadedClient {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        wake(&self.thread);
+        if let Some(thread) = self.thread.take() {
+            thread.join().expect("network thread panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::net::{config::NetConfig, network::bind_socket};
+
+    fn spawn_server(queue_capacity: usize) -> (ThreadedServer, std::net::SocketAddr) {
+        let socket = bind_socket("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = socket.local_addr().unwrap();
+        let server = Server::new(socket, NetConfig::default());
+        (ThreadedServer::spawn(server, queue_capacity), address)
+    }
+
+    fn spawn_client(server_address: std::net::SocketAddr) -> ThreadedClient {
+        let socket = bind_socket("127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = Client::new(socket, server_address, NetConfig::default());
+        ThreadedClient::spawn(client, 16)
+    }
+
+    /// Polls `f` until it yields something, or fails after a few seconds.
+    fn poll<T, F: FnMut() -> Option<T>>(mut f: F) -> T {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        loop {
+            if let Some(value) = f() {
+                return value;
+            }
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn messages_round_trip_between_threads() {
+        let (mut server, address) = spawn_server(16);
+        let mut client = spawn_client(address);
+
+        assert!(matches!(
+            poll(|| client.process_events()),
+            ClientEvent::Connected
+        ));
+        assert_eq!(client.state, ClientState::Connected);
+        assert!(matches!(
+            poll(|| server.process_events()),
+            ServerEvent::ClientConnected(0)
+        ));
+        assert_eq!(server.version(0), Some(PROTOCOL_VERSION));
+
+        assert!(client.write(&mut 7u32));
+        assert_eq!(poll(|| server.read_new::<u32>()), (0, 7));
+        assert!(server.write(0, &mut 8u32));
+        assert_eq!(poll(|| client.read_new::<u32>()), 8);
+
+        // NOTE: dropping stops and joins the network threads, which panics if they did
+        drop(client);
+        drop(server);
+    }
+
+    #[test]
+    fn events_wait_for_room_in_a_full_queue() {
+        let (mut server, address) = spawn_server(1);
+        let _clients: Vec<_> = (0..3).map(|_| spawn_client(address)).collect();
+
+        // NOTE: the network thread blocks on the full event queue meanwhile
+        std::thread::sleep(Duration::from_millis(200));
+        let mut connected = Vec::new();
+        while connected.len() < 3 {
+            match poll(|| server.process_events()) {
+                ServerEvent::ClientConnected(index) => connected.push(index),
+                event => panic!("unexpected event {event:?}"),
+            }
+        }
+        connected.sort();
+        assert_eq!(connected, [0, 1, 2]);
+    }
+}
@@ -0,0 +1,54 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+/// A non-blocking datagram socket; both calls must return `io::ErrorKind::WouldBlock` instead of
+/// blocking.
+pub trait Transport {
+    fn send_to(&self, buffer: &[u8], address: SocketAddr) -> io::Result<usize>;
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// Sends any datagrams that were queued up by `send_to`; called after each network frame.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Blocks until a datagram can be received or the timeout elapses, whichever comes first.
+    fn wait_readable(&self, timeout: Duration) -> io::Result<()> {
+        // NOTE: fallback for transports without readiness notification
+        std::thread::sleep(timeout.min(Duration::from_millis(1)));
+        Ok(())
+    }
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buffer: &[u8], address: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buffer, address)
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buffer)
+    }
+
+    #[cfg(unix)]
+    fn wait_readable(&self, timeout: Duration) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let mut fd = libc::pollfd {
+            fd: self.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // NOTE: round up, as waking up early would have us spin until the deadline
+        let timeout_ms = timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32;
+        if unsafe { libc::poll(&mut fd, 1, timeout_ms) } < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+}
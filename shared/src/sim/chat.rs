@@ -0,0 +1,276 @@
+use std::{
+    collections::VecDeque,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::debug;
+
+use crate::net::{
+    rate_limit::TokenBucket,
+    stream::{Stream, Streamable},
+};
+
+/// max bytes of UTF-8 text in a chat message
+pub const MAX_CHAT_LENGTH: usize = 200;
+/// the first protocol version with chat messages
+pub const CHAT_VERSION: u16 = 6;
+/// sender of the messages of the server itself, such as joins and leaves
+pub const SYSTEM_SENDER: u8 = u8::MAX;
+
+/// A line of lobby chat. Clients send their text, and the server broadcasts it stamped with the
+/// sender and time.
+#[derive(Clone)]
+pub struct ChatMessage {
+    /// player index, or `SYSTEM_SENDER`
+    pub sender: u8,
+    /// seconds since the Unix epoch, by the clock of the server
+    pub time: f64,
+    length: u8,
+    text: [u8; MAX_CHAT_LENGTH],
+}
+
+impl ChatMessage {
+    /// NOTE: text beyond `MAX_CHAT_LENGTH` bytes is cut off, at a character boundary
+    pub fn new(text: &str) -> Self {
+        let mut length = text.len().min(MAX_CHAT_LENGTH);
+        while !text.is_char_boundary(length) {
+            length -= 1;
+        }
+        let mut message = Self {
+            sender: SYSTEM_SENDER,
+            time: 0.,
+            length: length as u8,
+            text: [0; MAX_CHAT_LENGTH],
+        };
+        message.text[..length].copy_from_slice(&text.as_bytes()[..length]);
+        message
+    }
+
+    /// None if the text isn't valid UTF-8, which only a misbehaving client sends.
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.text[..self.length as usize]).ok()
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.sender == SYSTEM_SENDER
+    }
+}
+
+impl Streamable for ChatMessage {
+    fn stream<S: Stream>(&mut self, s: &mut S) {
+        s.copy(&mut self.sender);
+        s.copy(&mut self.length);
+        s.copy(&mut 0u8); // NOTE: padding, so that the time is aligned after the discriminant
+        s.copy(&mut 0u32);
+        s.copy(&mut self.time);
+        // NOTE: the length comes from the sender, so it must not index past the text
+        self.length = self.length.min(MAX_CHAT_LENGTH as u8);
+        for byte in &mut self.text[..self.length as usize] {
+            byte.stream(s);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct ChatConfig {
+    /// messages kept for players joining later; 0 disables history
+    pub history: usize,
+    /// messages per second per player
+    pub messages_per_second: f64,
+    pub message_burst: f64,
+    /// words masked with asterisks, matched regardless of ASCII case, even within other words
+    pub blocked_words: Vec<String>,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            history: 50,
+            messages_per_second: 1.,
+            message_burst: 5.,
+            blocked_words: Vec::new(),
+        }
+    }
+}
+
+impl ChatConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        crate::net::config::positive("messages_per_second", self.messages_per_second)?;
+        crate::net::config::positive("message_burst", self.message_burst)?;
+        if self.blocked_words.iter().any(|word| word.is_empty()) {
+            return Err("blocked_words must not contain empty words".into());
+        }
+        Ok(())
+    }
+}
+
+/// The server side of lobby chat, which filters what players say and remembers it for players
+/// joining later.
+pub struct Chat {
+    config: ChatConfig,
+    /// NOTE: lowercase, to match against lowercased text
+    blocked_words: Vec<String>,
+    history: VecDeque<ChatMessage>,
+    /// NOTE: indexed by player index
+    limits: Vec<Option<TokenBucket>>,
+    /// messages dropped for being invalid or exceeding the rate limit
+    pub dropped: u64,
+}
+
+impl Chat {
+    pub fn new(config: ChatConfig, capacity: u8) -> Self {
+        let mut limits = Vec::with_capacity(capacity as usize);
+        for _ in 0..capacity {
+            limits.push(None);
+        }
+        Self {
+            // NOTE: empty words would match everywhere, and `ChatConfig::validate` rejects them,
+            // but not every config goes through it
+            blocked_words: config
+                .blocked_words
+                .iter()
+                .filter(|word| !word.is_empty())
+                .map(|word| word.to_ascii_lowercase())
+                .collect(),
+            history: VecDeque::with_capacity(config.history),
+            limits,
+            dropped: 0,
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &ChatConfig {
+        &self.config
+    }
+
+    /// Oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &ChatMessage> {
+        self.history.iter()
+    }
+
+    /// Announces a player joining, and starts rate limiting its messages.
+    pub fn join(&mut self, index: u8, now: Instant) -> ChatMessage {
+        self.limits[index as usize] = Some(TokenBucket::full(self.config.message_burst, now));
+        self.system(&format!("player {index} joined"))
+    }
+
+    pub fn leave(&mut self, index: u8) -> ChatMessage {
+        self.limits[index as usize] = None;
+        self.system(&format!("player {index} left"))
+    }
+
+    /// A message of the server itself, stamped and remembered.
+    pub fn system(&mut self, text: &str) -> ChatMessage {
+        let mut message = ChatMessage::new(text);
+        self.stamp(&mut message, SYSTEM_SENDER);
+        message
+    }
+
+    /// Filters and stamps a message of a player, ready to broadcast; None if it's dropped.
+    pub fn receive(
+        &mut self,
+        index: u8,
+        mut message: ChatMessage,
+        now: Instant,
+    ) -> Option<ChatMessage> {
+        let Some(text) = message.text() else {
+            debug!(index, "dropped chat message of invalid UTF-8");
+            self.dropped += 1;
+            return None;
+        };
+        // NOTE: control characters could mess with terminals and logs showing the chat
+        if text.trim().is_empty() || text.chars().any(char::is_control) {
+            debug!(
+                index,
+                "dropped empty chat message or one with control characters"
+            );
+            self.dropped += 1;
+            return None;
+        }
+
+        let config = &self.config;
+        let admitted = self
+            .limits
+            .get_mut(index as usize)
+            .and_then(Option::as_mut)
+            .is_some_and(|bucket| {
+                bucket.take(1., config.messages_per_second, config.message_burst, now)
+            });
+        if !admitted {
+            debug!(index, "dropped rate limited chat message");
+            self.dropped += 1;
+            return None;
+        }
+
+        self.mask_blocked_words(&mut message);
+        self.stamp(&mut message, index);
+        Some(message)
+    }
+
+    fn mask_blocked_words(&self, message: &mut ChatMessage) {
+        let length = message.length as usize;
+        let lowercase = message.text[..length].to_ascii_lowercase();
+        for word in &self.blocked_words {
+            let word = word.as_bytes();
+            // NOTE: matches of valid UTF-8 in valid UTF-8 start and end at character boundaries,
+            // and ASCII lowercasing keeps byte offsets, so masking keeps the text valid
+            for start in 0..length.saturating_sub(word.len() - 1) {
+                if lowercase[start..].starts_with(word) {
+                    message.text[start..start + word.len()].fill(b'*');
+                }
+            }
+        }
+    }
+
+    fn stamp(&mut self, message: &mut ChatMessage, sender: u8) {
+        message.sender = sender;
+        message.time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0., |since| since.as_secs_f64());
+        if self.config.history == 0 {
+            return;
+        }
+        if self.history.len() == self.config.history {
+            self.history.pop_front();
+        }
+        self.history.push_back(message.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(blocked_words: &[&str]) -> Chat {
+        let config = ChatConfig {
+            blocked_words: blocked_words.iter().map(|word| word.to_string()).collect(),
+            ..ChatConfig::default()
+        };
+        let mut chat = Chat::new(config, 1);
+        chat.join(0, Instant::now());
+        chat
+    }
+
+    #[test]
+    fn blocked_words_are_masked_regardless_of_case() {
+        let mut chat = chat(&["heck"]);
+        let message = chat
+            .receive(0, ChatMessage::new("Heck, what the HECK"), Instant::now())
+            .unwrap();
+        assert_eq!(message.text(), Some("****, what the ****"));
+    }
+
+    #[test]
+    fn empty_blocked_words_are_ignored() {
+        let mut chat = chat(&["", "heck"]);
+        let message = chat
+            .receive(0, ChatMessage::new("oh heck"), Instant::now())
+            .unwrap();
+        assert_eq!(message.text(), Some("oh ****"));
+    }
+}
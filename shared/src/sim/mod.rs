@@ -1,5 +1,15 @@
-use crate::net::stream::{Stream, Streamable};
+use std::fmt;
 
+use tracing::debug;
+
+use crate::net::{
+    buffer::Buffer,
+    stream::{CheckedReadStream, Stream, Streamable},
+};
+
+use self::chat::ChatMessage;
+
+pub mod chat;
 pub mod physics_test;
 
 pub enum GameState {
@@ -9,27 +19,60 @@ pub enum GameState {
 
 #[derive(Clone)]
 pub struct Lobby {
-    pub join_mask: u8,
+    pub capacity: u8,
+    /// NOTE: a bit per seat, as many as client indices can address; only the first `capacity`
+    /// bits go over the wire
+    seats: [u8; 32],
 }
 
 impl Lobby {
-    #[allow(clippy::new_without_default)]
-    pub fn new() -> Self {
-        Self { join_mask: 0 }
+    pub fn new(capacity: u8) -> Self {
+        Self {
+            capacity,
+            seats: [0; 32],
+        }
     }
 
     pub fn add_player(&mut self, index: u8) {
-        self.join_mask |= 1 << index;
+        assert!(index < self.capacity, "seat {index} out of lobby capacity");
+        debug!(index, "player joined lobby");
+        self.seats[index as usize / 8] |= 1 << (index % 8);
     }
 
     pub fn remove_player(&mut self, index: u8) {
-        self.join_mask &= !(1 << index);
+        debug!(index, "player left lobby");
+        self.seats[index as usize / 8] &= !(1 << (index % 8));
+    }
+
+    pub fn contains(&self, index: u8) -> bool {
+        self.seats[index as usize / 8] & (1 << (index % 8)) != 0
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.seats
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+}
+
+/// Shows the seats as ones and zeroes, in index order.
+impl fmt::Display for Lobby {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for index in 0..self.capacity {
+            write!(f, "{}", self.contains(index) as u8)?;
+        }
+        Ok(())
     }
 }
 
 impl Streamable for Lobby {
     fn stream<S: Stream>(&mut self, s: &mut S) {
-        self.join_mask.stream(s);
+        self.capacity.stream(s);
+        let size = (self.capacity as usize).div_ceil(8);
+        for byte in &mut self.seats[..size] {
+            byte.stream(s);
+        }
     }
 }
 
@@ -37,6 +80,8 @@ impl Streamable for Lobby {
 pub enum LobbyMessage {
     LobbyUpdated(Lobby) = 10,
     StartGame,
+    /// NOTE: only for clients of `chat::CHAT_VERSION` and later
+    Chat(ChatMessage),
 }
 
 impl LobbyMessage {
@@ -44,6 +89,29 @@ impl LobbyMessage {
         unsafe { *(self as *const Self as *const u8) }
     }
 
+    /// Whether a message starting with this byte can be read without undefined behaviour.
+    pub fn is_valid_discriminant(discriminant: u8) -> bool {
+        [
+            LobbyMessage::LobbyUpdated(Lobby::new(0)),
+            LobbyMessage::StartGame,
+            LobbyMessage::Chat(ChatMessage::new("")),
+        ]
+        .iter()
+        .any(|message| message.discriminant() == discriminant)
+    }
+
+    /// Reads a message of a sender that may send anything; None if it isn't a lobby message, or
+    /// is cut short.
+    pub fn read_checked(buffer: &mut Buffer, version: u16) -> Option<Self> {
+        let &discriminant = buffer.unread_slice().first()?;
+        if !Self::is_valid_discriminant(discriminant) {
+            return None;
+        }
+        let mut stream = CheckedReadStream(buffer, version, false);
+        let message = stream.stream_new();
+        (!stream.2).then_some(message)
+    }
+
     /// NOTE: only grabs the first byte, which is valid because of repr(u8), so only use this for
     /// matching against - the rest will contain garbage and yield undefined behaviour (=UB)!
     unsafe fn discriminate(discriminant: &u8) -> &Self {
@@ -61,6 +129,9 @@ impl Streamable for LobbyMessage {
                 LobbyMessage::StartGame => {
                     *self = LobbyMessage::StartGame;
                 }
+                LobbyMessage::Chat(_) => {
+                    *self = LobbyMessage::Chat(s.stream_new());
+                }
             }
         }
         if S::IS_WRITING {
@@ -70,7 +141,41 @@ impl Streamable for LobbyMessage {
                     data.stream(s);
                 }
                 LobbyMessage::StartGame => {}
+                LobbyMessage::Chat(message) => {
+                    message.stream(s);
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::net::{network::PROTOCOL_VERSION, stream::WriteStream};
+
+    use super::*;
+
+    #[test]
+    fn reads_checked_messages() {
+        let mut buffer = Buffer::with_capacity(512);
+        let mut message = LobbyMessage::Chat(ChatMessage::new("hello"));
+        message.stream(&mut WriteStream(&mut buffer, PROTOCOL_VERSION));
+        let size = buffer.written_size();
+
+        buffer.reset_reader(size);
+        let Some(LobbyMessage::Chat(message)) =
+            LobbyMessage::read_checked(&mut buffer, PROTOCOL_VERSION)
+        else {
+            panic!("expected a chat message");
+        };
+        assert_eq!(message.text(), Some("hello"));
+
+        // NOTE: cut off within the text
+        buffer.reset_reader(size - 1);
+        assert!(LobbyMessage::read_checked(&mut buffer, PROTOCOL_VERSION).is_none());
+
+        buffer.reset_reader(size);
+        buffer.full_slice_mut()[0] = 0;
+        assert!(LobbyMessage::read_checked(&mut buffer, PROTOCOL_VERSION).is_none());
+    }
+}
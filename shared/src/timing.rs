@@ -1,4 +1,4 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 pub struct FrameDurationAccumulator {
     pub step_duration: f64,
@@ -48,6 +48,12 @@ impl FrameDurationAccumulator {
         self.frame_index += 1;
     }
 
+    /// The point in time at which the next call to `run_frame` will have a frame available.
+    pub fn next_frame_deadline(&self) -> Instant {
+        let remaining = (self.step_duration - self.accumulated_duration).max(0.);
+        self.current_start_time + Duration::from_secs_f64(remaining)
+    }
+
     pub fn interpolate_frames(&self) -> f64 {
         self.accumulated_duration / self.step_duration
     }